@@ -0,0 +1,6 @@
+-- admins can use /admin, and disabled users can't log in
+ALTER TABLE users
+ADD COLUMN admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users
+ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,53 @@
+-- an action repeated in a later upload happened again, so is kept again: the history no
+-- longer has a row per distinct action. SQLite can't drop a constraint, so we rebuild.
+CREATE TABLE episode_history_new (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+	username TEXT NOT NULL,
+	device TEXT,
+
+	podcast TEXT NOT NULL,
+	episode TEXT NOT NULL,
+
+	timestamp INTEGER, -- timestamp
+	guid TEXT,
+	action TEXT NOT NULL,
+	started INTEGER,
+	position INTEGER,
+	total INTEGER,
+
+	-- metadata
+	modified INTEGER NOT NULL,
+	content_hash TEXT NOT NULL,
+	hash_version INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO episode_history_new
+(
+	id,
+	username, device,
+	podcast, episode,
+	timestamp, guid,
+	action,
+	started, position, total,
+	modified, content_hash, hash_version
+)
+SELECT
+	id,
+	username, device,
+	podcast, episode,
+	timestamp, guid,
+	action,
+	started, position, total,
+	modified, content_hash, hash_version
+FROM episode_history;
+
+DROP TABLE episode_history;
+ALTER TABLE episode_history_new RENAME TO episode_history;
+
+CREATE INDEX IF NOT EXISTS episode_history_by_modified
+ON episode_history (username, modified);
+
+-- what the constraint's index was used for: an episode's actions, and imports' lookups
+CREATE INDEX IF NOT EXISTS episode_history_by_episode
+ON episode_history (username, podcast, episode, content_hash);
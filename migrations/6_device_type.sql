@@ -0,0 +1,9 @@
+-- device types are stored in their canonical (gpodder, lowercase) form
+UPDATE devices
+SET type = CASE lower(type)
+	WHEN 'desktop' THEN 'desktop'
+	WHEN 'laptop' THEN 'laptop'
+	WHEN 'mobile' THEN 'mobile'
+	WHEN 'server' THEN 'server'
+	ELSE 'other'
+END;
@@ -0,0 +1,44 @@
+-- append-only log of every episode action uploaded, for `aggregated=false`
+CREATE TABLE IF NOT EXISTS episode_history (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+	username TEXT NOT NULL,
+	device TEXT,
+
+	podcast TEXT NOT NULL,
+	episode TEXT NOT NULL,
+
+	timestamp INTEGER, -- timestamp
+	guid TEXT,
+	action TEXT NOT NULL,
+	started INTEGER,
+	position INTEGER,
+	total INTEGER,
+
+	-- metadata
+	modified INTEGER NOT NULL,
+	content_hash TEXT NOT NULL,
+
+	-- a client re-sending the same action doesn't create a new entry
+	UNIQUE(username, podcast, episode, content_hash)
+);
+
+-- seed the history with the latest state we have for each episode
+INSERT INTO episode_history
+(
+	username, device,
+	podcast, episode,
+	timestamp, guid,
+	action,
+	started, position, total,
+	modified, content_hash
+)
+SELECT
+	username, device,
+	podcast, episode,
+	timestamp, guid,
+	action,
+	started, position, total,
+	modified, content_hash
+FROM episodes
+ORDER BY modified;
@@ -0,0 +1,7 @@
+-- 0: the original, unstable `DefaultHasher` content_hash (or "" if never hashed)
+-- rows behind the current version are rehashed on startup
+ALTER TABLE episodes
+ADD COLUMN hash_version INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE episode_history
+ADD COLUMN hash_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+-- incremental syncs only want what's changed since a client last asked
+CREATE INDEX IF NOT EXISTS episodes_by_modified
+ON episodes (username, modified);
+
+CREATE INDEX IF NOT EXISTS episode_history_by_modified
+ON episode_history (username, modified);
+
+-- covering, so a device's subscription changes are read from the indexes alone
+CREATE INDEX IF NOT EXISTS subscriptions_by_created
+ON subscriptions (username, device, created, deleted, url);
+
+CREATE INDEX IF NOT EXISTS subscriptions_by_deleted
+ON subscriptions (username, device, deleted, created, url);
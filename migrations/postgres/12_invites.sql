@@ -0,0 +1,9 @@
+-- codes admins hand out, to register with under `--registration invite`
+CREATE TABLE IF NOT EXISTS invites (
+	code TEXT NOT NULL PRIMARY KEY,
+	created_by TEXT NOT NULL,
+	created BIGINT NOT NULL,
+	expires BIGINT, -- null == never
+	max_uses BIGINT NOT NULL,
+	used BIGINT NOT NULL DEFAULT 0
+);
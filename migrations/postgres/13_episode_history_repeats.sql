@@ -0,0 +1,8 @@
+-- an action repeated in a later upload happened again, so is kept again: the history no
+-- longer has a row per distinct action
+ALTER TABLE episode_history
+DROP CONSTRAINT IF EXISTS episode_history_username_podcast_episode_content_hash_key;
+
+-- what the constraint's index was used for: an episode's actions, and imports' lookups
+CREATE INDEX IF NOT EXISTS episode_history_by_episode
+ON episode_history (username, podcast, episode, content_hash);
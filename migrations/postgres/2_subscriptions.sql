@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS subscriptions (
+	username TEXT NOT NULL,
+	device TEXT NOT NULL,
+
+	url TEXT NOT NULL,
+
+	-- metadata
+	created BIGINT NOT NULL, -- timestamp
+	deleted BIGINT, -- timestamp, if null, subscription is active
+
+	UNIQUE(url, username, device, deleted)
+);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS episodes (
+	username TEXT NOT NULL,
+	device TEXT,
+
+	podcast TEXT NOT NULL,
+	episode TEXT NOT NULL,
+
+	timestamp TIMESTAMP, -- UTC
+	guid TEXT,
+	action TEXT NOT NULL,
+	started BIGINT,
+	position BIGINT,
+	total BIGINT,
+
+	-- metadata
+	modified BIGINT NOT NULL,
+
+	UNIQUE(username, podcast, episode)
+);
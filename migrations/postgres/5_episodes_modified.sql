@@ -0,0 +1,2 @@
+ALTER TABLE episodes
+ADD COLUMN content_hash TEXT NOT NULL DEFAULT '';
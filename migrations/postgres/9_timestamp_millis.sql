@@ -0,0 +1,10 @@
+-- timestamps are now stored in milliseconds
+UPDATE subscriptions
+SET created = created * 1000,
+	deleted = deleted * 1000;
+
+UPDATE episodes
+SET modified = modified * 1000;
+
+UPDATE episode_history
+SET modified = modified * 1000;
@@ -31,6 +31,6 @@ stty -echo; read pass; echo; stty echo
 hash=$(printf '%s' "$pass" | sha_hash)
 
 sqlite3 pod.sql <<!
-INSERT INTO users
-VALUES ("$user", "$hash", NULL);
+INSERT INTO users (username, pwhash)
+VALUES ("$user", "$hash");
 !
@@ -1,6 +1,12 @@
 use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::backup::Retention;
+use crate::limits::Limits;
+use crate::user::Registration;
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -17,6 +23,200 @@ pub struct Args {
     /// The port podsync listens on.
     #[arg(short, long, default_value_t = 80)]
     port: u16,
+
+    /// Where podsync stores its data, a `sqlite://` URL, or a
+    /// `postgres://` URL when built with the `postgres` feature.
+    #[arg(short, long, default_value = "sqlite://pod.sql")]
+    database: String,
+
+    /// Keep all data in memory, discarding it on exit. Useful for
+    /// trying podsync out, with a single user, "demo" (password "demo").
+    #[arg(long)]
+    ephemeral: bool,
+
+    /// Who may create their own account, at `/ui/register`
+    /// or `POST /api/podsync/register`.
+    #[arg(long, value_enum, default_value_t = Registration::Closed)]
+    registration: Registration,
+
+    /// Snapshot the database into this directory while serving, once on
+    /// start and then every `--backup-every` hours.
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// How often to snapshot into `--backup-dir`, in hours.
+    #[arg(long, value_name = "HOURS", default_value_t = 24,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    backup_every: u64,
+
+    #[command(flatten)]
+    backup: BackupOptions,
+
+    // pruned on start, and then daily
+    #[command(flatten)]
+    prune: PruneOptions,
+
+    #[command(flatten)]
+    limits: LimitOptions,
+
+    /// Run a command against the database, rather than serving.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BackupOptions {
+    /// Gzip snapshots.
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Keep the newest snapshot of each of the last N days, removing
+    /// others from the backup directory.
+    #[arg(long, value_name = "N")]
+    pub keep_daily: Option<usize>,
+
+    /// Keep the newest snapshot of each of the last N weeks, removing
+    /// others from the backup directory.
+    #[arg(long, value_name = "N")]
+    pub keep_weekly: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PruneOptions {
+    /// Remove subscriptions deleted more than DAYS ago. A device's latest
+    /// removal of a podcast is kept, so its clients still learn of it.
+    #[arg(long, value_name = "DAYS")]
+    pub prune_subscriptions: Option<u32>,
+
+    /// Remove episode actions older than DAYS, of podcasts their user
+    /// no longer subscribes to.
+    #[arg(long, value_name = "DAYS")]
+    pub prune_episodes: Option<u32>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LimitOptions {
+    /// The largest subscription or episode upload accepted, in bytes,
+    /// once unzipped. Larger are refused with 413 Payload Too Large.
+    #[arg(long, value_name = "BYTES", default_value_t = Limits::default().upload_body)]
+    max_upload_size: u64,
+
+    /// The largest body accepted by any other request, in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = Limits::default().body)]
+    max_body_size: u64,
+
+    /// The most subscription changes or episode actions accepted in one upload.
+    #[arg(long, value_name = "N", default_value_t = Limits::default().upload_items)]
+    max_upload_items: usize,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage a user's data.
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+
+    /// Write a consistent snapshot of a SQLite database, which may be in use,
+    /// to a file, or into a directory named for the time.
+    Backup {
+        dest: PathBuf,
+
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+
+    /// Check the database for oddities left by older versions of podsync.
+    Doctor {
+        /// Repair what's found, all in one transaction.
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// Remove old sync data, as the server does daily when given the same options.
+    Prune {
+        #[command(flatten)]
+        options: PruneOptions,
+    },
+
+    /// Replace the SQLite database, which mustn't be in use, with a snapshot.
+    /// The snapshot is checked first, and the old database kept as
+    /// `<database>.pre-restore`.
+    Restore { snapshot: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Write a user's data to stdout: everything, as JSON, or their
+    /// subscriptions as OPML, or their episodes' state as CSV.
+    Export {
+        name: String,
+
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        /// Just this device's subscriptions, for OPML.
+        #[arg(short, long)]
+        device: Option<String>,
+    },
+
+    /// Let a user into the admin console, at `/admin`.
+    Admin {
+        name: String,
+
+        /// Take their admin rights away instead.
+        #[arg(long)]
+        revoke: bool,
+    },
+
+    /// Load a `user export` into an existing user. Importing
+    /// the same file twice has no further effect.
+    Import { name: String, file: PathBuf },
+
+    /// Bring in subscriptions and episode actions from another
+    /// service or app.
+    ImportFrom {
+        name: String,
+
+        /// The device to subscribe, and to attribute episode actions to.
+        #[arg(short, long)]
+        device: String,
+
+        /// Report what would change, without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Json,
+    Opml,
+    Csv,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ImportSource {
+    /// An OPML subscription list, as most podcast apps export.
+    Opml { file: PathBuf },
+
+    /// Responses from gpodder.net's API: subscriptions from
+    /// `/subscriptions/{username}.json`, and episode actions from
+    /// `/api/2/episodes/{username}.json`.
+    Gpodder {
+        #[arg(long)]
+        subscriptions: Option<PathBuf>,
+
+        #[arg(long)]
+        episodes: Option<PathBuf>,
+    },
+
+    /// An AntennaPod database export.
+    Antennapod { file: PathBuf },
 }
 
 impl Args {
@@ -31,4 +231,63 @@ impl Args {
     pub fn secure(&self) -> bool {
         self.secure
     }
+
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    pub fn ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    pub fn registration(&self) -> Registration {
+        self.registration
+    }
+
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+
+    pub fn backup_dir(&self) -> Option<&Path> {
+        self.backup_dir.as_deref()
+    }
+
+    pub fn backup_every(&self) -> Duration {
+        Duration::from_secs(self.backup_every * 60 * 60)
+    }
+
+    pub fn backup(&self) -> &BackupOptions {
+        &self.backup
+    }
+
+    pub fn prune(&self) -> &PruneOptions {
+        &self.prune
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            upload_body: self.limits.max_upload_size,
+            body: self.limits.max_body_size,
+            upload_items: self.limits.max_upload_items,
+        }
+    }
+}
+
+impl PruneOptions {
+    pub fn any(&self) -> bool {
+        self.prune_subscriptions.is_some() || self.prune_episodes.is_some()
+    }
+}
+
+impl BackupOptions {
+    pub fn retention(&self) -> Option<Retention> {
+        if self.keep_daily.is_none() && self.keep_weekly.is_none() {
+            return None;
+        }
+
+        Some(Retention {
+            daily: self.keep_daily.unwrap_or(0),
+            weekly: self.keep_weekly.unwrap_or(0),
+        })
+    }
 }
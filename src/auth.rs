@@ -29,6 +29,11 @@ impl BasicAuth {
             .then(|| AuthAttempt { auth: self })
             .ok_or(podsync::Error::Unauthorized)
     }
+
+    // for paths without a username, where the credentials alone say who's logging in
+    pub fn into_attempt(self) -> AuthAttempt {
+        AuthAttempt { auth: self }
+    }
 }
 
 impl FromStr for BasicAuth {
@@ -57,6 +62,13 @@ impl FromStr for BasicAuth {
 }
 
 impl AuthAttempt {
+    // credentials from a login form, rather than a header
+    pub fn new(user: String, pass: String) -> Self {
+        Self {
+            auth: BasicAuth { user, pass },
+        }
+    }
+
     pub fn user(&self) -> &str {
         &self.auth.user
     }
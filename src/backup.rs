@@ -0,0 +1,440 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{error, info, warn};
+use sqlx::{query_as, query_scalar, sqlite::SqliteConnectOptions, SqlitePool};
+use time::{
+    format_description::FormatItem, macros::format_description, Date, OffsetDateTime,
+    PrimitiveDateTime,
+};
+
+use crate::podsync::{Error, PodSync, Result};
+use crate::store::Store;
+
+// scheduled snapshots are named for when they were taken, `pod-20230301T100000Z.sql[.gz]`
+const SNAPSHOT_PREFIX: &str = "pod-";
+const SNAPSHOT_TIME: &[FormatItem] =
+    format_description!("[year][month][day]T[hour][minute][second]Z");
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+// Writes a snapshot to `dest`, gzipped (and given a `.gz` extension) if `compress`.
+// It only appears once complete, so a crash never leaves a torn snapshot behind.
+pub async fn write(store: &dyn Store, dest: &Path, compress: bool) -> Result<PathBuf> {
+    let dest = if compress && !is_gzip(dest) {
+        with_suffix(dest, ".gz")
+    } else {
+        dest.to_path_buf()
+    };
+
+    if dest.exists() {
+        error!("not overwriting {}", dest.display());
+        return Err(Error::BadRequest);
+    }
+
+    let partial = with_suffix(&dest, ".partial");
+    let raw = if compress {
+        with_suffix(&dest, ".raw")
+    } else {
+        partial.clone()
+    };
+
+    for leftover in [&partial, &raw] {
+        remove_if_exists(leftover).map_err(|e| {
+            error!("couldn't remove {}: {e}", leftover.display());
+            Error::Internal
+        })?;
+    }
+
+    store.backup(&raw).await?;
+
+    if compress {
+        let (from, to) = (raw.clone(), partial.clone());
+        let gzipped = tokio::task::spawn_blocking(move || gzip(&from, &to))
+            .await
+            .expect("gzip task panicked");
+
+        let _ = fs::remove_file(&raw);
+        gzipped.map_err(|e| {
+            error!("couldn't compress {}: {e}", partial.display());
+            Error::Internal
+        })?;
+    }
+
+    fs::rename(&partial, &dest).map_err(|e| {
+        error!("couldn't move snapshot to {}: {e}", dest.display());
+        Error::Internal
+    })?;
+
+    Ok(dest)
+}
+
+// A snapshot into `dir`, named for `now`
+pub async fn snapshot(
+    store: &dyn Store,
+    dir: &Path,
+    compress: bool,
+    now: OffsetDateTime,
+) -> Result<PathBuf> {
+    let when = now
+        .to_offset(time::UtcOffset::UTC)
+        .format(SNAPSHOT_TIME)
+        .expect("formatting snapshot time");
+
+    write(
+        store,
+        &dir.join(format!("{SNAPSHOT_PREFIX}{when}.sql")),
+        compress,
+    )
+    .await
+}
+
+// Removes the snapshots in `dir` outside of `keep`, returning them
+pub fn prune(dir: &Path, keep: Retention) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if let Some(when) = path.file_name().and_then(|n| n.to_str()).and_then(taken) {
+            snapshots.push((when, path));
+        }
+    }
+
+    let expired = expired(snapshots, keep);
+    for path in &expired {
+        fs::remove_file(path)?;
+    }
+
+    Ok(expired)
+}
+
+// Snapshots `podsync` into `dir` now, and then every `every`
+pub async fn schedule(
+    podsync: Arc<PodSync>,
+    dir: PathBuf,
+    every: Duration,
+    compress: bool,
+    keep: Option<Retention>,
+) {
+    if let Err(e) = fs::create_dir_all(&dir) {
+        error!("couldn't create backup directory {}: {e}", dir.display());
+        return;
+    }
+
+    let mut interval = tokio::time::interval(every);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let Ok(path) = podsync.snapshot(&dir, compress).await else {
+            continue; // logged, we'll try again next time
+        };
+        info!("backed up to {}", path.display());
+
+        if let Some(keep) = keep {
+            match prune(&dir, keep) {
+                Ok(removed) => {
+                    for path in removed {
+                        info!("removed old backup {}", path.display());
+                    }
+                }
+                Err(e) => warn!("couldn't prune backups in {}: {e}", dir.display()),
+            }
+        }
+    }
+}
+
+// Swaps `snapshot` in for the SQLite database at `url`, which mustn't be in use. The
+// snapshot must be intact and from our migrations (or some of them: the rest are applied
+// on start). What it replaces is kept alongside, as `<database>.pre-restore`.
+pub async fn restore(url: &str, snapshot: &Path) -> std::result::Result<(), ()> {
+    let Some(db) = sqlite_path(url) else {
+        error!("can only restore to a SQLite database file, not {url:?}");
+        return Err(());
+    };
+
+    let staged = with_suffix(&db, ".restoring");
+    remove_if_exists(&staged).map_err(|e| error!("couldn't remove {}: {e}", staged.display()))?;
+
+    let (from, to) = (snapshot.to_path_buf(), staged.clone());
+    tokio::task::spawn_blocking(move || {
+        if is_gzip(&from) {
+            gunzip(&from, &to)
+        } else {
+            fs::copy(&from, &to).map(|_| ())
+        }
+    })
+    .await
+    .expect("copy task panicked")
+    .map_err(|e| error!("couldn't read {}: {e}", snapshot.display()))?;
+
+    let verified = verify(&staged).await;
+    if let Err(e) = &verified {
+        error!("not restoring {}: {e}", snapshot.display());
+        let _ = fs::remove_file(&staged);
+        return Err(());
+    }
+
+    // the old database's WAL goes with it, so it stays consistent and can't be
+    // replayed into the snapshot
+    let previous = with_suffix(&db, ".pre-restore");
+    for suffix in ["", "-wal", "-shm"] {
+        let from = with_suffix(&db, suffix);
+        let to = with_suffix(&previous, suffix);
+
+        remove_if_exists(&to)
+            .and_then(|()| match fs::rename(&from, &to) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                r => r,
+            })
+            .map_err(|e| error!("couldn't move {} aside: {e}", from.display()))?;
+    }
+
+    fs::rename(&staged, &db).map_err(|e| error!("couldn't restore {}: {e}", db.display()))?;
+
+    println!(
+        "restored {} to {}, the previous database is at {}",
+        snapshot.display(),
+        db.display(),
+        previous.display()
+    );
+
+    Ok(())
+}
+
+async fn verify(path: &Path) -> std::result::Result<(), String> {
+    let options = SqliteConnectOptions::new().filename(path);
+    let db = SqlitePool::connect_with(options)
+        .await
+        .map_err(|e| format!("can't open: {e}"))?;
+
+    let integrity: String = query_scalar("PRAGMA integrity_check")
+        .fetch_one(&db)
+        .await
+        .map_err(|e| format!("can't check integrity: {e}"))?;
+    if integrity != "ok" {
+        return Err(format!("integrity check failed: {integrity}"));
+    }
+
+    let applied: Vec<(i64, Vec<u8>)> = query_as(
+        "
+        SELECT version, checksum
+        FROM _sqlx_migrations
+        WHERE success
+        ORDER BY version
+        ",
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|e| format!("not a podsync database: {e}"))?;
+
+    db.close().await;
+
+    let ours = sqlx::migrate!("./migrations");
+    for (version, checksum) in applied {
+        match ours.iter().find(|m| m.version == version) {
+            Some(m) if *m.checksum == checksum[..] => {}
+            Some(_) => return Err(format!("its migration {version} differs from ours")),
+            None => {
+                return Err(format!(
+                    "it's from a newer podsync, with migration {version}"
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Which snapshots fall outside `keep`: we keep the newest of each of the last `daily`
+// days and `weekly` ISO weeks with a snapshot, and always the newest of all
+fn expired(mut snapshots: Vec<(OffsetDateTime, PathBuf)>, keep: Retention) -> Vec<PathBuf> {
+    snapshots.sort_by_key(|&(when, _)| std::cmp::Reverse(when));
+
+    let mut days: Vec<Date> = vec![];
+    let mut weeks: Vec<(i32, u8)> = vec![];
+
+    snapshots
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, (when, path))| {
+            let day = when.date();
+            let (year, week, _) = day.to_iso_week_date();
+            let mut kept = i == 0;
+
+            if !days.contains(&day) && days.len() < keep.daily {
+                days.push(day);
+                kept = true;
+            }
+            if !weeks.contains(&(year, week)) && weeks.len() < keep.weekly {
+                weeks.push((year, week));
+                kept = true;
+            }
+
+            (!kept).then_some(path)
+        })
+        .collect()
+}
+
+// when a snapshot was taken, from its file name
+fn taken(name: &str) -> Option<OffsetDateTime> {
+    let when = name.strip_prefix(SNAPSHOT_PREFIX)?;
+    let when = when
+        .strip_suffix(".sql.gz")
+        .or_else(|| when.strip_suffix(".sql"))?;
+
+    PrimitiveDateTime::parse(when, SNAPSHOT_TIME)
+        .ok()
+        .map(PrimitiveDateTime::assume_utc)
+}
+
+fn sqlite_path(url: &str) -> Option<PathBuf> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))?;
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    (!path.is_empty() && path != ":memory:").then(|| path.into())
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+fn gzip(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+fn gunzip(from: &Path, to: &Path) -> io::Result<()> {
+    let mut decoder = GzDecoder::new(File::open(from)?);
+    io::copy(&mut decoder, &mut File::create(to)?).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use time::macros::datetime;
+    use uuid::Uuid;
+
+    use crate::mock;
+    use crate::store::SqliteStore;
+
+    #[test]
+    fn retention() {
+        let snapshots = [
+            datetime!(2023-03-06 10:00 UTC), // monday
+            datetime!(2023-03-06 04:00 UTC),
+            datetime!(2023-03-05 10:00 UTC), // sunday, so the week before
+            datetime!(2023-03-04 10:00 UTC),
+            datetime!(2023-02-20 10:00 UTC),
+            datetime!(2023-02-13 10:00 UTC),
+        ];
+        let name = |when: &OffsetDateTime| PathBuf::from(when.format(SNAPSHOT_TIME).unwrap());
+        let expire = |daily, weekly| {
+            let mut expired: Vec<_> = expired(
+                snapshots.iter().map(|when| (*when, name(when))).collect(),
+                Retention { daily, weekly },
+            )
+            .into_iter()
+            .map(|path| {
+                snapshots
+                    .iter()
+                    .position(|when| name(when) == path)
+                    .unwrap()
+            })
+            .collect();
+            expired.sort();
+            expired
+        };
+
+        // the newest is always kept
+        assert_eq!(expire(0, 0), vec![1, 2, 3, 4, 5]);
+        assert_eq!(expire(2, 0), vec![1, 3, 4, 5]);
+        assert_eq!(expire(0, 3), vec![1, 3, 5]);
+        assert_eq!(expire(3, 3), vec![1, 5]);
+
+        assert_eq!(
+            taken("pod-20230306T100000Z.sql.gz"),
+            Some(datetime!(2023-03-06 10:00 UTC))
+        );
+        assert_eq!(taken("pod-20230306T100000Z.sql.partial"), None);
+        assert_eq!(taken("pod.sql"), None);
+    }
+
+    #[tokio::test]
+    async fn backup_restore() {
+        let dir = std::env::temp_dir().join(format!("podsync-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+
+        // VACUUM INTO writes nothing from sqlx's in-memory databases
+        let source = format!("sqlite://{}?mode=rwc", dir.join("pod.sql").display());
+        let db = SqlitePool::connect(&source).await.unwrap();
+        sqlx::migrate!("./migrations").run(&db).await.unwrap();
+
+        let store = SqliteStore::new(db);
+        store.create_user("user1", "").await.unwrap();
+
+        let now = datetime!(2023-03-01 10:00 UTC);
+        let path = snapshot(&store, &dir, true, now).await.unwrap();
+        assert_eq!(path, dir.join("pod-20230301T100000Z.sql.gz"));
+
+        // snapshots aren't overwritten
+        assert!(matches!(
+            snapshot(&store, &dir, true, now).await,
+            Err(Error::BadRequest)
+        ));
+
+        let db = dir.join("restored.sql");
+        fs::write(&db, "not a database").unwrap();
+        let url = format!("sqlite://{}", db.display());
+        restore(&url, &path).await.unwrap();
+
+        let restored = SqliteStore::new(SqlitePool::connect(&url).await.unwrap());
+        assert!(restored.user("user1").await.unwrap().is_some());
+        assert_eq!(
+            fs::read(with_suffix(&db, ".pre-restore")).unwrap(),
+            b"not a database"
+        );
+
+        // nor do we restore what isn't a snapshot
+        assert!(restore(&url, &with_suffix(&db, ".pre-restore"))
+            .await
+            .is_err());
+
+        // those podsync takes are named for its clock
+        let podsync = PodSync::with_clock(Arc::new(restored), Arc::new(mock::Clock::default()));
+        let path = podsync.snapshot(&dir, false).await.unwrap();
+        assert_eq!(path, dir.join("pod-19700101T000025Z.sql"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use std::{fs, io};
+
+use log::error;
+
+use crate::args::{Command, ExportFormat, ImportSource, UserCommand};
+use crate::backup;
+use crate::migrate::{self, Migration};
+use crate::podsync::PodSync;
+
+// failures are logged as they happen
+pub async fn run(podsync: &PodSync, command: &Command) -> Result<(), ()> {
+    match command {
+        Command::User { command } => match command {
+            UserCommand::Export {
+                name,
+                format,
+                device,
+            } => match (format, device) {
+                (ExportFormat::Json, None) => {
+                    let export = podsync.export_user(name).await.map_err(|_| ())?;
+
+                    serde_json::to_writer_pretty(io::stdout().lock(), &export)
+                        .map_err(|e| error!("couldn't write export: {e}"))?;
+                    println!();
+                }
+                (ExportFormat::Opml, device) => {
+                    let opml = podsync
+                        .export_opml(name, device.as_deref())
+                        .await
+                        .map_err(|_| ())?;
+
+                    print!("{opml}");
+                }
+                (ExportFormat::Csv, None) => {
+                    let csv = podsync.export_csv(name).await.map_err(|_| ())?;
+
+                    print!("{csv}");
+                }
+                (_, Some(_)) => {
+                    error!("only OPML exports can be of a single device");
+                    return Err(());
+                }
+            },
+            UserCommand::Admin { name, revoke } => {
+                podsync.set_admin(name, !revoke).await.map_err(|_| ())?;
+
+                println!(
+                    "{name} is {}an admin",
+                    if *revoke { "no longer " } else { "" }
+                );
+            }
+            UserCommand::Import { name, file } => {
+                let contents =
+                    fs::read(file).map_err(|e| error!("couldn't read {}: {e}", file.display()))?;
+                let export = serde_json::from_slice(&contents)
+                    .map_err(|e| error!("couldn't parse {}: {e}", file.display()))?;
+
+                let summary = podsync.import_user(name, export).await.map_err(|_| ())?;
+
+                println!(
+                    "{name}: {} devices, {} new subscriptions, {} episode actions ({} stale)",
+                    summary.devices,
+                    summary.subscriptions,
+                    summary.episodes,
+                    summary.stale_episodes,
+                );
+            }
+            UserCommand::ImportFrom {
+                name,
+                device,
+                dry_run,
+                source,
+            } => {
+                let migration = read_source(source, device)
+                    .await
+                    .map_err(|e| error!("couldn't import: {e}"))?;
+
+                let report = podsync
+                    .migrate(name, device, migration, *dry_run)
+                    .await
+                    .map_err(|_| ())?;
+
+                if *dry_run {
+                    println!("dry run, nothing has been changed");
+                }
+                for url in &report.subscriptions_added {
+                    println!("subscribe {url}");
+                }
+                println!(
+                    "{name} on {device}: {} new subscriptions ({} already held), episode actions: {} new, {} updated, {} unchanged, {} stale, {} unreadable",
+                    report.subscriptions_added.len(),
+                    report.subscriptions_held,
+                    report.episodes_new,
+                    report.episodes_updated,
+                    report.episodes_unchanged,
+                    report.episodes_stale,
+                    report.episodes_rejected,
+                );
+            }
+        },
+        Command::Backup { dest, options } => {
+            let keep = options.retention();
+            if keep.is_some() && !dest.is_dir() {
+                error!("--keep-daily and --keep-weekly need a directory to back up into");
+                return Err(());
+            }
+
+            let path = podsync
+                .snapshot(dest, options.compress)
+                .await
+                .map_err(|_| ())?;
+            println!("backed up to {}", path.display());
+
+            if let Some(keep) = keep {
+                let removed = backup::prune(dest, keep)
+                    .map_err(|e| error!("couldn't prune {}: {e}", dest.display()))?;
+
+                for path in removed {
+                    println!("removed {}", path.display());
+                }
+            }
+        }
+        Command::Doctor { fix } => {
+            let diagnosis = podsync.doctor(*fix).await.map_err(|_| ())?;
+
+            if diagnosis.is_healthy() {
+                println!("no problems found");
+                return Ok(());
+            }
+
+            for (username, device, url) in &diagnosis.duplicate_subscriptions {
+                println!("{username} is subscribed to {url} more than once on {device}");
+            }
+            for (username, device) in &diagnosis.unknown_devices {
+                println!("{username} has subscriptions on {device}, an unknown device");
+            }
+            for (username, podcast, episode) in &diagnosis.positionless_plays {
+                println!("{username} has a \"play\" of {episode} ({podcast}) without a position");
+            }
+            if diagnosis.positionless_history > 0 {
+                println!(
+                    "{} \"play\"s in the episode history are without a position",
+                    diagnosis.positionless_history
+                );
+            }
+            if diagnosis.unhashed_episodes > 0 {
+                println!(
+                    "{} episode actions have no content hash",
+                    diagnosis.unhashed_episodes
+                );
+            }
+
+            if *fix {
+                println!("repaired");
+            } else {
+                println!("run with --fix to repair");
+                return Err(());
+            }
+        }
+        Command::Prune { options } => {
+            if !options.any() {
+                error!("nothing to prune, see --prune-subscriptions and --prune-episodes");
+                return Err(());
+            }
+
+            let pruned = podsync
+                .prune(options.prune_subscriptions, options.prune_episodes)
+                .await
+                .map_err(|_| ())?;
+
+            println!(
+                "removed {} deleted subscriptions, {} episode actions and {} from the episode history",
+                pruned.subscriptions, pruned.episodes, pruned.history,
+            );
+        }
+        Command::Restore { .. } => unreachable!("restores happen before the database is opened"),
+    }
+
+    Ok(())
+}
+
+async fn read_source(source: &ImportSource, device: &str) -> Result<Migration, migrate::Error> {
+    match source {
+        ImportSource::Opml { file } => migrate::from_opml(&fs::read_to_string(file)?),
+        ImportSource::Gpodder {
+            subscriptions,
+            episodes,
+        } => {
+            let read = |file: &Option<_>| file.as_ref().map(fs::read_to_string).transpose();
+
+            migrate::from_gpodder(
+                read(subscriptions)?.as_deref(),
+                read(episodes)?.as_deref(),
+                device,
+            )
+        }
+        ImportSource::Antennapod { file } => migrate::from_antennapod(file, device).await,
+    }
+}
@@ -0,0 +1,236 @@
+// Responses compressed as the client's `Accept-Encoding` allows, and request bodies
+// which may arrive gzipped.
+
+use std::io::{self, Read};
+
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder},
+    Level,
+};
+use flate2::read::GzDecoder;
+use futures_util::{pin_mut, Stream, TryStreamExt};
+use log::warn;
+use serde::de::DeserializeOwned;
+use tokio_util::io::{ReaderStream, StreamReader};
+use warp::{
+    http::header::{self, HeaderValue},
+    hyper::{body::Buf, Body},
+    reply::Response,
+    Filter, Rejection,
+};
+
+use crate::limits;
+use crate::podsync::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    // whichever the client rates higher, brotli if they're level, or neither if both are refused
+    fn negotiate(accept: &str) -> Option<Self> {
+        let (mut brotli, mut gzip, mut any) = (None, None, None);
+
+        for coding in accept.split(',') {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let q = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                Some(q) => match q.trim().parse::<f32>() {
+                    Ok(q) => q,
+                    Err(_) => continue,
+                },
+                None => 1.0,
+            };
+
+            match &*name.to_ascii_lowercase() {
+                "br" => brotli = Some(q),
+                "gzip" | "x-gzip" => gzip = Some(q),
+                "*" => any = Some(q),
+                _ => {}
+            }
+        }
+
+        let brotli = brotli.or(any).unwrap_or(0.0);
+        let gzip = gzip.or(any).unwrap_or(0.0);
+
+        if brotli > 0.0 && brotli >= gzip {
+            Some(Self::Brotli)
+        } else if gzip > 0.0 {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+// Compresses responses that have a body to speak of (going by their `Content-Type`),
+// as they're streamed out
+pub fn compress(accept_encoding: Option<String>, mut res: Response) -> Response {
+    let headers = res.headers();
+    if !headers.contains_key(header::CONTENT_TYPE) || headers.contains_key(header::CONTENT_ENCODING)
+    {
+        return res;
+    }
+
+    res.headers_mut().append(
+        header::VARY,
+        HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+    );
+
+    let Some(encoding) = accept_encoding.as_deref().and_then(Encoding::negotiate) else {
+        return res;
+    };
+
+    let (mut parts, body) = res.into_parts();
+    let body = StreamReader::new(body.map_err(io::Error::other));
+    let body = match encoding {
+        // brotli's default is its slowest, meant for static files
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(
+            body,
+            Level::Precise(4),
+        ))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(body))),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+
+    Response::from_parts(parts, body)
+}
+
+// A request body, which may be gzipped, and mustn't be over `max` bytes, before or after
+// it's unzipped
+pub fn body(max: u64) -> impl Filter<Extract = (Vec<u8>,), Error = Rejection> + Clone {
+    limits::content_length(max)
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::stream())
+        .and_then(move |encoding: Option<String>, body| async move {
+            let body = read_body(body, max).await.map_err(warp::reject::custom)?;
+
+            decode(encoding.as_deref(), body, max).map_err(warp::reject::custom)
+        })
+}
+
+// As `warp::body::json`, for bodies as above
+pub fn json_body<T: DeserializeOwned + Send>(
+    max: u64,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    body(max).and_then(|body: Vec<u8>| async move {
+        serde_json::from_slice(&body).map_err(|e| {
+            warn!("couldn't parse request body: {e}");
+            warp::reject::custom(Error::BadRequest)
+        })
+    })
+}
+
+async fn read_body<B: Buf>(
+    body: impl Stream<Item = std::result::Result<B, warp::Error>>,
+    max: u64,
+) -> Result<Vec<u8>> {
+    pin_mut!(body);
+    let mut read = vec![];
+
+    while let Some(mut chunk) = body.try_next().await.map_err(|e| {
+        warn!("couldn't read request body: {e}");
+        Error::BadRequest
+    })? {
+        if (read.len() + chunk.remaining()) as u64 > max {
+            warn!("refusing a request body over {max} bytes");
+            return Err(Error::PayloadTooLarge);
+        }
+        read.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    Ok(read)
+}
+
+fn decode(encoding: Option<&str>, body: Vec<u8>, max: u64) -> Result<Vec<u8>> {
+    match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("identity") => Ok(body),
+        Some("gzip" | "x-gzip") => {
+            let mut unzipped = vec![];
+            GzDecoder::new(&body[..])
+                .take(max + 1)
+                .read_to_end(&mut unzipped)
+                .map_err(|e| {
+                    warn!("couldn't unzip request body: {e}");
+                    Error::BadRequest
+                })?;
+
+            if unzipped.len() as u64 > max {
+                warn!("refusing a request body over {max} bytes, once unzipped");
+                return Err(Error::PayloadTooLarge);
+            }
+            Ok(unzipped)
+        }
+        Some(encoding) => {
+            warn!("unsupported request body encoding {encoding:?}");
+            Err(Error::UnsupportedMediaType)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn negotiate() {
+        let cases = [
+            ("", None),
+            ("identity", None),
+            ("gzip", Some(Encoding::Gzip)),
+            ("gzip, deflate, br", Some(Encoding::Brotli)),
+            ("br;q=0.5, gzip", Some(Encoding::Gzip)),
+            ("br;q=0, gzip;q=0", None),
+            ("*", Some(Encoding::Brotli)),
+            ("*;q=0.1, gzip;q=0.2", Some(Encoding::Gzip)),
+            ("GZIP;q=1.0", Some(Encoding::Gzip)),
+            ("gzip;q=nonsense", None),
+        ];
+
+        for (accept, expected) in cases {
+            assert_eq!(Encoding::negotiate(accept), expected, "{accept:?}");
+        }
+    }
+
+    #[test]
+    fn gzipped_body() {
+        let body = b"[1, 2, 3]".to_vec();
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(&body).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+
+        assert_eq!(decode(Some("gzip"), gzipped.clone(), 9).unwrap(), body);
+        assert_eq!(decode(None, body.clone(), 9).unwrap(), body);
+        assert!(matches!(
+            decode(Some("gzip"), body, 9),
+            Err(Error::BadRequest)
+        ));
+        assert!(matches!(
+            decode(Some("br"), gzipped.clone(), 9),
+            Err(Error::UnsupportedMediaType)
+        ));
+        // it's the unzipped size that counts
+        assert!(matches!(
+            decode(Some("gzip"), gzipped, 8),
+            Err(Error::PayloadTooLarge)
+        ));
+    }
+}
@@ -1,6 +1,7 @@
-use serde::{Deserialize, Serialize};
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, sqlx::Type, Serialize)]
+#[derive(Debug, Serialize)]
 pub struct DeviceAndSub {
     pub id: String,
     pub caption: String,
@@ -11,11 +12,13 @@ pub struct DeviceAndSub {
 #[derive(Debug, Deserialize)]
 pub struct DeviceUpdate {
     pub caption: Option<String>,
+    // missing leaves it unchanged, but `null` is `Other`, as we write it
+    #[serde(default, deserialize_with = "present")]
     pub r#type: Option<DeviceType>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type, Clone)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, sqlx::Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
 pub enum DeviceType {
     Desktop,
     Laptop,
@@ -30,19 +33,60 @@ impl Default for DeviceType {
     }
 }
 
-// mod null_type {
-//     deserialize
-//     serialize
-// }
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Desktop => "desktop",
+            Self::Laptop => "laptop",
+            Self::Mobile => "mobile",
+            Self::Server => "server",
+            Self::Other => "other",
+        }
+    }
+}
+
+// gpodder uses `null` for an unknown/other device type, and clients aren't
+// consistent with their casing - so we're lenient on the way in and degrade
+// anything unrecognised to `Other`, rather than rejecting the device update
+impl Serialize for DeviceType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Other => serializer.serialize_none(),
+            _ => serializer.serialize_str(self.as_str()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = Option::<String>::deserialize(deserializer)?;
+
+        Ok(match s {
+            Some(s) => Self::try_from(&*s).unwrap_or_else(|()| {
+                warn!("unknown device type {s:?}, treating as \"other\"");
+                Self::Other
+            }),
+            None => Self::Other,
+        })
+    }
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DeviceType>, D::Error> {
+    DeviceType::deserialize(deserializer).map(Some)
+}
 
 impl TryFrom<&'_ str> for DeviceType {
     type Error = ();
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        match s {
-            "Mobile" => Ok(DeviceType::Mobile),
-            _ => Err(()),
-        }
+        Ok(match &*s.to_ascii_lowercase() {
+            "desktop" => DeviceType::Desktop,
+            "laptop" => DeviceType::Laptop,
+            "mobile" => DeviceType::Mobile,
+            "server" => DeviceType::Server,
+            "other" | "null" => DeviceType::Other,
+            _ => return Err(()),
+        })
     }
 }
 
@@ -53,3 +97,54 @@ impl TryFrom<String> for DeviceType {
         Self::try_from(&*s)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(json: &str) -> DeviceUpdate {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn type_casing() {
+        assert_eq!(
+            parse(r#"{"type":"Mobile"}"#).r#type,
+            Some(DeviceType::Mobile)
+        );
+        assert_eq!(
+            parse(r#"{"type":"LAPTOP"}"#).r#type,
+            Some(DeviceType::Laptop)
+        );
+        assert_eq!(
+            parse(r#"{"type":"server"}"#).r#type,
+            Some(DeviceType::Server)
+        );
+    }
+
+    #[test]
+    fn type_unknown_or_null() {
+        assert_eq!(
+            parse(r#"{"type":"tablet"}"#).r#type,
+            Some(DeviceType::Other)
+        );
+        assert_eq!(parse(r#"{"type":null}"#).r#type, Some(DeviceType::Other));
+        assert_eq!(parse(r#"{}"#).r#type, None);
+    }
+
+    #[test]
+    fn type_round_trip() {
+        for r#type in [DeviceType::Mobile, DeviceType::Other] {
+            let json = format!(r#"{{"type":{}}}"#, serde_json::to_string(&r#type).unwrap());
+            assert_eq!(parse(&json).r#type, Some(r#type));
+        }
+    }
+
+    #[test]
+    fn type_serialize() {
+        let json = |r#type| serde_json::to_string(&r#type).unwrap();
+
+        assert_eq!(json(DeviceType::Desktop), r#""desktop""#);
+        assert_eq!(json(DeviceType::Other), "null");
+    }
+}
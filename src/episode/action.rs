@@ -1,4 +1,13 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
+use sqlx::{
+    decode::Decode,
+    encode::{Encode, IsNull},
+    error::BoxDynError,
+    sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef},
+    Sqlite, Type,
+};
 
 pub type TimePrimitive = i64;
 
@@ -8,20 +17,131 @@ pub enum EpisodeAction {
     New,
     Download,
     Play {
-        started: TimePrimitive,
+        started: Option<TimePrimitive>,
         position: TimePrimitive,
-        total: TimePrimitive,
+        total: Option<TimePrimitive>,
     },
     Delete,
+    Flattr,
+    // an action outside of the gpodder spec, kept verbatim for other clients
+    Other(String),
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[serde(from = "String", into = "String")]
 pub enum EpisodeActionRaw {
     New,
     Download,
     Play,
     Delete,
+    Flattr,
+    Other(String),
+}
+
+impl EpisodeActionRaw {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::New => "new",
+            Self::Download => "download",
+            Self::Play => "play",
+            Self::Delete => "delete",
+            Self::Flattr => "flattr",
+            Self::Other(s) => s,
+        }
+    }
+
+    // what we store in the database, matching the original `sqlx::Type` derive
+    fn as_db_str(&self) -> &str {
+        match self {
+            Self::New => "New",
+            Self::Download => "Download",
+            Self::Play => "Play",
+            Self::Delete => "Delete",
+            Self::Flattr => "Flattr",
+            Self::Other(s) => s,
+        }
+    }
+}
+
+impl From<String> for EpisodeActionRaw {
+    fn from(s: String) -> Self {
+        match &*s.to_ascii_lowercase() {
+            "new" => Self::New,
+            "download" => Self::Download,
+            "play" => Self::Play,
+            "delete" => Self::Delete,
+            "flattr" => Self::Flattr,
+            _ => Self::Other(s),
+        }
+    }
+}
+
+impl From<EpisodeActionRaw> for String {
+    fn from(raw: EpisodeActionRaw) -> Self {
+        match raw {
+            EpisodeActionRaw::Other(s) => s,
+            _ => raw.as_str().into(),
+        }
+    }
+}
+
+impl fmt::Display for EpisodeActionRaw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Type<Sqlite> for EpisodeActionRaw {
+    fn type_info() -> SqliteTypeInfo {
+        <String as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <String as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for EpisodeActionRaw {
+    fn encode_by_ref(&self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> IsNull {
+        <String as Encode<'q, Sqlite>>::encode(self.as_db_str().to_string(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Sqlite> for EpisodeActionRaw {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        <String as Decode<'r, Sqlite>>::decode(value).map(Into::into)
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+    use sqlx::Postgres;
+
+    use super::*;
+
+    impl Type<Postgres> for EpisodeActionRaw {
+        fn type_info() -> PgTypeInfo {
+            <String as Type<Postgres>>::type_info()
+        }
+
+        fn compatible(ty: &PgTypeInfo) -> bool {
+            <String as Type<Postgres>>::compatible(ty)
+        }
+    }
+
+    impl<'q> Encode<'q, Postgres> for EpisodeActionRaw {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+            <&str as Encode<'q, Postgres>>::encode(self.as_db_str(), buf)
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for EpisodeActionRaw {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            <String as Decode<'r, Postgres>>::decode(value).map(Into::into)
+        }
+    }
 }
 
 impl From<EpisodeAction>
@@ -44,16 +164,13 @@ impl From<EpisodeAction>
             EpisodeAction::New => (EpisodeActionRaw::New, None, None, None),
             EpisodeAction::Download => (EpisodeActionRaw::Download, None, None, None),
             EpisodeAction::Delete => (EpisodeActionRaw::Delete, None, None, None),
+            EpisodeAction::Flattr => (EpisodeActionRaw::Flattr, None, None, None),
+            EpisodeAction::Other(s) => (EpisodeActionRaw::Other(s), None, None, None),
             EpisodeAction::Play {
                 started,
                 position,
                 total,
-            } => (
-                EpisodeActionRaw::Play,
-                Some(started),
-                Some(position),
-                Some(total),
-            ),
+            } => (EpisodeActionRaw::Play, started, Some(position), total),
         }
     }
 }
@@ -80,12 +197,14 @@ impl
             (EpisodeActionRaw::New, _, _, _) => Self::New,
             (EpisodeActionRaw::Download, _, _, _) => Self::Download,
             (EpisodeActionRaw::Delete, _, _, _) => Self::Delete,
-            (EpisodeActionRaw::Play, Some(started), Some(position), Some(total)) => Self::Play {
+            (EpisodeActionRaw::Flattr, _, _, _) => Self::Flattr,
+            (EpisodeActionRaw::Other(s), _, _, _) => Self::Other(s),
+            (EpisodeActionRaw::Play, started, Some(position), total) => Self::Play {
                 started,
                 position,
                 total,
             },
-            _ => return Err("\"play\" without started/position/total"),
+            (EpisodeActionRaw::Play, _, None, _) => return Err("\"play\" without position"),
         })
     }
 }
@@ -17,23 +17,18 @@ pub struct Episode {
     pub device: Option<String>, // optional on from-client, not present on to-client
 }
 
+#[cfg(test)]
 impl Episode {
     pub fn hash(&self) -> String {
-        use std::{
-            collections::hash_map::DefaultHasher,
-            hash::{Hash, Hasher},
-        };
-
-        let mut hasher = DefaultHasher::new();
-        Hash::hash(self, &mut hasher);
-        hasher.finish().to_string()
+        EpisodeRaw::from(self.clone()).content_hash()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
 #[serde_with::skip_serializing_none]
 #[derive(Deserialize, Serialize)] // transitive, from Episode
-#[derive(sqlx::Type)]
+#[derive(sqlx::FromRow)]
 pub struct EpisodeRaw {
     pub device: Option<String>,
     pub podcast: String,
@@ -49,7 +44,47 @@ pub struct EpisodeRaw {
     pub modified: Option<Timestamp>, // for db, not for http
 }
 
+// bump when the canonical encoding below changes, existing rows are rehashed on startup
+pub const HASH_VERSION: i64 = 1;
+
 impl EpisodeRaw {
+    // A stable hash of the action (everything but `modified`), used to avoid
+    // bumping `modified` when a client resends what we already have.
+    //
+    // This is a sha256 over a canonical encoding: each field in a fixed order,
+    // as `name=-` when absent or `name=<length>:<value>` when present, newline separated.
+    // Times are RFC 3339 in UTC and action names are gpodder's lowercase ones.
+    pub fn content_hash(&self) -> String {
+        use std::fmt::Write;
+
+        fn field(out: &mut String, name: &str, value: Option<&str>) {
+            match value {
+                Some(v) => writeln!(out, "{name}={}:{v}", v.len()),
+                None => writeln!(out, "{name}=-"),
+            }
+            .expect("write to String")
+        }
+
+        let mut out = format!("podsync-episode-v{HASH_VERSION}\n");
+        let int = |i: Option<TimePrimitive>| i.map(|i| i.to_string());
+
+        field(&mut out, "device", self.device.as_deref());
+        field(&mut out, "podcast", Some(&self.podcast));
+        field(&mut out, "episode", Some(&self.episode));
+        field(
+            &mut out,
+            "timestamp",
+            self.timestamp.as_ref().map(Time::canonical).as_deref(),
+        );
+        field(&mut out, "guid", self.guid.as_deref());
+        field(&mut out, "action", Some(&self.action.to_string()));
+        field(&mut out, "started", int(self.started).as_deref());
+        field(&mut out, "position", int(self.position).as_deref());
+        field(&mut out, "total", int(self.total).as_deref());
+
+        sha256::digest(out)
+    }
+
     fn from_episode(ep: Episode, modified: Option<Timestamp>) -> Self {
         let Episode {
             podcast,
@@ -118,3 +153,30 @@ impl From<Episode> for EpisodeRaw {
         Self::from_episode(episode, None)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn content_hash_is_stable() {
+        let raw = EpisodeRaw {
+            device: Some("dev1".into()),
+            podcast: "pod1".into(),
+            episode: "ep1".into(),
+            timestamp: Some(Time::from_i64(5)),
+            guid: None,
+            action: EpisodeActionRaw::Play,
+            started: Some(0),
+            position: Some(30),
+            total: Some(60),
+            modified: None,
+        };
+
+        // if this changes, HASH_VERSION needs bumping
+        assert_eq!(
+            raw.content_hash(),
+            "6e54e858f9c844016c66bd2ce496bfec067a00adcb58b923efeea09b7ed5c327"
+        );
+    }
+}
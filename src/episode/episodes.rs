@@ -7,4 +7,7 @@ use crate::time::Timestamp;
 pub struct Episodes {
     pub timestamp: Timestamp,
     pub actions: Vec<Episode>,
+    // where the next page starts, when `limit`ed
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub next: Option<String>,
 }
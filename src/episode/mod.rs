@@ -1,12 +1,14 @@
 mod action;
 pub use action::{EpisodeAction, EpisodeActionRaw};
 
+// responses are streamed from `EpisodePage`s, this is for tests to read them whole
+#[cfg(test)]
 mod episodes;
+#[cfg(test)]
 pub use episodes::Episodes;
 
 mod episode;
-pub use episode::{Episode, EpisodeRaw};
+pub use episode::{Episode, EpisodeRaw, HASH_VERSION};
 
 mod time;
-#[cfg(test)]
-pub use self::time::Time;
+pub use self::time::{Time, TimeFormat};
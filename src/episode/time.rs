@@ -1,23 +1,40 @@
-use serde::{Deserialize, Serialize};
+use std::{cmp, hash};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    decode::Decode,
+    encode::{Encode, IsNull},
+    error::BoxDynError,
+    sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef},
+    Sqlite, Type,
+};
 use time::{
-    macros::{date, time},
-    PrimitiveDateTime,
+    format_description::well_known::{Iso8601, Rfc3339},
+    macros::{date, format_description, time},
+    OffsetDateTime, PrimitiveDateTime, UtcOffset,
 };
 
-// this struct exists to work around #[serde(with = ...)]
-// not handling Option for us
-#[derive(Debug, Clone, Hash)]
-#[cfg_attr(test, derive(PartialEq, Eq))]
-#[derive(Deserialize, Serialize)]
-#[serde(transparent)]
-#[derive(sqlx::Type)]
-#[sqlx(transparent)]
-pub struct Time(#[serde(with = "time_no_offset")] PrimitiveDateTime);
+// An episode action's time, always held (and stored) in UTC.
+//
+// Clients may send any RFC 3339 / ISO 8601 variant, but by default we
+// send back the offset-less format that AntennaPod expects.
+#[derive(Debug, Clone)]
+pub struct Time {
+    dt: PrimitiveDateTime,
+    format: TimeFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeFormat {
+    #[default]
+    NoOffset, // yyyy-MM-dd'T'HH:mm:ss, with any fraction of a second
+    Rfc3339,
+}
 
 impl Default for Time {
     fn default() -> Self {
         let dt = PrimitiveDateTime::new(date!(1970 - 01 - 01), time!(0:00));
-        Self(dt)
+        dt.into()
     }
 }
 
@@ -29,24 +46,219 @@ impl Time {
             date!(1970 - 01 - 01),
             Time::from_hms(0, 0, i.try_into().unwrap()).unwrap(),
         );
-        Self(dt)
+        dt.into()
+    }
+
+    pub fn from_unix_millis(ms: i64) -> Option<Self> {
+        OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000)
+            .ok()
+            .map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()).into())
+    }
+
+    // the representation used for content hashing, independent of `format`
+    pub fn canonical(&self) -> String {
+        self.dt
+            .assume_utc()
+            .format(&Rfc3339)
+            .expect("RFC 3339 formatting of a UTC time")
+    }
+
+    pub fn with_format(self, format: TimeFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let with_offset = OffsetDateTime::parse(s, &Rfc3339)
+            .or_else(|_| OffsetDateTime::parse(s, &Iso8601::DEFAULT))
+            .map(|dt| dt.to_offset(UtcOffset::UTC))
+            .map(|dt| PrimitiveDateTime::new(dt.date(), dt.time()));
+
+        // no offset given, assume UTC
+        let without_offset = || {
+            PrimitiveDateTime::parse(s, &Iso8601::DEFAULT).or_else(|_| {
+                PrimitiveDateTime::parse(
+                    s,
+                    format_description!(
+                        "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]]"
+                    ),
+                )
+            })
+        };
+
+        with_offset
+            .or_else(|_| without_offset())
+            .ok()
+            .map(Into::into)
     }
 }
 
-impl Into<PrimitiveDateTime> for Time {
-    fn into(self) -> PrimitiveDateTime {
-        self.0
+impl From<Time> for PrimitiveDateTime {
+    fn from(t: Time) -> Self {
+        t.dt
     }
 }
 
 impl From<PrimitiveDateTime> for Time {
     fn from(dt: PrimitiveDateTime) -> Self {
-        Self(dt)
+        Self {
+            dt,
+            format: Default::default(),
+        }
     }
 }
 
-time::serde::format_description!(
-    time_no_offset,
-    PrimitiveDateTime,
-    "[year]-[month]-[day]T[hour]:[minute]:[second]" // yyyy-MM-dd'T'HH:mm:ss
-);
+// the format is a presentation detail, only the time itself is compared
+impl PartialEq for Time {
+    fn eq(&self, other: &Self) -> bool {
+        self.dt == other.dt
+    }
+}
+
+impl Eq for Time {}
+
+impl PartialOrd for Time {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Time {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.dt.cmp(&other.dt)
+    }
+}
+
+impl hash::Hash for Time {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.dt.hash(state)
+    }
+}
+
+impl Serialize for Time {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let formatted = match self.format {
+            // a fraction would be lost on the way back, and with it the order of actions
+            // within the second, so it's only left off when there's none
+            TimeFormat::NoOffset if self.dt.nanosecond() == 0 => self.dt.format(
+                format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"),
+            ),
+            TimeFormat::NoOffset => self.dt.format(format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond]"
+            )),
+            TimeFormat::Rfc3339 => self.dt.assume_utc().format(&Rfc3339),
+        };
+
+        serializer.serialize_str(&formatted.map_err(serde::ser::Error::custom)?)
+    }
+}
+
+impl<'de> Deserialize<'de> for Time {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        Self::parse(&s).ok_or_else(|| de::Error::custom(format!("invalid timestamp {s:?}")))
+    }
+}
+
+impl Type<Sqlite> for Time {
+    fn type_info() -> SqliteTypeInfo {
+        <PrimitiveDateTime as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <PrimitiveDateTime as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for Time {
+    fn encode_by_ref(&self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> IsNull {
+        <PrimitiveDateTime as Encode<'q, Sqlite>>::encode_by_ref(&self.dt, buf)
+    }
+}
+
+impl<'r> Decode<'r, Sqlite> for Time {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        <PrimitiveDateTime as Decode<'r, Sqlite>>::decode(value).map(Into::into)
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+    use sqlx::Postgres;
+
+    use super::*;
+
+    impl Type<Postgres> for Time {
+        fn type_info() -> PgTypeInfo {
+            <PrimitiveDateTime as Type<Postgres>>::type_info()
+        }
+
+        fn compatible(ty: &PgTypeInfo) -> bool {
+            <PrimitiveDateTime as Type<Postgres>>::compatible(ty)
+        }
+    }
+
+    impl<'q> Encode<'q, Postgres> for Time {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+            <PrimitiveDateTime as Encode<'q, Postgres>>::encode_by_ref(&self.dt, buf)
+        }
+    }
+
+    impl<'r> Decode<'r, Postgres> for Time {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            <PrimitiveDateTime as Decode<'r, Postgres>>::decode(value).map(Into::into)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(s: &str) -> Time {
+        Time::parse(s).unwrap_or_else(|| panic!("couldn't parse {s:?}"))
+    }
+
+    #[test]
+    fn parse_variants() {
+        let expected: Time = PrimitiveDateTime::new(date!(2023 - 03 - 01), time!(10:00)).into();
+
+        assert_eq!(parse("2023-03-01T10:00:00"), expected);
+        assert_eq!(parse("2023-03-01T10:00:00Z"), expected);
+        assert_eq!(parse("2023-03-01T11:00:00+01:00"), expected);
+        assert_eq!(parse("2023-03-01 10:00:00"), expected);
+        assert_eq!(parse("20230301T100000Z"), expected);
+
+        let millis = parse("2023-03-01T10:00:00.250Z");
+        assert!(millis > expected);
+        assert_eq!(
+            serde_json::to_string(&millis).unwrap(),
+            r#""2023-03-01T10:00:00.25""#
+        );
+        assert_eq!(
+            serde_json::to_string(&expected).unwrap(),
+            r#""2023-03-01T10:00:00""#
+        );
+    }
+
+    #[test]
+    fn round_trip_millis() {
+        for ms in [1_677_664_800_001, 1_677_664_800_250, 1_677_664_800_999] {
+            let t = Time::from_unix_millis(ms).unwrap();
+            let json = serde_json::to_string(&t).unwrap();
+
+            assert_eq!(serde_json::from_str::<Time>(&json).unwrap(), t, "{json}");
+        }
+    }
+
+    #[test]
+    fn serialize_rfc3339() {
+        let t = parse("2023-03-01T11:00:00.5+01:00").with_format(TimeFormat::Rfc3339);
+
+        assert_eq!(
+            serde_json::to_string(&t).unwrap(),
+            r#""2023-03-01T10:00:00.5Z""#
+        );
+    }
+}
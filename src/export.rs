@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+
+use log::error;
+use quick_xml::{
+    events::{BytesDecl, BytesText, Event},
+    Writer,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::device::DeviceType;
+use crate::episode::{EpisodeRaw, Time};
+use crate::migrate;
+use crate::path_format::Format;
+use crate::podsync::{Error, Result};
+use crate::subscription::Subscription;
+use crate::time::{self, Timestamp};
+
+// bump on incompatible changes to `Export`, imports of other versions are refused
+pub const EXPORT_VERSION: u32 = 1;
+
+// Everything we hold for a user, for moving them to another instance
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct Export {
+    pub version: u32,
+    pub username: String,
+    #[serde(with = "time::millis")]
+    pub exported: Timestamp,
+    pub devices: Vec<ExportDevice>,
+    // including deleted subscriptions, so devices' histories survive the move
+    pub subscriptions: Vec<ExportSubscription>,
+    // every episode action, oldest first, as from `aggregated=false`
+    pub episodes: Vec<EpisodeRaw>,
+    // podsync has no per-user settings yet, this keeps a place for them
+    #[serde(default)]
+    pub settings: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ExportDevice {
+    pub id: String,
+    pub caption: String,
+    pub r#type: DeviceType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ExportSubscription {
+    pub device: String,
+    pub url: String,
+    #[serde(with = "time::millis")]
+    pub created: Timestamp,
+    #[serde(with = "time::millis::option", default)]
+    pub deleted: Option<Timestamp>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct ImportSummary {
+    pub devices: usize,
+    // those we didn't already hold
+    pub subscriptions: usize,
+    pub episodes: usize,
+    // episode actions older than what we hold, which only went into the history
+    pub stale_episodes: usize,
+}
+
+impl From<(String, Subscription)> for ExportSubscription {
+    fn from((device, sub): (String, Subscription)) -> Self {
+        Self {
+            device,
+            url: sub.url,
+            created: sub.created,
+            deleted: sub.deleted,
+        }
+    }
+}
+
+impl From<ExportSubscription> for (String, Subscription) {
+    fn from(sub: ExportSubscription) -> Self {
+        (
+            sub.device,
+            Subscription {
+                url: sub.url,
+                created: sub.created,
+                deleted: sub.deleted,
+            },
+        )
+    }
+}
+
+// OPML 2.0, for podcast apps that don't speak gpodder. We only have the feeds' urls,
+// so they're their own `text` too.
+pub fn to_opml(title: &str, urls: &[String]) -> String {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+
+    writer
+        .write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+        .and_then(|()| {
+            writer
+                .create_element("opml")
+                .with_attribute(("version", "2.0"))
+                .write_inner_content(|writer| {
+                    writer
+                        .create_element("head")
+                        .write_inner_content(|writer| {
+                            writer
+                                .create_element("title")
+                                .write_text_content(BytesText::new(title))
+                                .map(|_| ())
+                        })?
+                        .create_element("body")
+                        .write_inner_content(|writer| {
+                            for url in urls {
+                                writer
+                                    .create_element("outline")
+                                    .with_attribute(("type", "rss"))
+                                    .with_attribute(("text", &url[..]))
+                                    .with_attribute(("xmlUrl", &url[..]))
+                                    .write_empty()?;
+                            }
+                            Ok(())
+                        })
+                        .map(|_| ())
+                })
+                .map(|_| ())
+        })
+        .expect("writing XML to a Vec");
+
+    let mut opml = String::from_utf8(writer.into_inner()).expect("XML from strs is UTF-8");
+    opml.push('\n');
+    opml
+}
+
+// A list of feeds in any of gpodder's formats. JSONP wraps the JSON in a call to `jsonp`.
+pub fn to_podcast_list(
+    format: Format,
+    title: &str,
+    urls: &[String],
+    jsonp: Option<&str>,
+) -> Result<String> {
+    let json = || serde_json::to_string(urls).expect("strings serialize");
+
+    Ok(match format {
+        Format::Json => json(),
+        Format::Jsonp => {
+            // only a name, so it can't smuggle in script of its own
+            let callback = jsonp
+                .filter(|f| !f.is_empty())
+                .filter(|f| {
+                    f.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_$.".contains(c))
+                })
+                .ok_or_else(|| {
+                    error!("JSONP needs a callback name, given {jsonp:?}");
+                    Error::BadRequest
+                })?;
+
+            format!("{callback}({})", json())
+        }
+        Format::Opml => to_opml(title, urls),
+        Format::Txt => urls.iter().map(|url| format!("{url}\n")).collect(),
+        Format::Xml => {
+            let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+
+            writer
+                .write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+                .and_then(|()| {
+                    writer
+                        .create_element("podcasts")
+                        .write_inner_content(|writer| {
+                            for url in urls {
+                                writer
+                                    .create_element("podcast")
+                                    .write_inner_content(|writer| {
+                                        writer
+                                            .create_element("url")
+                                            .write_text_content(BytesText::new(url))
+                                            .map(|_| ())
+                                    })?;
+                            }
+                            Ok(())
+                        })
+                        .map(|_| ())
+                })
+                .expect("writing XML to a Vec");
+
+            let mut xml = String::from_utf8(writer.into_inner()).expect("XML from strs is UTF-8");
+            xml.push('\n');
+            xml
+        }
+    })
+}
+
+// A list of feeds uploaded in one of the formats gpodder takes them in: JSON, OPML or text
+pub fn from_podcast_list(format: Format, body: &[u8]) -> Result<Vec<String>> {
+    let body = std::str::from_utf8(body).map_err(|e| {
+        error!("podcast list isn't UTF-8: {e}");
+        Error::BadRequest
+    })?;
+
+    match format {
+        Format::Json => serde_json::from_str(body).map_err(|e| {
+            error!("couldn't parse podcast list: {e}");
+            Error::BadRequest
+        }),
+        Format::Opml => migrate::from_opml(body)
+            .map(|migration| migration.subscriptions)
+            .map_err(|e| {
+                error!("couldn't parse podcast list: {e}");
+                Error::BadRequest
+            }),
+        Format::Txt => Ok(body
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect()),
+        Format::Jsonp | Format::Xml => {
+            error!("podcast lists can't be uploaded as {format:?}");
+            Err(Error::BadRequest)
+        }
+    }
+}
+
+const CSV_HEADER: &[&str] = &[
+    "podcast",
+    "episode",
+    "guid",
+    "action",
+    "started",
+    "position",
+    "total",
+    "timestamp",
+    "device",
+    "modified",
+];
+
+// One row per episode action, with RFC 3339 times, for spreadsheets and the like
+pub fn to_csv(episodes: &[EpisodeRaw]) -> String {
+    fn row(csv: &mut String, fields: impl IntoIterator<Item = String>) {
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                csv.push(',');
+            }
+
+            // RFC 4180
+            if field.contains([',', '"', '\n', '\r']) {
+                csv.push('"');
+                csv.push_str(&field.replace('"', "\"\""));
+                csv.push('"');
+            } else {
+                csv.push_str(&field);
+            }
+        }
+        csv.push_str("\r\n");
+    }
+
+    let mut csv = String::new();
+    row(&mut csv, CSV_HEADER.iter().map(|h| h.to_string()));
+
+    for ep in episodes {
+        let int = |i: Option<i64>| i.map(|i| i.to_string()).unwrap_or_default();
+
+        row(
+            &mut csv,
+            [
+                ep.podcast.clone(),
+                ep.episode.clone(),
+                ep.guid.clone().unwrap_or_default(),
+                ep.action.to_string(),
+                int(ep.started),
+                int(ep.position),
+                int(ep.total),
+                ep.timestamp
+                    .as_ref()
+                    .map(Time::canonical)
+                    .unwrap_or_default(),
+                ep.device.clone().unwrap_or_default(),
+                ep.modified.map(|m| m.to_string()).unwrap_or_default(),
+            ],
+        );
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::episode::EpisodeActionRaw;
+
+    #[test]
+    fn opml() {
+        let opml = to_opml(
+            "bob's <subscriptions>",
+            &["https://example.com/feed?a=1&b=2".into()],
+        );
+
+        assert_eq!(
+            opml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0">
+  <head>
+    <title>bob&apos;s &lt;subscriptions&gt;</title>
+  </head>
+  <body>
+    <outline type="rss" text="https://example.com/feed?a=1&amp;b=2" xmlUrl="https://example.com/feed?a=1&amp;b=2"/>
+  </body>
+</opml>
+"#
+        );
+
+        // and we can read it back
+        let migration = crate::migrate::from_opml(&opml).unwrap();
+        assert_eq!(
+            migration.subscriptions,
+            vec!["https://example.com/feed?a=1&b=2"]
+        );
+    }
+
+    #[test]
+    fn podcast_lists() {
+        let urls = vec!["https://a.example/feed".to_string(), "b&c".into()];
+        let list = |format, jsonp| to_podcast_list(format, "title", &urls, jsonp);
+
+        assert_eq!(
+            list(Format::Json, None).unwrap(),
+            r#"["https://a.example/feed","b&c"]"#
+        );
+        assert_eq!(
+            list(Format::Jsonp, Some("cb")).unwrap(),
+            r#"cb(["https://a.example/feed","b&c"])"#
+        );
+        assert!(list(Format::Jsonp, None).is_err());
+        assert!(list(Format::Jsonp, Some("alert(1);cb")).is_err());
+        assert_eq!(
+            list(Format::Txt, None).unwrap(),
+            "https://a.example/feed\nb&c\n"
+        );
+        assert_eq!(
+            list(Format::Xml, None).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<podcasts>
+  <podcast>
+    <url>https://a.example/feed</url>
+  </podcast>
+  <podcast>
+    <url>b&amp;c</url>
+  </podcast>
+</podcasts>
+"#
+        );
+
+        // and those that can be uploaded read back
+        for format in [Format::Json, Format::Opml, Format::Txt] {
+            let list = list(format, None).unwrap();
+            assert_eq!(
+                from_podcast_list(format, list.as_bytes()).unwrap(),
+                urls,
+                "{format:?}"
+            );
+        }
+        assert!(from_podcast_list(Format::Xml, b"").is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let ep = EpisodeRaw {
+            podcast: "https://example.com/feed".into(),
+            episode: "https://example.com/1.mp3".into(),
+            timestamp: Some(Time::from_i64(30)),
+            guid: Some("a \"quoted\", guid".into()),
+            action: EpisodeActionRaw::Play,
+            started: Some(0),
+            position: Some(20),
+            total: None,
+            device: Some("dev1".into()),
+            modified: Some(Timestamp::from_millis(1_500)),
+        };
+
+        assert_eq!(
+            to_csv(&[ep]),
+            "podcast,episode,guid,action,started,position,total,timestamp,device,modified\r\n\
+            https://example.com/feed,https://example.com/1.mp3,\"a \"\"quoted\"\", guid\",play,0,20,,1970-01-01T00:00:30Z,dev1,1970-01-01T00:00:01.5Z\r\n"
+        );
+    }
+}
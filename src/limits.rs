@@ -0,0 +1,57 @@
+// Bounds on what clients can send, so none can have the server buffer or store
+// an unreasonable amount.
+
+use log::warn;
+use warp::{Filter, Rejection};
+
+use crate::podsync::Error;
+
+pub const MAX_URL: usize = 4096;
+pub const MAX_DEVICE_ID: usize = 128;
+pub const MAX_CAPTION: usize = 256;
+pub const MAX_USERNAME: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // bytes, of subscription and episode uploads
+    pub upload_body: u64,
+    // bytes, of any other request
+    pub body: u64,
+    // subscription changes or episode actions in an upload
+    pub upload_items: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            upload_body: 8 * 1024 * 1024,
+            body: 64 * 1024,
+            upload_items: 10_000,
+        }
+    }
+}
+
+// Turns away requests declaring a body over `max` bytes, before any of it's read.
+// Bodies without a length are counted as they're read, see `compression::json_body`.
+pub fn content_length(max: u64) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |length: Option<u64>| async move {
+            match length {
+                Some(length) if length > max => {
+                    warn!("refusing a request body of {length} bytes, over {max}");
+                    Err(warp::reject::custom(Error::PayloadTooLarge))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+// a client's string, if it's no more than `max` bytes, otherwise why not
+pub fn bounded(field: &str, value: &str, max: usize) -> Result<(), String> {
+    if value.len() > max {
+        Err(format!("{field} is over {max} bytes"))
+    } else {
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
-use std::{future::Future, sync::Arc};
+use std::{future::Future, io, sync::Arc, time::Duration};
 
 use ::time::ext::NumericalDuration;
 use cookie::{Cookie, SameSite};
+use futures_util::stream;
 use serde::{Deserialize, Serialize};
 use warp::{
     http::{
@@ -27,22 +28,39 @@ mod subscription;
 
 mod episode;
 
+mod export;
+
+mod migrate;
+
 mod podsync;
-use podsync::{PodSync, PodSyncAuthed};
+use podsync::{EpisodePage, PodSync, PodSyncAuthed};
+
+mod store;
+use store::{MemoryStore, SqliteStore, Store};
 
 mod time;
 use crate::time::Timestamp;
 
 mod path_format;
-use path_format::split_format_json;
+use path_format::{split_format, split_format_json, Format};
 
 mod args;
-use args::Args;
+use args::{Args, Command};
+
+mod backup;
+
+mod cli;
+
+mod compression;
+
+mod limits;
+
+mod ui;
 
 #[cfg(test)]
 mod mock;
 
-static DB_URL: &str = "sqlite://pod.sql";
+static DEMO_USER: &str = "demo"; // --ephemeral
 static COOKIE_NAME: &str = "sessionid"; // gpodder/mygpo, doc/api/reference/auth.rst:16
 
 #[derive(Debug, Deserialize)]
@@ -50,15 +68,108 @@ pub struct QuerySince {
     since: crate::time::Timestamp,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct QueryJsonp {
+    // the callback a `.jsonp` response calls
+    jsonp: Option<String>,
+}
+
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
 
     let args = <Args as clap::Parser>::parse();
 
-    match Sqlite::create_database(DB_URL).await {
+    // before we open (and migrate) the database we're replacing
+    if let Some(Command::Restore { snapshot }) = args.command() {
+        let restored = backup::restore(args.database(), snapshot).await;
+        std::process::exit(if restored.is_ok() { 0 } else { 1 });
+    }
+
+    let store: Arc<dyn Store> = if args.ephemeral() {
+        info!("Using an in-memory store, log in as {DEMO_USER}:{DEMO_USER}");
+
+        let store = MemoryStore::default();
+        store
+            .create_user(DEMO_USER, &auth::pwhash(DEMO_USER))
+            .await
+            .expect("creating demo user");
+        store
+            .set_admin(DEMO_USER, true)
+            .await
+            .expect("making demo user an admin");
+
+        Arc::new(store)
+    } else {
+        let url = args.database();
+
+        match url.split_once("://").map(|(scheme, _)| scheme) {
+            Some("sqlite") => open_sqlite(url).await,
+            #[cfg(feature = "postgres")]
+            Some("postgres" | "postgresql") => open_postgres(url).await,
+            #[cfg(not(feature = "postgres"))]
+            Some("postgres" | "postgresql") => {
+                panic!("podsync was built without postgres support (`--features postgres`)")
+            }
+            _ => panic!("unsupported database {url:?}"),
+        }
+    };
+
+    let secure = args.secure();
+    let podsync = Arc::new(
+        PodSync::new(store)
+            .with_limits(args.limits())
+            .with_registration(args.registration()),
+    );
+
+    podsync.rehash_episodes().await.expect("rehashing episodes");
+
+    if let Some(command) = args.command() {
+        if cli::run(&podsync, command).await.is_err() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(dir) = args.backup_dir() {
+        let options = args.backup();
+
+        tokio::spawn(backup::schedule(
+            Arc::clone(&podsync),
+            dir.to_path_buf(),
+            args.backup_every(),
+            options.compress,
+            options.retention(),
+        ));
+    }
+
+    if args.prune().any() {
+        let podsync = Arc::clone(&podsync);
+        let options = args.prune();
+        let (subscriptions, episodes) = (options.prune_subscriptions, options.prune_episodes);
+
+        tokio::spawn(async move {
+            let mut daily = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+
+            loop {
+                daily.tick().await;
+                // failures are logged, and we'll try again tomorrow
+                let _ = podsync.prune(subscriptions, episodes).await;
+            }
+        });
+    }
+
+    let routes = routes(podsync, secure);
+
+    warp::serve(routes)
+        .run(args.addr().expect("couldn't parse address"))
+        .await;
+}
+
+async fn open_sqlite(url: &str) -> Arc<dyn Store> {
+    match Sqlite::create_database(url).await {
         Ok(()) => {
-            info!("Using {}", DB_URL);
+            info!("Using {}", url);
         }
         Err(e) => {
             let sqlx::Error::Database(db_err) = e else {
@@ -69,27 +180,35 @@ async fn main() {
         }
     }
 
-    let db = SqlitePool::connect(DB_URL).await.expect("DB connection");
+    let db = SqlitePool::connect(url).await.expect("DB connection");
 
     sqlx::migrate!("./migrations")
         .run(&db)
         .await
         .expect("migration");
 
-    let secure = args.secure();
-    let podsync = Arc::new(PodSync::new(db));
+    Arc::new(SqliteStore::new(db))
+}
 
-    let routes = routes(podsync, secure);
+#[cfg(feature = "postgres")]
+async fn open_postgres(url: &str) -> Arc<dyn Store> {
+    let db = sqlx::PgPool::connect(url).await.expect("DB connection");
+    info!("Using postgres"); // the url may hold a password
 
-    warp::serve(routes)
-        .run(args.addr().expect("couldn't parse address"))
-        .await;
+    sqlx::migrate!("./migrations/postgres")
+        .run(&db)
+        .await
+        .expect("migration");
+
+    Arc::new(store::PostgresStore::new(db))
 }
 
 fn routes(
     podsync: Arc<PodSync>,
     secure: bool,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let limits = podsync.limits();
+
     let hello = warp::path::end()
         .and(warp::get())
         .map(|| "PodSync is Working!");
@@ -97,6 +216,7 @@ fn routes(
     let auth = {
         let login = warp::post()
             .and(warp::path!("api" / "2" / "auth" / String / "login.json"))
+            .and(limits::content_length(limits.body))
             .and(warp::header::optional("authorization"))
             .and(warp::cookie::optional(COOKIE_NAME))
             .then({
@@ -137,6 +257,7 @@ fn routes(
             .and(warp::path!(
                 "api" / "2" / "auth" / .. /* String / "logout.json" */
             ))
+            .and(limits::content_length(limits.body))
             .and(authorize(UsernameFormat::Name, podsync.clone()))
             .and(warp::path::path("logout.json").and(warp::path::end()))
             .then(move |podsync: PodSyncAuthed<true>| {
@@ -163,7 +284,7 @@ fn routes(
             .and(warp::post())
             .and(authorize(UsernameFormat::Name, podsync.clone()))
             .and(warp::path::param::<String>().and(warp::path::end()))
-            .and(warp::body::json())
+            .and(compression::json_body(limits.body))
             .then(
                 move |podsync: PodSyncAuthed<true>, deviceid_format: String, device| {
                     result_to_ok(async move {
@@ -182,12 +303,20 @@ fn routes(
             .and(authorize(UsernameFormat::Name, podsync.clone()))
             .and(warp::path::param::<String>().and(warp::path::end()))
             .and(warp::query())
+            .and(conditions())
             .then(
-                move |podsync: PodSyncAuthed<true>, deviceid_format: String, query: QuerySince| {
-                    result_to_json(async move {
-                        let device_id = split_format_json(&deviceid_format)?;
-                        podsync.subscriptions(device_id, query.since).await
+                move |podsync: PodSyncAuthed<true>,
+                      deviceid_format: String,
+                      query: QuerySince,
+                      conditions: Conditions| async move {
+                    conditional(&podsync, conditions, async {
+                        result_to_json(async {
+                            let device_id = split_format_json(&deviceid_format)?;
+                            podsync.subscriptions(device_id, query.since).await
+                        })
+                        .await
                     })
+                    .await
                 },
             );
 
@@ -195,7 +324,7 @@ fn routes(
             .and(warp::post())
             .and(authorize(UsernameFormat::Name, podsync.clone()))
             .and(warp::path::param::<String>().and(warp::path::end()))
-            .and(warp::body::json())
+            .and(compression::json_body(limits.upload_body))
             .then(
                 move |podsync: PodSyncAuthed<true>, deviceid_format: String, changes| {
                     result_to_json(async move {
@@ -215,9 +344,15 @@ fn routes(
             .and(authorize(UsernameFormat::NameJson, podsync.clone()))
             .and(warp::path::end())
             .and(warp::query())
+            .and(conditions())
             .then(
-                move |podsync: PodSyncAuthed<true>, query: podsync::QueryEpisodes| {
-                    result_to_json(async move { podsync.episodes(query).await })
+                move |podsync: PodSyncAuthed<true>,
+                      query: podsync::QueryEpisodes,
+                      conditions: Conditions| async move {
+                    conditional(&podsync, conditions, async {
+                        result_to_json_stream(podsync.episode_page(query)).await
+                    })
+                    .await
                 },
             );
 
@@ -225,19 +360,153 @@ fn routes(
             .and(warp::post())
             .and(authorize(UsernameFormat::NameJson, podsync.clone()))
             .and(warp::path::end())
-            .and(warp::body::json())
-            .then(move |podsync: PodSyncAuthed<true>, body| {
-                result_to_json(async move { podsync.update_episodes(body).await })
-            });
+            .and(warp::query())
+            .and(compression::json_body(limits.upload_body))
+            .then(
+                move |podsync: PodSyncAuthed<true>, query: podsync::QueryUpload, body| {
+                    result_to_json(async move { podsync.upload_episodes(body, query).await })
+                },
+            );
 
         get.or(upload)
     };
 
-    hello
+    // gpodder's simple API, in any of the formats it allows
+    let simple = {
+        let get_user = warp::path("subscriptions")
+            .and(warp::get())
+            .and(segments(1))
+            .and(warp::path::peek())
+            .and(authorize(UsernameFormat::NameFormat, podsync.clone()))
+            .and(warp::path::end())
+            .and(warp::query())
+            .and(conditions())
+            .then(
+                |path: warp::path::Peek,
+                 podsync: PodSyncAuthed<true>,
+                 query: QueryJsonp,
+                 conditions: Conditions| async move {
+                    conditional(&podsync, conditions, async {
+                        result_to_formatted(async {
+                            let (_, format) = split_format(path.as_str())?;
+                            let list = podsync
+                                .subscription_list(None, format, query.jsonp.as_deref())
+                                .await?;
+                            Ok((format, list))
+                        })
+                        .await
+                    })
+                    .await
+                },
+            );
+
+        let get_device = warp::path("subscriptions")
+            .and(warp::get())
+            .and(segments(2))
+            .and(authorize(UsernameFormat::Name, podsync.clone()))
+            .and(warp::path::param::<String>().and(warp::path::end()))
+            .and(warp::query())
+            .and(conditions())
+            .then(
+                |podsync: PodSyncAuthed<true>,
+                 deviceid_format: String,
+                 query: QueryJsonp,
+                 conditions: Conditions| async move {
+                    conditional(&podsync, conditions, async {
+                        result_to_formatted(async {
+                            let (device_id, format) = split_format(&deviceid_format)?;
+                            let list = podsync
+                                .subscription_list(Some(device_id), format, query.jsonp.as_deref())
+                                .await?;
+                            Ok((format, list))
+                        })
+                        .await
+                    })
+                    .await
+                },
+            );
+
+        let put_device = warp::path("subscriptions")
+            .and(warp::put())
+            .and(authorize(UsernameFormat::Name, podsync.clone()))
+            .and(warp::path::param::<String>().and(warp::path::end()))
+            .and(compression::body(limits.upload_body))
+            .then(
+                |podsync: PodSyncAuthed<true>, deviceid_format: String, body: Vec<u8>| {
+                    result_to_ok(async move {
+                        let (device_id, format) = split_format(&deviceid_format)?;
+                        podsync
+                            .put_subscription_list(device_id, format, &body)
+                            .await
+                    })
+                },
+            );
+
+        get_user.or(get_device).or(put_device)
+    };
+
+    let podsync_api = {
+        let export = warp::path!("api" / "podsync" / "export")
+            .and(warp::get())
+            .and(session_authorize(podsync.clone()))
+            .then(|podsync: PodSyncAuthed<true>| {
+                result_to_json(async move { podsync.export().await })
+            });
+
+        let opml = warp::path!("api" / "podsync" / "subscriptions.opml")
+            .and(warp::get())
+            .and(session_authorize(podsync.clone()))
+            .and(warp::query())
+            .then(|podsync: PodSyncAuthed<true>, query: podsync::QueryOpml| {
+                result_to_text(
+                    async move { podsync.export_opml(query).await },
+                    "text/x-opml; charset=utf-8",
+                )
+            });
+
+        let csv = warp::path!("api" / "podsync" / "episodes.csv")
+            .and(warp::get())
+            .and(session_authorize(podsync.clone()))
+            .then(|podsync: PodSyncAuthed<true>| {
+                result_to_text(
+                    async move { podsync.export_csv().await },
+                    "text/csv; charset=utf-8",
+                )
+            });
+
+        let register = warp::path!("api" / "podsync" / "register")
+            .and(warp::post())
+            .and(compression::json_body(limits.body))
+            .then({
+                let podsync = Arc::clone(&podsync);
+                move |body: podsync::Registering| {
+                    let podsync = Arc::clone(&podsync);
+
+                    result_to_ok(async move {
+                        podsync
+                            .register(&body.username, &body.password, body.invite.as_deref())
+                            .await
+                    })
+                }
+            });
+
+        export.or(opml).or(csv).or(register)
+    };
+
+    let api = hello
         .or(auth)
         .or(devices)
         .or(subscriptions)
         .or(episodes)
+        .or(simple)
+        .or(podsync_api)
+        .or(ui::routes(podsync, secure));
+
+    warp::header::optional("accept-encoding")
+        .and(api)
+        .map(|accept_encoding, reply| {
+            compression::compress(accept_encoding, Reply::into_response(reply))
+        })
         .with(warp::log::custom(|info| {
             use std::fmt::*;
 
@@ -288,6 +557,169 @@ where
     }
 }
 
+// Episode actions are written out as they're read, rather than gathered up first. As
+// for `Episodes`, though `timestamp` and `next` come last, once they're known.
+async fn result_to_json_stream<F>(f: F) -> impl warp::Reply
+where
+    F: Future<Output = podsync::Result<EpisodePage>>,
+{
+    const CHUNK: usize = 64 * 1024;
+
+    let page = match f.await {
+        Ok(page) => page,
+        Err(e) => return err_to_warp(e).into_response(),
+    };
+
+    // the page, and whether an action's been written yet
+    let body = stream::try_unfold(Some((page, false)), |state| async move {
+        let Some((mut page, mut written)) = state else {
+            return Ok(None);
+        };
+        let mut chunk = Vec::with_capacity(CHUNK);
+
+        if !written {
+            chunk.extend_from_slice(br#"{"actions":["#);
+        }
+
+        while chunk.len() < CHUNK {
+            match page.next().await {
+                Some(Ok(episode)) => {
+                    if written {
+                        chunk.push(b',');
+                    }
+                    serde_json::to_writer(&mut chunk, &episode)?;
+                    written = true;
+                }
+                Some(Err(_)) => {
+                    // too late for an error status, so the response is cut short
+                    return Err(io::Error::other("reading episodes"));
+                }
+                None => {
+                    let (timestamp, next) = page.finish();
+
+                    chunk.extend_from_slice(br#"],"timestamp":"#);
+                    serde_json::to_writer(&mut chunk, &timestamp)?;
+                    if let Some(next) = next {
+                        chunk.extend_from_slice(br#","next":"#);
+                        serde_json::to_writer(&mut chunk, &next)?;
+                    }
+                    chunk.push(b'}');
+
+                    return Ok(Some((chunk, None)));
+                }
+            }
+        }
+
+        Ok(Some((chunk, Some((page, written)))))
+    });
+
+    warp::reply::with_header(
+        warp::reply::Response::new(Body::wrap_stream(body)),
+        "content-type",
+        "application/json",
+    )
+    .into_response()
+}
+
+// A client's copy of what it's polling, as far as it's told us
+struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+fn conditions() -> impl Filter<Extract = (Conditions,), Error = Rejection> + Clone {
+    warp::header::optional("if-none-match")
+        .and(warp::header::optional("if-modified-since"))
+        .map(|if_none_match, if_modified_since| Conditions {
+            if_none_match,
+            if_modified_since,
+        })
+}
+
+// 304 if the client's copy is as new as the user's last change, otherwise the reply,
+// tagged with that change. It's read before the reply, so a change made meanwhile
+// costs the client a needless 200 next time, rather than being missed.
+async fn conditional<R: Reply>(
+    podsync: &PodSyncAuthed<true>,
+    conditions: Conditions,
+    reply: impl Future<Output = R>,
+) -> warp::reply::Response {
+    let (change, last_modified) = match podsync.last_change().await {
+        Ok(change) => change,
+        Err(e) => return err_to_warp(e).into_response(),
+    };
+    // weak, as a response's `timestamp` may be the time it was made
+    let etag = format!("W/\"{}\"", change.as_millis());
+
+    // If-Modified-Since only counts without If-None-Match (RFC 9110, 13.1.3)
+    let unchanged = match conditions {
+        Conditions {
+            if_none_match: Some(tags),
+            ..
+        } => tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/")),
+        Conditions {
+            if_modified_since: Some(since),
+            ..
+        } => match (last_modified, Timestamp::from_http_date(&since)) {
+            (Some(last_modified), Some(since)) => last_modified <= since,
+            _ => false,
+        },
+        _ => false,
+    };
+
+    let mut res = if unchanged {
+        let mut res = warp::reply::Response::new(Body::empty());
+        *res.status_mut() = http::StatusCode::NOT_MODIFIED;
+        res
+    } else {
+        reply.await.into_response()
+    };
+
+    if unchanged || res.status().is_success() {
+        let headers = res.headers_mut();
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            headers.insert(http::header::ETAG, etag);
+        }
+        if let Some(date) = last_modified.and_then(Timestamp::to_http_date) {
+            if let Ok(date) = HeaderValue::from_str(&date) {
+                headers.insert(http::header::LAST_MODIFIED, date);
+            }
+        }
+        // per user, and worth checking each time
+        headers.insert(
+            http::header::CACHE_CONTROL,
+            HeaderValue::from_static("private, no-cache"),
+        );
+    }
+
+    res
+}
+
+async fn result_to_formatted<F>(f: F) -> impl warp::Reply
+where
+    F: Future<Output = podsync::Result<(Format, String)>>,
+{
+    match f.await {
+        Ok((format, body)) => {
+            warp::reply::with_header(body, "content-type", format.content_type()).into_response()
+        }
+        Err(e) => err_to_warp(e).into_response(),
+    }
+}
+
+async fn result_to_text<F>(f: F, content_type: &'static str) -> impl warp::Reply
+where
+    F: Future<Output = podsync::Result<String>>,
+{
+    match f.await {
+        Ok(body) => warp::reply::with_header(body, "content-type", content_type).into_response(),
+        Err(e) => err_to_warp(e).into_response(),
+    }
+}
+
 async fn result_to_ok<F>(f: F) -> impl warp::Reply
 where
     F: Future<Output = podsync::Result<()>>,
@@ -331,6 +763,7 @@ fn err_to_warp(e: podsync::Error) -> impl warp::Reply {
 enum UsernameFormat {
     Name,
     NameJson,
+    NameFormat,
 }
 
 impl UsernameFormat {
@@ -338,10 +771,25 @@ impl UsernameFormat {
         match self {
             Self::Name => Ok(username),
             Self::NameJson => split_format_json(username),
+            Self::NameFormat => split_format(username).map(|(username, _)| username),
         }
     }
 }
 
+// Just paths with `n` segments to go, so of routes that start the same, only one
+// goes on to authorize
+fn segments(n: usize) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(move |rest: warp::path::Peek| async move {
+            if rest.segments().count() == n {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
 fn cookie_authorize(
     username_fmt: UsernameFormat,
     podsync: Arc<PodSync>,
@@ -393,6 +841,36 @@ fn authorize(
         .and_then(|auth: podsync::Result<_>| async move { auth.map_err(warp::reject::custom) })
 }
 
+// for paths without a username, acting on whoever the session or credentials belong to
+fn session_authorize(
+    podsync: Arc<PodSync>,
+) -> impl Filter<Extract = (PodSyncAuthed<true>,), Error = warp::Rejection> + Clone {
+    let cookie = warp::cookie(COOKIE_NAME).then({
+        let podsync = Arc::clone(&podsync);
+        move |session_id: SessionId| {
+            let podsync = Arc::clone(&podsync);
+            async move {
+                podsync
+                    .authenticate(session_id)
+                    .await
+                    .map(PodSyncAuthed::for_session_user)
+            }
+        }
+    });
+
+    let login = warp::header("authorization")
+        .and(warp::cookie::optional(COOKIE_NAME))
+        .then(move |auth: BasicAuth, session_id: Option<SessionId>| {
+            let podsync = Arc::clone(&podsync);
+            async move { podsync.login(auth.into_attempt(), session_id).await }
+        });
+
+    cookie
+        .or(login)
+        .unify()
+        .and_then(|auth: podsync::Result<_>| async move { auth.map_err(warp::reject::custom) })
+}
+
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
     if let Some(err) = err.find::<podsync::Error>() {
         Ok(err_to_warp(*err))
@@ -403,16 +881,27 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
 
 #[cfg(test)]
 mod test {
-    use sqlx::query;
-
     use super::*;
     use crate::mock;
+    use crate::user::Registration;
     use base64_light::base64_encode as base64;
 
-    #[tokio::test]
-    async fn hello() {
-        let db = mock::create_db().await;
-        let podsync = Arc::new(PodSync::new(db));
+    mock::store_tests!(
+        hello,
+        login_session,
+        export,
+        episode_pages,
+        conditional_requests,
+        deleted_device,
+        compression,
+        body_limits,
+        dotted_names_and_formats,
+        register
+    );
+
+    async fn hello(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        let podsync = Arc::new(PodSync::new(store));
         let filter = routes(podsync, true);
 
         let res = warp::test::request().path("/").reply(&filter).await;
@@ -420,25 +909,15 @@ mod test {
         assert_eq!(res.status(), 200);
     }
 
-    #[tokio::test]
-    async fn login_session() {
-        let db = mock::create_db().await;
+    async fn login_session(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
 
         // setup bob:abc
         let pass = "abc";
         let pwhash = auth::pwhash(pass);
-        query!(
-            r#"
-            INSERT INTO users
-            VALUES ("bob", ?, NULL);
-            "#,
-            pwhash,
-        )
-        .execute(&db)
-        .await
-        .unwrap();
+        store.create_user("bob", &pwhash).await.unwrap();
 
-        let podsync = Arc::new(PodSync::new(db));
+        let podsync = Arc::new(PodSync::new(store));
         let filter = routes(podsync, true);
         let bob_auth = format!("Basic {}", base64(&format!("{}:{}", "bob", pass)));
 
@@ -503,4 +982,554 @@ mod test {
             .await;
         assert_eq!(res.status(), 401);
     }
+
+    async fn export(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let podsync = Arc::new(PodSync::new(store));
+        let filter = routes(podsync, true);
+        let bob_auth = format!("Basic {}", base64("bob:abc"));
+
+        // there's no username in the path, the credentials give us bob's
+        let res = warp::test::request()
+            .path("/api/podsync/export")
+            .header("authorization", &bob_auth)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["username"], "bob");
+        assert_eq!(body["version"], export::EXPORT_VERSION);
+
+        let wrong_auth = format!("Basic {}", base64("bob:123"));
+        let res = warp::test::request()
+            .path("/api/podsync/export")
+            .header("authorization", &wrong_auth)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        for (path, content_type, start) in [
+            (
+                "/api/podsync/subscriptions.opml?device=phone",
+                "text/x-opml; charset=utf-8",
+                "<?xml",
+            ),
+            (
+                "/api/podsync/episodes.csv",
+                "text/csv; charset=utf-8",
+                "podcast,episode,",
+            ),
+        ] {
+            let res = warp::test::request()
+                .path(path)
+                .header("authorization", &bob_auth)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200, "{path}");
+            assert_eq!(res.headers()["content-type"], content_type);
+            assert!(res.body().starts_with(start.as_bytes()), "{path}");
+
+            let res = warp::test::request()
+                .path(path)
+                .header("authorization", &wrong_auth)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 401, "{path}");
+        }
+    }
+
+    async fn episode_pages(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let podsync = Arc::new(PodSync::new(store));
+        let filter = routes(podsync, true);
+        let bob_auth = format!("Basic {}", base64("bob:abc"));
+
+        let actions: Vec<_> = (1..=3)
+            .map(|i| {
+                serde_json::json!({
+                    "podcast": "pod1",
+                    "episode": format!("ep{i}"),
+                    "action": "download",
+                })
+            })
+            .collect();
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/2/episodes/bob.json")
+            .header("authorization", &bob_auth)
+            .json(&actions)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let get = |query: String| {
+            let (filter, bob_auth) = (filter.clone(), bob_auth.clone());
+            async move {
+                let res = warp::test::request()
+                    .path(&format!("/api/2/episodes/bob.json{query}"))
+                    .header("authorization", &bob_auth)
+                    .reply(&filter)
+                    .await;
+                let body = serde_json::from_slice::<serde_json::Value>(res.body()).ok();
+                (res.status(), body)
+            }
+        };
+        let episodes = |body: &serde_json::Value| {
+            body["actions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|action| action["episode"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // without a limit, it's all there as ever
+        let (status, body) = get("".into()).await;
+        assert_eq!(status, 200);
+        let body = body.unwrap();
+        assert_eq!(episodes(&body), vec!["ep1", "ep2", "ep3"]);
+        assert!(body["timestamp"].is_i64());
+        assert!(body.get("next").is_none());
+
+        let (_, body) = get("?limit=2".into()).await;
+        let body = body.unwrap();
+        assert_eq!(episodes(&body), vec!["ep1", "ep2"]);
+        let next = body["next"].as_str().unwrap();
+
+        let (_, body) = get(format!("?limit=2&cursor={next}")).await;
+        let body = body.unwrap();
+        assert_eq!(episodes(&body), vec!["ep3"]);
+        assert!(body.get("next").is_none());
+
+        // limits past what the database takes are as good as none
+        for limit in [usize::MAX, i64::MAX as usize + 1] {
+            let (status, body) = get(format!("?limit={limit}")).await;
+            assert_eq!(status, 200, "{limit}");
+            let body = body.unwrap();
+            assert_eq!(episodes(&body), vec!["ep1", "ep2", "ep3"]);
+            assert!(body.get("next").is_none());
+        }
+
+        let (status, _) = get("?cursor=nonsense".into()).await;
+        assert_eq!(status, 400);
+    }
+
+    async fn conditional_requests(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let clock = Arc::new(mock::Clock::default());
+        let podsync = Arc::new(PodSync::with_clock(store, Arc::clone(&clock) as _));
+        let filter = routes(podsync, true);
+        let bob_auth = format!("Basic {}", base64("bob:abc"));
+
+        let subscribe = |url: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/api/2/subscriptions/bob/dev1.json")
+                .header("authorization", &bob_auth)
+                .json(&serde_json::json!({ "add": [url], "remove": [] }))
+                .reply(&filter)
+        };
+        let get = |header: Option<(&str, &str)>| {
+            let mut req = warp::test::request()
+                .path("/api/2/subscriptions/bob/dev1.json?since=0")
+                .header("authorization", &bob_auth);
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            req.reply(&filter)
+        };
+
+        assert_eq!(subscribe("pod1").await.status(), 200);
+
+        let res = get(None).await;
+        assert_eq!(res.status(), 200);
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+        assert_eq!(etag, r#"W/"25000""#);
+        // the change's second isn't over yet
+        assert!(res.headers().get("last-modified").is_none());
+
+        let res = get(Some(("if-none-match", &etag))).await;
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+        assert_eq!(res.headers()["etag"], etag.as_str());
+
+        clock.advance(1_000);
+        let res = get(None).await;
+        assert_eq!(
+            res.headers()["last-modified"],
+            "Thu, 01 Jan 1970 00:00:25 GMT"
+        );
+        let res = get(Some(("if-modified-since", "Thu, 01 Jan 1970 00:00:25 GMT"))).await;
+        assert_eq!(res.status(), 304);
+        let res = get(Some(("if-modified-since", "Thu, 01 Jan 1970 00:00:24 GMT"))).await;
+        assert_eq!(res.status(), 200);
+
+        // a change is news to anyone with an older copy
+        assert_eq!(subscribe("pod2").await.status(), 200);
+        let res = get(Some(("if-none-match", &etag))).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["etag"], r#"W/"26000""#);
+
+        let res = warp::test::request()
+            .path("/api/2/episodes/bob.json")
+            .header("authorization", &bob_auth)
+            .header("if-none-match", r#"W/"26000""#)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 304);
+    }
+
+    async fn deleted_device(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let clock = Arc::new(mock::Clock::default());
+        let podsync = Arc::new(PodSync::with_clock(store, Arc::clone(&clock) as _));
+        let filter = routes(Arc::clone(&podsync), true);
+        let bob_auth = format!("Basic {}", base64("bob:abc"));
+
+        let request = |path: &str| {
+            warp::test::request()
+                .path(path)
+                .header("authorization", &bob_auth)
+        };
+        for device in ["dev1", "dev2"] {
+            let res = request(&format!("/api/2/subscriptions/bob/{device}.json"))
+                .method("POST")
+                .json(&serde_json::json!({ "add": [format!("{device}-pod")], "remove": [] }))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 200);
+            clock.advance(1_000);
+        }
+
+        let res = request("/subscriptions/bob.json").reply(&filter).await;
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+        assert_eq!(etag, r#"W/"26000""#);
+
+        // dev1 isn't the latest change, but its going is one
+        let authed = podsync
+            .login(auth::AuthAttempt::new("bob".into(), "abc".into()), None)
+            .await
+            .unwrap();
+        authed.delete_device("dev1").await.unwrap();
+
+        let res = request("/subscriptions/bob.json")
+            .header("if-none-match", &etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["etag"], r#"W/"27000""#);
+        let list: Vec<String> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(list, vec!["dev2-pod"]);
+
+        // and a client still syncing dev1 is told to drop its subscriptions
+        let res = request("/api/2/subscriptions/bob/dev1.json?since=25")
+            .reply(&filter)
+            .await;
+        let changes: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(changes["add"], serde_json::json!([]));
+        assert_eq!(changes["remove"], serde_json::json!(["dev1-pod"]));
+    }
+
+    async fn compression(backend: mock::Backend) {
+        use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+        use std::io::{Read, Write};
+
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let podsync = Arc::new(PodSync::new(store));
+        let filter = routes(podsync, true);
+        let bob_auth = format!("Basic {}", base64("bob:abc"));
+
+        let actions = serde_json::json!([{
+            "podcast": "pod1",
+            "episode": "ep1",
+            "action": "download",
+        }]);
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        serde_json::to_writer(&mut gzipped, &actions).unwrap();
+        gzipped.flush().unwrap();
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/2/episodes/bob.json")
+            .header("authorization", &bob_auth)
+            .header("content-encoding", "gzip")
+            .body(gzipped.finish().unwrap())
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let get = |accept_encoding: &'static str| {
+            warp::test::request()
+                .path("/api/2/episodes/bob.json")
+                .header("authorization", &bob_auth)
+                .header("accept-encoding", accept_encoding)
+                .reply(&filter)
+        };
+
+        let res = get("gzip, deflate").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(res.headers()["vary"], "accept-encoding");
+        let mut body = String::new();
+        GzDecoder::new(&res.body()[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["actions"][0]["episode"], "ep1");
+
+        let res = get("gzip;q=0.5, br").await;
+        assert_eq!(res.headers()["content-encoding"], "br");
+
+        let res = get("identity").await;
+        assert!(res.headers().get("content-encoding").is_none());
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["actions"][0]["episode"], "ep1");
+
+        // nothing to compress in an empty reply
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/2/devices/bob/dev1.json")
+            .header("authorization", &bob_auth)
+            .header("accept-encoding", "gzip")
+            .json(&serde_json::json!({ "caption": "phone" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("content-encoding").is_none());
+    }
+
+    async fn body_limits(backend: mock::Backend) {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let limits = limits::Limits {
+            upload_body: 1024,
+            body: 64,
+            upload_items: 2,
+        };
+        let podsync = Arc::new(PodSync::new(store).with_limits(limits));
+        let filter = routes(podsync, true);
+        let bob_auth = format!("Basic {}", base64("bob:abc"));
+
+        let upload = |body: Vec<u8>, gzipped: bool| {
+            let mut req = warp::test::request()
+                .method("POST")
+                .path("/api/2/episodes/bob.json")
+                .header("authorization", &bob_auth);
+            if gzipped {
+                req = req.header("content-encoding", "gzip");
+            }
+            req.body(body).reply(&filter)
+        };
+        let actions = |count: usize, name_len: usize| {
+            let actions: Vec<_> = (0..count)
+                .map(|i| {
+                    serde_json::json!({
+                        "podcast": "pod1",
+                        "episode": format!("ep{i}{}", "x".repeat(name_len)),
+                        "action": "download",
+                    })
+                })
+                .collect();
+            serde_json::to_vec(&actions).unwrap()
+        };
+
+        assert_eq!(upload(actions(2, 0), false).await.status(), 200);
+        // too many
+        assert_eq!(upload(actions(3, 0), false).await.status(), 413);
+        // too big
+        assert_eq!(upload(actions(1, 1024), false).await.status(), 413);
+
+        // and too big once unzipped
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(&actions(1, 1024)).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        assert!(gzipped.len() < 1024);
+        assert_eq!(upload(gzipped, true).await.status(), 413);
+
+        // everything else has a smaller limit, checked before anyone's logged in
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/2/auth/bob/login.json")
+            .body(vec![b' '; 65])
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 413);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/2/devices/bob/dev1.json")
+            .header("authorization", &bob_auth)
+            .json(&serde_json::json!({ "caption": "x".repeat(64) }))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 413);
+    }
+
+    async fn dotted_names_and_formats(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("john.doe", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let podsync = Arc::new(PodSync::new(store));
+        let filter = routes(podsync, true);
+        let auth = format!("Basic {}", base64("john.doe:abc"));
+
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", &auth)
+        };
+        let list = |path: &'static str| {
+            let req = request("GET", path);
+            let filter = &filter;
+            async move {
+                let res = req.reply(filter).await;
+                assert_eq!(res.status(), 200, "{path}");
+                let mut urls: Vec<_> = std::str::from_utf8(res.body())
+                    .unwrap()
+                    .lines()
+                    .map(String::from)
+                    .collect();
+                urls.sort();
+                urls
+            }
+        };
+
+        let res = request("GET", "/api/2/devices/john.doe.json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = request("PUT", "/subscriptions/john.doe/pixel.7.txt")
+            .body("pod1\npod2\n")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            list("/subscriptions/john.doe/pixel.7.txt").await,
+            vec!["pod1", "pod2"]
+        );
+
+        // a list replaces what's there
+        let res = request("PUT", "/subscriptions/john.doe/pixel.7.json")
+            .json(&["pod2", "pod3"])
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            list("/subscriptions/john.doe.txt").await,
+            vec!["pod2", "pod3"]
+        );
+        // which the advanced API sees as changes
+        let res = request("GET", "/api/2/subscriptions/john.doe/pixel.7.json?since=0")
+            .reply(&filter)
+            .await;
+        let changes: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(changes["remove"], serde_json::json!(["pod1"]));
+
+        let res = request("GET", "/subscriptions/john.doe.jsonp?jsonp=callback")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.headers()["content-type"], Format::Jsonp.content_type());
+        assert!(res.body().starts_with(b"callback(["));
+
+        let res = request("GET", "/subscriptions/john.doe/pixel.7.xml")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.headers()["content-type"], Format::Xml.content_type());
+
+        let res = request("GET", "/subscriptions/john.doe/pixel.7.opml")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.headers()["content-type"], Format::Opml.content_type());
+
+        for (method, path) in [
+            // a callback is needed
+            ("GET", "/subscriptions/john.doe.jsonp"),
+            ("GET", "/subscriptions/john.doe.html"),
+            // only the simple API has other formats
+            ("GET", "/api/2/devices/john.doe.opml"),
+            // devices are letters, digits, and _ . -
+            ("POST", "/api/2/subscriptions/john.doe/my%20phone.json"),
+        ] {
+            let res = request(method, path)
+                .json(&serde_json::json!({ "add": [], "remove": [] }))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), 400, "{method} {path}");
+        }
+    }
+
+    async fn register(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        let register = |podsync: Arc<PodSync>, body: &'static str| {
+            let filter = routes(podsync, true);
+            async move {
+                warp::test::request()
+                    .path("/api/podsync/register")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(body)
+                    .reply(&filter)
+                    .await
+                    .status()
+            }
+        };
+
+        // closed by default
+        let podsync = Arc::new(PodSync::new(Arc::clone(&store)));
+        let body = r#"{"username": "bob.b", "password": "abc"}"#;
+        assert_eq!(register(Arc::clone(&podsync), body).await, 403);
+
+        let podsync = Arc::new(PodSync::new(store).with_registration(Registration::Open));
+        assert_eq!(register(Arc::clone(&podsync), body).await, 200);
+        assert_eq!(register(Arc::clone(&podsync), body).await, 409);
+        let body = r#"{"username": "bob/b", "password": "abc"}"#;
+        assert_eq!(register(Arc::clone(&podsync), body).await, 400);
+
+        // usable in the API's paths straight away
+        let filter = routes(podsync, true);
+        let res = warp::test::request()
+            .path("/api/2/devices/bob.b.json")
+            .header("authorization", format!("Basic {}", base64("bob.b:abc")))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
 }
@@ -0,0 +1,240 @@
+use std::path::Path;
+
+use sqlx::{query_as, sqlite::SqliteConnectOptions, SqlitePool};
+
+use super::{Error, Migration};
+use crate::episode::{Episode, EpisodeAction, Time};
+
+// An AntennaPod database export ("Export database" in its settings). We take every
+// subscribed feed, and a play action for each episode that's been started or played.
+pub async fn from_antennapod(path: &Path, device: &str) -> Result<Migration, Error> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let db = SqlitePool::connect_with(options).await?;
+
+    read(&db, device).await
+}
+
+// podcast, episode, guid, read, position, duration, last played
+type Media = (String, String, Option<String>, i64, i64, i64, Option<i64>);
+
+async fn read(db: &SqlitePool, device: &str) -> Result<Migration, Error> {
+    // AntennaPod 3 also keeps feeds that were only previewed, with a `state` of 1
+    let (has_state,): (bool,) = query_as(
+        "
+        SELECT COUNT(*) > 0
+        FROM pragma_table_info('Feeds')
+        WHERE name = 'state'
+        ",
+    )
+    .fetch_one(db)
+    .await?;
+    let subscribed = if has_state {
+        "AND COALESCE(Feeds.state, 0) = 0"
+    } else {
+        ""
+    };
+
+    // local feeds are folders on the phone, no use to another device
+    let feeds: Vec<(String,)> = query_as(&format!(
+        "
+        SELECT download_url
+        FROM Feeds
+        WHERE download_url NOT LIKE 'antennapod_local:%'
+            {subscribed}
+        ORDER BY id
+        ",
+    ))
+    .fetch_all(db)
+    .await?;
+
+    // AntennaPod holds positions and durations in milliseconds, and either may be null
+    let media: Vec<Media> = query_as(&format!(
+        "
+        SELECT Feeds.download_url, FeedMedia.download_url,
+            FeedItems.item_identifier,
+            COALESCE(FeedItems.read, 0),
+            COALESCE(FeedMedia.position, 0), COALESCE(FeedMedia.duration, 0),
+            FeedMedia.last_played_time
+        FROM FeedMedia
+        JOIN FeedItems ON FeedItems.id = FeedMedia.feeditem
+        JOIN Feeds ON Feeds.id = FeedItems.feed
+        WHERE Feeds.download_url NOT LIKE 'antennapod_local:%'
+            {subscribed}
+            AND FeedMedia.download_url IS NOT NULL
+            AND (FeedMedia.position > 0 OR FeedItems.read = 1)
+        ORDER BY FeedMedia.last_played_time
+        ",
+    ))
+    .fetch_all(db)
+    .await?;
+
+    const PLAYED: i64 = 1;
+
+    let episodes = media
+        .into_iter()
+        .map(
+            |(podcast, episode, guid, read, position, duration, last_played)| {
+                // as AntennaPod's own sync does, played episodes are at their end
+                let position = if read == PLAYED && position == 0 {
+                    duration
+                } else {
+                    position
+                };
+
+                Episode {
+                    podcast,
+                    episode,
+                    timestamp: last_played
+                        .filter(|&ms| ms > 0)
+                        .and_then(Time::from_unix_millis),
+                    guid,
+                    action: EpisodeAction::Play {
+                        started: None,
+                        position: position / 1000,
+                        total: (duration > 0).then_some(duration / 1000),
+                    },
+                    device: Some(device.into()),
+                }
+            },
+        )
+        .collect();
+
+    Ok(Migration {
+        subscriptions: feeds.into_iter().map(|(url,)| url).collect(),
+        episodes,
+        rejected: 0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::Executor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn database() {
+        let db = SqlitePool::connect(":memory:").await.unwrap();
+
+        // just the parts of AntennaPod's schema we read
+        db.execute(
+            "
+            CREATE TABLE Feeds (id INTEGER PRIMARY KEY, download_url TEXT);
+            CREATE TABLE FeedItems (
+                id INTEGER PRIMARY KEY, feed INTEGER, item_identifier TEXT, read INTEGER
+            );
+            CREATE TABLE FeedMedia (
+                id INTEGER PRIMARY KEY, feeditem INTEGER, download_url TEXT,
+                position INTEGER, duration INTEGER, last_played_time INTEGER
+            );
+
+            INSERT INTO Feeds VALUES
+                (1, 'https://example.com/feed'),
+                (2, 'antennapod_local:content://folder');
+            INSERT INTO FeedItems VALUES
+                (1, 1, 'guid-1', 0),
+                (2, 1, 'guid-2', 1),
+                (3, 1, 'guid-3', -1),
+                (4, 2, 'local', 1);
+            INSERT INTO FeedMedia VALUES
+                (1, 1, 'https://example.com/1.mp3', 90500, 600000, 1677664800000),
+                (2, 2, 'https://example.com/2.mp3', 0, 300000, 0),
+                (3, 3, 'https://example.com/3.mp3', 0, 300000, 0),
+                (4, 4, 'content://folder/local.mp3', 0, 300000, 0);
+            ",
+        )
+        .await
+        .unwrap();
+
+        let migration = read(&db, "dev1").await.unwrap();
+
+        assert_eq!(migration.subscriptions, vec!["https://example.com/feed"]);
+
+        let actions: Vec<_> = migration
+            .episodes
+            .iter()
+            .map(|ep| (&ep.episode[..], ep.guid.as_deref(), &ep.action))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                // played, so at the end
+                (
+                    "https://example.com/2.mp3",
+                    Some("guid-2"),
+                    &EpisodeAction::Play {
+                        started: None,
+                        position: 300,
+                        total: Some(300),
+                    }
+                ),
+                (
+                    "https://example.com/1.mp3",
+                    Some("guid-1"),
+                    &EpisodeAction::Play {
+                        started: None,
+                        position: 90,
+                        total: Some(600),
+                    }
+                ),
+            ]
+        );
+
+        assert_eq!(migration.episodes[0].timestamp, None);
+        assert_eq!(
+            migration.episodes[1].timestamp,
+            Time::from_unix_millis(1_677_664_800_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn previews_and_nulls() {
+        let db = SqlitePool::connect(":memory:").await.unwrap();
+
+        // as AntennaPod 3 has them, with a feed that was only previewed
+        db.execute(
+            "
+            CREATE TABLE Feeds (id INTEGER PRIMARY KEY, download_url TEXT, state INTEGER);
+            CREATE TABLE FeedItems (
+                id INTEGER PRIMARY KEY, feed INTEGER, item_identifier TEXT, read INTEGER
+            );
+            CREATE TABLE FeedMedia (
+                id INTEGER PRIMARY KEY, feeditem INTEGER, download_url TEXT,
+                position INTEGER, duration INTEGER, last_played_time INTEGER
+            );
+
+            INSERT INTO Feeds VALUES
+                (1, 'https://example.com/feed', 0),
+                (2, 'https://example.com/preview', 1);
+            INSERT INTO FeedItems VALUES
+                (1, 1, 'guid-1', 1),
+                (2, 2, 'guid-2', 1);
+            INSERT INTO FeedMedia VALUES
+                (1, 1, 'https://example.com/1.mp3', NULL, NULL, NULL),
+                (2, 2, 'https://example.com/2.mp3', 0, 300000, 0);
+            ",
+        )
+        .await
+        .unwrap();
+
+        let migration = read(&db, "dev1").await.unwrap();
+
+        assert_eq!(migration.subscriptions, vec!["https://example.com/feed"]);
+        let actions: Vec<_> = migration
+            .episodes
+            .iter()
+            .map(|ep| (&ep.episode[..], &ep.action))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![(
+                "https://example.com/1.mp3",
+                &EpisodeAction::Play {
+                    started: None,
+                    position: 0,
+                    total: None,
+                }
+            )]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use log::warn;
+use serde::Deserialize;
+
+use super::{Error, Migration};
+use crate::episode::Episode;
+
+// Responses from gpodder.net's API, saved to files. Subscriptions from either
+// `/subscriptions/{username}.json` (podcasts) or `/subscriptions/{username}/{device}.json`
+// (urls), episode actions from `/api/2/episodes/{username}.json`.
+pub fn from_gpodder(
+    subscriptions: Option<&str>,
+    episodes: Option<&str>,
+    device: &str,
+) -> Result<Migration, Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Subscription {
+        Url(String),
+        Podcast { url: String },
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Actions {
+        Response { actions: Vec<serde_json::Value> },
+        List(Vec<serde_json::Value>),
+    }
+
+    let mut migration = Migration::default();
+
+    if let Some(subscriptions) = subscriptions {
+        let subscriptions: Vec<Subscription> = serde_json::from_str(subscriptions)?;
+
+        migration.subscriptions = subscriptions
+            .into_iter()
+            .map(|sub| match sub {
+                Subscription::Url(url) | Subscription::Podcast { url } => url,
+            })
+            .collect();
+    }
+
+    if let Some(episodes) = episodes {
+        let (Actions::Response { actions } | Actions::List(actions)) =
+            serde_json::from_str(episodes)?;
+
+        for (index, action) in actions.into_iter().enumerate() {
+            match serde_json::from_value::<Episode>(action) {
+                Ok(ep) => migration.episodes.push(Episode {
+                    // gpodder.net's device ids mean nothing here
+                    device: Some(device.into()),
+                    ..ep
+                }),
+                Err(e) => {
+                    warn!("skipping episode action #{index}: {e}");
+                    migration.rejected += 1;
+                }
+            }
+        }
+    }
+
+    Ok(migration)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::episode::EpisodeAction;
+
+    #[test]
+    fn api_responses() {
+        let podcasts = r#"[{ "url": "https://example.com/one", "title": "One" }]"#;
+        let urls = r#"["https://example.com/one", "https://example.com/two"]"#;
+        let episodes = r#"{
+            "actions": [
+                {
+                    "podcast": "https://example.com/one",
+                    "episode": "https://example.com/one/1.mp3",
+                    "device": "their-phone",
+                    "action": "play",
+                    "timestamp": "2023-03-01T10:00:00",
+                    "started": 0,
+                    "position": 120,
+                    "total": 600
+                },
+                { "podcast": "https://example.com/one", "action": "download" }
+            ],
+            "timestamp": 1677664800
+        }"#;
+
+        let migration = from_gpodder(Some(podcasts), None, "dev1").unwrap();
+        assert_eq!(migration.subscriptions, vec!["https://example.com/one"]);
+
+        let migration = from_gpodder(Some(urls), Some(episodes), "dev1").unwrap();
+        assert_eq!(migration.subscriptions.len(), 2);
+        assert_eq!(migration.rejected, 1); // no episode
+
+        let [ep] = &migration.episodes[..] else {
+            panic!("expected one episode, got {:?}", migration.episodes);
+        };
+        assert_eq!(ep.device.as_deref(), Some("dev1"));
+        assert_eq!(
+            ep.action,
+            EpisodeAction::Play {
+                started: Some(0),
+                position: 120,
+                total: Some(600),
+            }
+        );
+    }
+}
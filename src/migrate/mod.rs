@@ -0,0 +1,81 @@
+use std::{fmt, io};
+
+use serde::Serialize;
+
+use crate::episode::Episode;
+
+mod antennapod;
+mod gpodder;
+mod opml;
+
+pub use antennapod::from_antennapod;
+pub use gpodder::from_gpodder;
+pub use opml::from_opml;
+
+// What we could read from another service or app, for one of our users' devices
+#[derive(Debug, Default)]
+pub struct Migration {
+    pub subscriptions: Vec<String>,
+    pub episodes: Vec<Episode>,
+    // episode actions we couldn't make sense of, and so skipped
+    pub rejected: usize,
+}
+
+// What a migration changes (or would change, on a dry run)
+#[derive(Debug, Default, Serialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct MigrationReport {
+    pub subscriptions_added: Vec<String>,
+    pub subscriptions_held: usize,
+    pub episodes_new: usize,
+    pub episodes_updated: usize,
+    pub episodes_unchanged: usize,
+    // older than what we hold, these only go into the history
+    pub episodes_stale: usize,
+    pub episodes_rejected: usize,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Xml(quick_xml::Error),
+    Sql(sqlx::Error),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "{e}"),
+            Self::Json(e) => write!(fmt, "invalid JSON: {e}"),
+            Self::Xml(e) => write!(fmt, "invalid XML: {e}"),
+            Self::Sql(e) => write!(fmt, "couldn't read database: {e}"),
+            Self::Invalid(what) => write!(fmt, "{what}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(e: quick_xml::Error) -> Self {
+        Self::Xml(e)
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Sql(e)
+    }
+}
@@ -0,0 +1,78 @@
+use quick_xml::{events::Event, Reader};
+
+use super::{Error, Migration};
+
+// Every `<outline xmlUrl="...">`, however deeply nested in categories
+pub fn from_opml(opml: &str) -> Result<Migration, Error> {
+    let mut reader = Reader::from_str(opml);
+    let mut subscriptions = vec![];
+    let mut seen_opml = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"opml" => seen_opml = true,
+                b"outline" => {
+                    for attr in e.attributes() {
+                        let attr = attr.map_err(quick_xml::Error::from)?;
+
+                        if attr
+                            .key
+                            .local_name()
+                            .as_ref()
+                            .eq_ignore_ascii_case(b"xmlUrl")
+                        {
+                            let url = attr.unescape_value()?;
+                            let url = url.trim();
+
+                            if !url.is_empty() && !subscriptions.iter().any(|s| s == url) {
+                                subscriptions.push(url.to_string());
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if !seen_opml {
+        return Err(Error::Invalid("not an OPML document"));
+    }
+
+    Ok(Migration {
+        subscriptions,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn outlines() {
+        let opml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <opml version="2.0">
+                <head><title>Subscriptions</title></head>
+                <body>
+                    <outline text="Tech">
+                        <outline type="rss" text="One" xmlUrl="https://example.com/one?a=1&amp;b=2" />
+                    </outline>
+                    <outline type="rss" text="Two" xmlurl="https://example.com/two"></outline>
+                    <outline type="rss" text="One again" xmlUrl="https://example.com/one?a=1&amp;b=2" />
+                </body>
+            </opml>
+        "#;
+
+        let migration = from_opml(opml).unwrap();
+        assert_eq!(
+            migration.subscriptions,
+            vec!["https://example.com/one?a=1&b=2", "https://example.com/two"]
+        );
+
+        assert!(matches!(from_opml("<rss></rss>"), Err(Error::Invalid(_))));
+    }
+}
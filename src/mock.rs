@@ -1,13 +1,134 @@
-use sqlx::{migrate::MigrateDatabase, Pool, Sqlite, SqlitePool};
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+use sqlx::{migrate::MigrateDatabase, sqlite::SqlitePoolOptions, Pool, Sqlite};
+
+use crate::store::{Fixtures, MemoryStore, SqliteStore, Store};
+use crate::time::{self, Timestamp};
 
 pub async fn create_db() -> Pool<Sqlite> {
     let url = ":memory:";
 
     Sqlite::create_database(url).await.unwrap();
 
-    let db = SqlitePool::connect(url).await.unwrap();
+    // each connection to `:memory:` is a database of its own, so there's just the one
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect(url)
+        .await
+        .unwrap();
 
     sqlx::migrate!("./migrations").run(&db).await.unwrap();
 
     db
 }
+
+// each test gets a fresh database on the server at $PODSYNC_TEST_POSTGRES
+#[cfg(feature = "postgres")]
+pub async fn create_pg_db() -> sqlx::PgPool {
+    use sqlx::{postgres::PgConnectOptions, Connection, Executor, PgConnection, PgPool};
+
+    let url = std::env::var("PODSYNC_TEST_POSTGRES")
+        .expect("PODSYNC_TEST_POSTGRES should be the url of a postgres server to test against");
+    let options: PgConnectOptions = url.parse().unwrap();
+
+    let name = format!("podsync_test_{}", uuid::Uuid::new_v4().simple());
+    let mut conn = PgConnection::connect_with(&options).await.unwrap();
+    conn.execute(&*format!("CREATE DATABASE {name}"))
+        .await
+        .unwrap();
+
+    let db = PgPool::connect_with(options.database(&name)).await.unwrap();
+
+    sqlx::migrate!("./migrations/postgres")
+        .run(&db)
+        .await
+        .unwrap();
+
+    db
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backend {
+    Sqlite,
+    Memory,
+    #[cfg(feature = "postgres")]
+    Postgres,
+}
+
+pub async fn create_store(backend: Backend) -> (Arc<dyn Store>, Arc<dyn Fixtures>) {
+    match backend {
+        Backend::Sqlite => {
+            let store = Arc::new(SqliteStore::new(create_db().await));
+            (store.clone(), store)
+        }
+        Backend::Memory => {
+            let store = Arc::new(MemoryStore::default());
+            (store.clone(), store)
+        }
+        #[cfg(feature = "postgres")]
+        Backend::Postgres => {
+            let store = Arc::new(crate::store::PostgresStore::new(create_pg_db().await));
+            (store.clone(), store)
+        }
+    }
+}
+
+// run each `async fn test(backend: Backend)` against every store backend
+macro_rules! store_tests {
+    ($($name:ident),* $(,)?) => {
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name($crate::mock::Backend::Sqlite).await
+                }
+            )*
+        }
+
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name($crate::mock::Backend::Memory).await
+                }
+            )*
+        }
+
+        #[cfg(feature = "postgres")]
+        mod postgres {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name($crate::mock::Backend::Postgres).await
+                }
+            )*
+        }
+    };
+}
+pub(crate) use store_tests;
+
+pub const NOW: Timestamp = Timestamp::from_millis(25_000);
+
+// a clock that only moves when told to
+pub struct Clock(AtomicI64);
+
+impl Clock {
+    pub fn advance(&self, ms: i64) {
+        self.0.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self(AtomicI64::new(NOW.as_millis()))
+    }
+}
+
+impl time::Clock for Clock {
+    fn now(&self) -> Result<Timestamp, std::time::SystemTimeError> {
+        Ok(Timestamp::from_millis(self.0.load(Ordering::SeqCst)))
+    }
+}
@@ -1,13 +1,117 @@
+use std::str::FromStr;
+
 use crate::podsync::{Error, Result};
 
+// gpodder's response formats, as named by a path's extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Jsonp,
+    Opml,
+    Txt,
+    Xml,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "jsonp" => Ok(Self::Jsonp),
+            "opml" => Ok(Self::Opml),
+            "txt" => Ok(Self::Txt),
+            "xml" => Ok(Self::Xml),
+            _ => Err(Error::BadRequest),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Jsonp => "application/javascript; charset=utf-8",
+            Self::Opml => "text/x-opml; charset=utf-8",
+            Self::Txt => "text/plain; charset=utf-8",
+            Self::Xml => "application/xml; charset=utf-8",
+        }
+    }
+}
+
+// `{name}.{format}`, split at the last dot, as names may have dots of their own
+pub fn split_format(s: &str) -> Result<(&str, Format)> {
+    let (name, format) = s.rsplit_once('.').ok_or(Error::BadRequest)?;
+
+    if name.is_empty() {
+        return Err(Error::BadRequest);
+    }
+
+    Ok((name, format.parse()?))
+}
+
 pub fn split_format_json(s: &str) -> Result<&str> {
-    let (a, b) = s.split_once('.').ok_or(Error::BadRequest)?;
+    match split_format(s)? {
+        (name, Format::Json) => Ok(name),
+        _ => Err(Error::BadRequest),
+    }
+}
 
-    err_unless_json(b)?;
+// As gpodder.net has them: letters, digits, `_`, `.` and `-`. Only ASCII, as warp doesn't
+// percent-decode path segments, so anything else would never match its own path.
+pub fn valid_device_id(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
 
-    Ok(a)
+// Usable in any path, and unambiguous in `{username}.{format}` and basic auth
+pub fn valid_username(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with('.')
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '+' | '-'))
 }
 
-fn err_unless_json(s: &str) -> Result<()> {
-    (s == "json").then_some(()).ok_or(Error::BadRequest)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(split_format_json("bob.json").unwrap(), "bob");
+        assert_eq!(split_format_json("john.doe.json").unwrap(), "john.doe");
+        assert_eq!(
+            split_format("pixel.7.opml").unwrap(),
+            ("pixel.7", Format::Opml)
+        );
+
+        for bad in ["bob", "bob.", ".json", "bob.html", "bob.json.txt"] {
+            assert!(split_format_json(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn device_ids() {
+        assert!(valid_device_id("pixel-7_a.b"));
+
+        assert!(!valid_device_id(""));
+        assert!(!valid_device_id("my phone"));
+        assert!(!valid_device_id("phone+tablet"));
+        assert!(!valid_device_id("téléphone"));
+    }
+
+    #[test]
+    fn usernames() {
+        assert!(valid_username("john.doe+podcasts"));
+        assert!(valid_username("bob_1-2"));
+
+        assert!(!valid_username(""));
+        assert!(!valid_username(".hidden"));
+        assert!(!valid_username("bob:abc"));
+        assert!(!valid_username("bob/eve"));
+        assert!(!valid_username("bob smith"));
+        // it'd arrive as `jos%C3%A9.json`
+        assert!(!valid_username("josé"));
+    }
 }
@@ -1,18 +1,48 @@
-use std::{future::Future, result, str::FromStr, sync::Arc};
-
-use log::{error, info, trace};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::{Path, PathBuf},
+    pin::Pin,
+    result,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use base64_light::{base64_decode, base64url_encode};
+use futures_util::{stream::Peekable, StreamExt};
+use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, Pool, Sqlite, Transaction};
+use uuid::Uuid;
 use warp::http;
 
-use crate::auth::{AuthAttempt, SessionId};
+use crate::auth::{self, AuthAttempt, SessionId};
+use crate::backup;
 use crate::device::{DeviceAndSub, DeviceUpdate};
-use crate::episode::{Episode, EpisodeRaw, Episodes};
-use crate::subscription::{SubscriptionChangesFromClient, SubscriptionChangesToClient};
-use crate::time::Timestamp;
-use crate::user::User;
-
-pub struct PodSync(Pool<Sqlite>);
+#[cfg(test)]
+use crate::episode::Episodes;
+use crate::episode::{Episode, EpisodeRaw, Time, TimeFormat, HASH_VERSION};
+use crate::export::{self, Export, ExportDevice, ImportSummary, EXPORT_VERSION};
+use crate::limits::{self, Limits, MAX_CAPTION, MAX_DEVICE_ID, MAX_URL, MAX_USERNAME};
+use crate::migrate::{Migration, MigrationReport};
+use crate::path_format::{self, Format};
+use crate::store::{
+    is_stale, Diagnosis, EpisodeCursor, EpisodeFilter, EpisodeStream, Pruned, Store, UserSummary,
+};
+use crate::subscription::{
+    Subscription, SubscriptionChangesFromClient, SubscriptionChangesToClient,
+};
+use crate::time::{Clock, SystemClock, Timestamp};
+use crate::user::{Invite, Registration};
+
+// how many failed logins we remember, for the admin console
+const FAILED_LOGINS: usize = 100;
+
+pub struct PodSync {
+    store: Arc<dyn Store>,
+    clock: Arc<dyn Clock>,
+    limits: Limits,
+    registration: Registration,
+    failed_logins: Mutex<VecDeque<FailedLogin>>,
+}
 
 pub struct PodSyncAuthed<const USER_MATCH: bool = false> {
     sync: Arc<PodSync>,
@@ -20,21 +50,120 @@ pub struct PodSyncAuthed<const USER_MATCH: bool = false> {
     username: String,
 }
 
+// a logged in user who's an admin, see `PodSyncAuthed::admin`
+pub struct PodSyncAdmin {
+    sync: Arc<PodSync>,
+    username: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct FailedLogin {
+    pub username: String,
+    pub at: Timestamp,
+    pub reason: &'static str,
+}
+
+#[derive(Debug)]
+pub struct UserOverview {
+    pub summary: UserSummary,
+    pub last_active: Option<Timestamp>,
+}
+
+#[derive(Debug)]
+pub struct InviteOverview {
+    pub invite: Invite,
+    // neither used up nor expired
+    pub usable: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct InstanceStats {
+    pub users: usize,
+    pub admins: usize,
+    pub disabled: usize,
+    // users with a change in the last week
+    pub active: usize,
+    pub devices: i64,
+    pub subscriptions: i64,
+    pub episodes: i64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UpdatedUrls {
     // important: this timestamp is used by future client synchronisations
     timestamp: Timestamp,
     // unused by antennapod
     update_urls: Vec<(String, String)>,
+    // episode actions older than the ones we already have, and so ignored
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stale_actions: Vec<StaleAction>,
+    // episode actions we couldn't parse, in lenient mode
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rejected: Vec<RejectedAction>,
 }
 
+#[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct StaleAction {
+    podcast: String,
+    episode: String,
+    timestamp: Time,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct RejectedAction {
+    index: usize,
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryUpload {
+    // store the valid actions from an upload, rather than rejecting the whole batch
+    lenient: Option<bool>,
+}
+
+// the body of `POST /api/podsync/register`
 #[derive(Debug, Deserialize)]
+pub struct Registering {
+    pub username: String,
+    pub password: String,
+    pub invite: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryOpml {
+    // just this device's subscriptions, rather than all of the user's
+    device: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct QueryEpisodes {
     since: Option<Timestamp>,
-    #[allow(dead_code)]
     aggregated: Option<bool>,
     podcast: Option<String>,
     device: Option<String>,
+    // send full RFC 3339 timestamps, rather than the offset-less format AntennaPod expects
+    rfc3339: Option<bool>,
+    // at most this many actions, with a `next` cursor for the rest
+    limit: Option<usize>,
+    // a previous response's `next`, to carry on from
+    cursor: Option<String>,
+}
+
+// A response's episode actions, read from the store as they're sent. Its `timestamp`
+// and `next` are only known once they've all been read.
+pub struct EpisodePage {
+    username: String,
+    rows: Peekable<EpisodeStream>,
+    remaining: Option<usize>,
+    // the last action within the limit, and whether any came after it
+    end: Option<EpisodeCursor>,
+    more: bool,
+    time_format: TimeFormat,
+    latest: Option<Timestamp>,
+    count: usize,
+    now: Timestamp,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -42,6 +171,10 @@ pub enum Error {
     Internal,
     Unauthorized,
     BadRequest,
+    Forbidden,
+    Conflict,
+    UnsupportedMediaType,
+    PayloadTooLarge,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -52,6 +185,10 @@ impl Into<http::StatusCode> for Error {
             Self::Internal => http::StatusCode::INTERNAL_SERVER_ERROR,
             Self::Unauthorized => http::StatusCode::UNAUTHORIZED,
             Self::BadRequest => http::StatusCode::BAD_REQUEST,
+            Self::Forbidden => http::StatusCode::FORBIDDEN,
+            Self::Conflict => http::StatusCode::CONFLICT,
+            Self::UnsupportedMediaType => http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::PayloadTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
 }
@@ -59,8 +196,84 @@ impl Into<http::StatusCode> for Error {
 impl warp::reject::Reject for Error {}
 
 impl PodSync {
-    pub fn new(db: Pool<Sqlite>) -> Self {
-        Self(db)
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        Self::with_clock(store, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(store: Arc<dyn Store>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            store,
+            clock,
+            limits: Limits::default(),
+            registration: Registration::default(),
+            failed_logins: Default::default(),
+        }
+    }
+
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Self { limits, ..self }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn with_registration(self, registration: Registration) -> Self {
+        Self {
+            registration,
+            ..self
+        }
+    }
+
+    pub fn registration(&self) -> Registration {
+        self.registration
+    }
+
+    // Creates an account for whoever asks, as `--registration` allows. Invites are only
+    // needed (and only used up) under `Registration::Invite`.
+    pub async fn register(
+        &self,
+        username: &str,
+        password: &str,
+        invite: Option<&str>,
+    ) -> Result<()> {
+        let invite = match (self.registration, invite) {
+            (Registration::Closed, _) => {
+                error!("rejecting registration of {username}, registration is closed");
+                return Err(Error::Forbidden);
+            }
+            (Registration::Invite, None) => {
+                error!("rejecting registration of {username} without an invite");
+                return Err(Error::Forbidden);
+            }
+            (Registration::Invite, Some(code)) => Some(code.trim()),
+            (Registration::Open, _) => None,
+        };
+
+        valid_username(username)
+            .and_then(|()| valid_password(password))
+            .map_err(|reason| {
+                error!("rejecting registration: {reason}");
+                Error::BadRequest
+            })?;
+
+        let now = self.now()?;
+        self.store
+            .register(username, &auth::pwhash(password), invite, now)
+            .await?;
+
+        info!(
+            "registered user {username}{}",
+            if invite.is_some() { " by invite" } else { "" }
+        );
+        Ok(())
+    }
+
+    fn now(&self) -> Result<Timestamp> {
+        self.clock.now().map_err(|e| {
+            error!("couldn't get time: {e:?}");
+            Error::Internal
+        })
     }
 
     pub async fn login(
@@ -70,29 +283,20 @@ impl PodSync {
     ) -> Result<PodSyncAuthed<true>> {
         let username = auth_attempt.user();
 
-        let user = query_as!(
-            User,
-            "
-                SELECT *
-                FROM users
-                WHERE username = ?
-                ",
-            username,
-        )
-        .fetch_one(&self.0)
-        .await
-        .map_err(|e| {
-            if matches!(e, sqlx::Error::RowNotFound) {
-                error!("rejecting non-existant user {}", username);
-                Error::Unauthorized
-            } else {
-                error!("couldn't authenticate user {}: {e:?}", username);
-                Error::Internal
-            }
-        })?;
+        let Some(user) = self.store.user(username).await? else {
+            error!("rejecting non-existant user {}", username);
+            self.failed_login(username, "no such user");
+            return Err(Error::Unauthorized);
+        };
 
         if auth_attempt.calc_pwhash() != user.pwhash {
             error!("wrong password for user {}", username);
+            self.failed_login(username, "wrong password");
+            return Err(Error::Unauthorized);
+        }
+        if user.disabled {
+            error!("rejecting disabled user {}", username);
+            self.failed_login(username, "disabled");
             return Err(Error::Unauthorized);
         }
 
@@ -121,21 +325,7 @@ impl PodSync {
                 let session_id = SessionId::new();
                 let str = session_id.to_string();
 
-                query!(
-                    "
-                    UPDATE users
-                    SET session_id = ?
-                    WHERE username = ?
-                    ",
-                    str,
-                    username,
-                )
-                .execute(&self.0)
-                .await
-                .map_err(|e| {
-                    error!("couldn't login user {}: {e:?}", username);
-                    Error::Internal
-                })?;
+                self.store.set_session(username, Some(&str)).await?;
 
                 info!("{username} login: new session created");
                 ok(session_id)
@@ -165,27 +355,20 @@ impl PodSync {
     pub async fn authenticate(self: &Arc<Self>, session_id: SessionId) -> Result<PodSyncAuthed> {
         let session_str = session_id.to_string();
 
-        let users = query_as!(
-            User,
-            "
-            SELECT *
-            FROM users
-            WHERE session_id = ?
-            ",
-            session_str,
-        )
-        .fetch_all(&self.0)
-        .await
-        .map_err(|e| {
-            error!("couldn't query for session {session_id}: {e:?}");
-            Error::Internal
-        })?;
+        let users = self.store.users_with_session(&session_str).await?;
 
         match &users[..] {
             [] => {
                 error!("no user found for session {session_id}");
                 Err(Error::Unauthorized)
             }
+            [user] if user.disabled => {
+                error!(
+                    "rejecting session {session_id} of disabled user {}",
+                    user.username
+                );
+                Err(Error::Unauthorized)
+            }
             [user] => {
                 assert_eq!(user.session_id, Some(session_str));
 
@@ -201,691 +384,2644 @@ impl PodSync {
             }
         }
     }
-}
 
-impl PodSyncAuthed {
-    pub fn with_user(self, username: &str) -> Result<PodSyncAuthed<true>> {
-        if username == self.username {
-            Ok(PodSyncAuthed {
-                sync: self.sync,
-                session_id: self.session_id,
-                username: self.username,
-            })
-        } else {
-            error!(
-                "mismatching session & username: session={{ username: {}, session_id: {} }}, username={username}",
-                self.username,
-                self.session_id,
-            );
-            Err(Error::Unauthorized)
+    fn failed_login(&self, username: &str, reason: &'static str) {
+        let (Ok(at), Ok(mut failed)) = (self.now(), self.failed_logins.lock()) else {
+            error!("couldn't record a failed login for {username}");
+            return;
+        };
+
+        if failed.len() == FAILED_LOGINS {
+            failed.pop_front();
         }
+        failed.push_back(FailedLogin {
+            // it's whatever was typed in, which could be anything
+            username: username.chars().take(MAX_USERNAME).collect(),
+            at,
+            reason,
+        });
     }
 }
 
-impl PodSyncAuthed<true> {
-    pub async fn logout(&self) -> Result<()> {
-        let username = &self.username;
-        info!("{username} logout");
-
-        query!(
-            "
-                UPDATE users
-                SET session_id = NULL
-                WHERE username = ?
-                ",
-            username,
-        )
-        .execute(&self.sync.0)
-        .await
-        .map(|_| ())
-        .map_err(|e| {
-            error!("error updating session_id: {e:?}");
-            Error::Internal
-        })
+impl PodSync {
+    // a consistent snapshot of everything, to a file or into a directory (named for now)
+    pub async fn snapshot(&self, dest: &Path, compress: bool) -> Result<PathBuf> {
+        if dest.is_dir() {
+            let now = self.now()?;
+            let Some(when) = now.to_datetime() else {
+                error!("can't name a snapshot for {now}");
+                return Err(Error::Internal);
+            };
+            backup::snapshot(&*self.store, dest, compress, when).await
+        } else {
+            backup::write(&*self.store, dest, compress).await
+        }
     }
 
-    pub fn session_id(&self) -> &SessionId {
-        &self.session_id
-    }
+    pub async fn doctor(&self, fix: bool) -> Result<Diagnosis> {
+        let now = self.now()?;
+        let diagnosis = self.store.diagnose(fix, now).await?;
 
-    pub async fn devices(&self) -> Result<Vec<DeviceAndSub>> {
-        let username = &self.username;
-        trace!("{username} getting devices");
+        if fix && !diagnosis.is_healthy() {
+            info!("repaired {diagnosis:?}");
+        }
 
-        query_as!(
-            DeviceAndSub,
-            r#"
-            SELECT id, caption as "caption!: _", type as "type!: _", COUNT(*) as "subscriptions!: _"
-            FROM devices
-            INNER JOIN subscriptions
-                ON devices.username = subscriptions.username
-            GROUP BY devices.username, devices.id
-            HAVING devices.username = ?
-            "#,
-            username,
-        )
-        .fetch_all(&self.sync.0)
-        .await
-        .map(|devs| {
-            info!("{username}, {} devices", devs.len());
-            devs
-        })
-        .map_err(|e| {
-            error!("error selecting devices: {:?}", e);
-            Error::Internal
-        })
+        Ok(diagnosis)
     }
 
-    pub async fn update_device(&self, device_id: &str, update: DeviceUpdate) -> Result<()> {
-        let username = &self.username;
-        info!("{username} updating device {device_id}: {update:?}");
+    // see `Store::prune`, with each retention period in days
+    pub async fn prune(
+        &self,
+        subscriptions_days: Option<u32>,
+        episodes_days: Option<u32>,
+    ) -> Result<Pruned> {
+        let now = self.now()?;
+
+        let pruned = self
+            .store
+            .prune(
+                subscriptions_days.map(|days| now.days_before(days)),
+                episodes_days.map(|days| now.days_before(days)),
+            )
+            .await?;
 
-        let caption: Option<_> = update.caption;
-        let type_default = update.r#type.clone().unwrap_or_default();
-        let r#type: Option<_> = update.r#type;
-
-        let result = query!(
-            "
-            INSERT INTO devices
-            (id, username, caption, type)
-            VALUES
-            (?, ?, ?, ?)
-            ON CONFLICT
-            DO
-                UPDATE SET
-                    caption = coalesce(?, devices.caption),
-                    type = coalesce(?, devices.type)
-                WHERE id = ? AND username = ?
-            ",
-            device_id,
-            username,
-            caption,
-            type_default,
-            caption,
-            r#type,
-            device_id,
-            username
-        )
-        .execute(&self.sync.0)
-        .await;
-
-        match result {
-            Ok(_result) => Ok(()),
-            Err(e) => {
-                error!("error inserting device: {:?}", e);
-                Err(Error::Internal)
-            }
-        }
+        info!(
+            "pruned {} deleted subscriptions, {} episode actions and {} from the history",
+            pruned.subscriptions, pruned.episodes, pruned.history,
+        );
+
+        Ok(pruned)
     }
 
-    pub async fn subscriptions(
-        &self,
-        device_id: &str,
-        since: Timestamp,
-    ) -> Result<SubscriptionChangesToClient> {
-        let username = &self.username;
+    // Rehash any episodes stored under an older content hash, so the `content_hash <> ?`
+    // dedup keeps working. `modified` is untouched - clients have nothing new to fetch.
+    pub async fn rehash_episodes(&self) -> Result<()> {
+        let count = self.store.rehash_episodes().await?;
 
-        trace!("{username} on {device_id}, requesting subscription changes since {since}");
+        if count > 0 {
+            info!("rehashed {count} episodes to hash v{HASH_VERSION}");
+        }
 
-        #[derive(Debug, sqlx::FromRow)]
-        struct Url {
-            url: String,
-            deleted: Option<Timestamp>,
-            created: Timestamp,
-        }
-
-        let urls = query_as!(
-            Url,
-            r#"
-            SELECT url,
-                deleted as "deleted: _",
-                created as "created!: _"
-            FROM subscriptions
-            WHERE username = ?
-                AND device = ?
-                AND (
-                    created > ? OR deleted > ?
-                )
-            "#,
-            username,
-            device_id,
-            since,
-            since,
-        )
-        .fetch_all(&self.sync.0)
-        .await
-        .map_err(|e| {
-            error!("error selecting subscriptions: {e:?}");
-            Error::Internal
-        })?;
+        Ok(())
+    }
 
-        enum E {
-            Created(String),
-            Removed(String),
+    pub async fn set_admin(&self, username: &str, admin: bool) -> Result<()> {
+        if self.store.user(username).await?.is_none() {
+            error!("can't change admin of non-existant user {username}");
+            return Err(Error::BadRequest);
         }
 
-        impl E {
-            fn url(self) -> String {
-                match self {
-                    E::Created(u) => u,
-                    E::Removed(u) => u,
-                }
-            }
-            fn is_create(&self) -> bool {
-                matches!(self, E::Created(_))
-            }
+        info!(
+            "{username} is {}an admin",
+            if admin { "" } else { "no longer " }
+        );
+        self.store.set_admin(username, admin).await
+    }
+
+    pub async fn export_user(&self, username: &str) -> Result<Export> {
+        if self.store.user(username).await?.is_none() {
+            error!("can't export non-existant user {username}");
+            return Err(Error::BadRequest);
         }
 
-        let latest = urls.iter().map(|u| u.created).max();
+        let exported = self.now()?;
 
-        let (created, deleted): (Vec<_>, Vec<_>) = urls
+        let devices = self
+            .store
+            .devices(username)
+            .await?
             .into_iter()
-            .map(|entry| match entry.deleted {
-                Some(_) => E::Removed(entry.url),
-                None => E::Created(entry.url),
+            .map(|dev| ExportDevice {
+                id: dev.id,
+                caption: dev.caption,
+                r#type: dev.r#type,
             })
-            .partition(E::is_create);
+            .collect();
 
-        let created: Vec<_> = created.into_iter().map(E::url).collect();
-        let deleted: Vec<_> = deleted.into_iter().map(E::url).collect();
+        let subscriptions = self
+            .store
+            .all_subscriptions(username)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        let episodes = self
+            .store
+            .episodes(
+                username,
+                &EpisodeFilter {
+                    since: Timestamp::zero(),
+                    podcast: None,
+                    device: None,
+                    aggregated: false,
+                    after: None,
+                    limit: None,
+                },
+            )
+            .await?
+            .into_iter()
+            .map(|ep| EpisodeRaw {
+                // keep any subseconds, which the offset-less format drops
+                timestamp: ep.timestamp.map(|t| t.with_format(TimeFormat::Rfc3339)),
+                modified: None,
+                ..ep
+            })
+            .collect::<Vec<_>>();
 
-        let now = now()?;
-        let timestamp = latest.unwrap_or(now);
+        let export = Export {
+            version: EXPORT_VERSION,
+            username: username.into(),
+            exported,
+            devices,
+            subscriptions,
+            episodes,
+            settings: Default::default(),
+        };
 
         info!(
-            "{username} on {device_id}, {} subs created, {} deleted, timestamp {timestamp}",
-            created.len(),
-            deleted.len(),
+            "{username} exported, {} devices, {} subscriptions, {} episode actions",
+            export.devices.len(),
+            export.subscriptions.len(),
+            export.episodes.len(),
         );
 
-        Ok(SubscriptionChangesToClient {
-            add: created,
-            remove: deleted,
-            timestamp,
-        })
+        Ok(export)
     }
 
-    async fn transact<'t, T, R, F>(&self, transaction: T) -> Result<R>
-    where
-        T: FnOnce(Transaction<'t, Sqlite>) -> F,
-        F: Future<Output = Result<(Transaction<'t, Sqlite>, R)>>,
-    {
-        let tx = self.sync.0.begin().await.map_err(|e| {
-            error!("error beginning transaction: {:?}", e);
-            Error::Internal
-        })?;
+    // Active subscriptions as OPML, merged across the user's devices unless one is given
+    pub async fn export_opml(&self, username: &str, device_id: Option<&str>) -> Result<String> {
+        if self.store.user(username).await?.is_none() {
+            error!("can't export non-existant user {username}");
+            return Err(Error::BadRequest);
+        }
 
-        // could probably pass &mut *tx here
-        let (tx, r) = transaction(tx).await?;
+        let urls = self.active_subscriptions(username, device_id).await?;
 
-        tx.commit().await.map_err(|e| {
-            error!("error committing transaction: {:?}", e);
-            Error::Internal
-        })?;
+        info!("{username} exported {} subscriptions as OPML", urls.len());
 
-        Ok(r)
+        Ok(export::to_opml(&list_title(username, device_id), &urls))
     }
 
-    pub async fn update_subscriptions(
+    async fn active_subscriptions(
         &self,
-        device_id: &str,
-        changes: SubscriptionChangesFromClient,
-    ) -> Result<UpdatedUrls> {
-        let username = &self.username;
-        let now = now()?;
-
-        trace!("{username} updating subscription for device {device_id}");
-
-        self.transact(|mut tx| async {
-            for url in &changes.remove {
-                query!(
-                    "
-                    UPDATE subscriptions
-                    SET
-                        deleted = ?
-                    WHERE username = ?
-                        AND device = ?
-                        AND url = ?
-                        AND deleted IS NULL
-                    ",
-                    now,
-                    username,
-                    device_id,
-                    url,
-                )
-                .execute(&mut *tx)
-                .await
-                .map_err(|e| {
-                    error!("error deleting (updating) subscription: {e:?}");
-                    Error::Internal
-                })?;
+        username: &str,
+        device_id: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let mut urls = vec![];
+        for (device, sub) in self.store.all_subscriptions(username).await? {
+            let wanted = device_id.is_none() || device_id == Some(&device[..]);
+
+            if wanted && sub.deleted.is_none() && !urls.contains(&sub.url) {
+                urls.push(sub.url);
             }
+        }
 
-            for url in &changes.add {
-                query!(
-                    "
-                    INSERT INTO subscriptions
-                    (username, device, url, created)
-                    VALUES
-                    (?, ?, ?, ?) -- `deleted` <- NULL
-                    ON CONFLICT
-                    DO NOTHING
-                    ",
-                    username,
-                    device_id,
-                    url,
-                    now,
-                )
-                .execute(&mut *tx)
-                .await
-                .map_err(|e| {
-                    error!("error inserting subscription: {e:?}");
-                    Error::Internal
-                })?;
-            }
+        Ok(urls)
+    }
 
-            Ok((tx, ()))
-        })
-        .await?;
+    // The latest action for each episode, as CSV
+    pub async fn export_csv(&self, username: &str) -> Result<String> {
+        if self.store.user(username).await?.is_none() {
+            error!("can't export non-existant user {username}");
+            return Err(Error::BadRequest);
+        }
 
-        info!(
-            "{username} on {device_id}, added {} subscriptions, removed {}, timestamp {now}",
-            changes.add.len(),
-            changes.remove.len()
-        );
+        let episodes = self
+            .store
+            .episodes(
+                username,
+                &EpisodeFilter {
+                    since: Timestamp::zero(),
+                    podcast: None,
+                    device: None,
+                    aggregated: true,
+                    after: None,
+                    limit: None,
+                },
+            )
+            .await?;
 
-        Ok(UpdatedUrls {
-            timestamp: now,
-            update_urls: changes
-                .add
-                .into_iter()
-                .map(|url| (url.clone(), url))
-                .collect(),
-        })
+        info!("{username} exported {} episodes as CSV", episodes.len());
+
+        Ok(export::to_csv(&episodes))
     }
 
-    pub async fn episodes(&self, query: QueryEpisodes) -> Result<Episodes> {
-        let username = &self.username;
-        let since = query.since.unwrap_or_else(Timestamp::zero);
-        let podcast_filter = query.podcast;
-        let device_filter = query.device;
-        // query.aggregated: unique on (sub, episode)-tuple - always true with how we store
+    // Importing the same export twice is harmless: subscriptions we hold are skipped,
+    // and episode actions are deduplicated by their content hash.
+    pub async fn import_user(&self, username: &str, export: Export) -> Result<ImportSummary> {
+        if export.version != EXPORT_VERSION {
+            error!(
+                "can't import version {} export, expected {EXPORT_VERSION}",
+                export.version
+            );
+            return Err(Error::BadRequest);
+        }
 
-        trace!(
-            "{username}, requesting episode changes since {since}, device={}, podcast={}",
-            device_filter.as_deref().unwrap_or("<none>"),
-            podcast_filter.as_deref().unwrap_or("<none>"),
-        );
+        if self.store.user(username).await?.is_none() {
+            error!("can't import into non-existant user {username}");
+            return Err(Error::BadRequest);
+        }
 
-        let episodes = query_as!(
-            EpisodeRaw,
-            r#"
-            SELECT episodes.podcast, episode,
-                guid, episodes.device,
-                timestamp as "timestamp: _",
-                action as "action!: _",
-                started, position, total,
-                modified as "modified?: _"
-            FROM
-                episodes,
-                (SELECT ? as podcast, ? as device) as filter
-            WHERE username = ?
-                AND modified > ?
-                AND (filter.podcast IS NULL OR filter.podcast = episodes.podcast)
-                AND (filter.device IS NULL OR filter.device = episodes.device)
-            "#,
-            podcast_filter,
-            device_filter,
-            username,
-            since,
-        )
-        .fetch_all(&self.sync.0)
-        .await
-        .map_err(|e| {
-            error!("error selecting episodes: {e:?}");
-            Error::Internal
-        })?;
+        if export.username != username {
+            info!("importing {}'s data into {username}", export.username);
+        }
 
-        let latest = episodes.iter().filter_map(|ep| ep.modified).max();
+        let now = self.now()?;
+        let mut summary = ImportSummary::default();
 
-        let mut episodes = episodes
-            .into_iter()
-            .map(TryInto::try_into)
-            .collect::<result::Result<Vec<Episode>, _>>()
-            .map_err(|e| {
-                error!("couldn't construct episode changes from DB: {e:?}");
-                Error::Internal
-            })?;
+        for dev in export.devices {
+            let update = DeviceUpdate {
+                caption: (!dev.caption.is_empty()).then_some(dev.caption),
+                r#type: Some(dev.r#type),
+            };
 
-        // workaround a bug in antennapod - populate the timestamp (EpisodeActionFilter.java:75)
-        for ep in &mut episodes {
-            if ep.timestamp.is_none() {
-                ep.timestamp = Some(Default::default());
-            }
+            self.store.update_device(username, &dev.id, update).await?;
+            summary.devices += 1;
         }
 
-        let now = now()?;
-        let timestamp = latest.unwrap_or(now);
-        info!(
-            "{username}, {} episodes changes, timestamp {timestamp}",
-            episodes.len()
-        );
+        let mut subscriptions = BTreeMap::<String, Vec<Subscription>>::new();
+        for sub in export.subscriptions {
+            let (device, sub) = sub.into();
+            subscriptions.entry(device).or_default().push(sub);
+        }
+        for (device, subs) in subscriptions {
+            summary.subscriptions += self
+                .store
+                .import_subscriptions(username, &device, &subs)
+                .await?;
+        }
+
+        summary.episodes = export.episodes.len();
+
+        // Only each episode's latest action is applied, as stepping through the rest would
+        // have it `modified` again with every import: actions without a timestamp are
+        // never stale, so the older ones would each replace what we hold in turn.
+        let mut latest = BTreeMap::<_, (Option<Time>, EpisodeRaw)>::new();
+        for ep in export.episodes.iter().cloned() {
+            let key = (ep.podcast.clone(), ep.episode.clone());
+
+            let timestamp = match latest.get(&key) {
+                Some((held, _)) if is_stale(held.as_ref(), ep.timestamp.as_ref()) => {
+                    summary.stale_episodes += 1;
+                    continue;
+                }
+                Some((held, _)) => ep.timestamp.clone().or_else(|| held.clone()),
+                None => ep.timestamp.clone(),
+            };
+
+            latest.insert(key, (timestamp, ep));
+        }
+
+        let latest = latest.into_values().map(|(_, ep)| ep).collect();
+        summary.stale_episodes += self
+            .store
+            .import_episodes(username, &export.episodes, latest, now)
+            .await?
+            .len();
+
+        info!("{username} imported: {summary:?}");
+
+        Ok(summary)
+    }
+
+    // Bring in data from another service or app, through the same store updates a
+    // client's uploads go through. Subscriptions are only ever added.
+    pub async fn migrate(
+        &self,
+        username: &str,
+        device_id: &str,
+        migration: Migration,
+        dry_run: bool,
+    ) -> Result<MigrationReport> {
+        if self.store.user(username).await?.is_none() {
+            error!("can't migrate into non-existant user {username}");
+            return Err(Error::BadRequest);
+        }
+
+        let mut report = MigrationReport {
+            episodes_rejected: migration.rejected,
+            ..Default::default()
+        };
+
+        let held = self
+            .store
+            .subscriptions(username, device_id, Timestamp::zero())
+            .await?;
+        for url in migration.subscriptions {
+            let is_held = held
+                .iter()
+                .any(|sub| sub.url == url && sub.deleted.is_none());
+
+            if is_held {
+                report.subscriptions_held += 1;
+            } else if !report.subscriptions_added.contains(&url) {
+                report.subscriptions_added.push(url);
+            }
+        }
+
+        // play the actions against what we hold, as `update_episodes` will
+        let mut current: HashMap<_, _> = self
+            .store
+            .episodes(
+                username,
+                &EpisodeFilter {
+                    since: Timestamp::zero(),
+                    podcast: None,
+                    device: None,
+                    aggregated: true,
+                    after: None,
+                    limit: None,
+                },
+            )
+            .await?
+            .into_iter()
+            .map(|ep| {
+                let hash = ep.content_hash();
+                ((ep.podcast, ep.episode), (ep.timestamp, hash))
+            })
+            .collect();
+
+        let episodes: Vec<EpisodeRaw> = migration.episodes.into_iter().map(Into::into).collect();
+        for ep in &episodes {
+            let key = (ep.podcast.clone(), ep.episode.clone());
+            let hash = ep.content_hash();
+
+            let timestamp = match current.get(&key) {
+                None => {
+                    report.episodes_new += 1;
+                    ep.timestamp.clone()
+                }
+                Some((held, _)) if is_stale(held.as_ref(), ep.timestamp.as_ref()) => {
+                    report.episodes_stale += 1;
+                    continue;
+                }
+                Some((held, held_hash)) => {
+                    if *held_hash == hash {
+                        report.episodes_unchanged += 1;
+                    } else {
+                        report.episodes_updated += 1;
+                    }
+                    ep.timestamp.clone().or_else(|| held.clone())
+                }
+            };
+
+            current.insert(key, (timestamp, hash));
+        }
+
+        if dry_run {
+            info!("{username} on {device_id}, migration dry run: {report:?}");
+            return Ok(report);
+        }
+
+        let now = self.now()?;
+
+        // so the subscriptions' device shows up in `devices`
+        let device = DeviceUpdate {
+            caption: None,
+            r#type: None,
+        };
+        self.store
+            .update_device(username, device_id, device)
+            .await?;
+
+        let changes = SubscriptionChangesFromClient {
+            add: report.subscriptions_added.clone(),
+            remove: vec![],
+        };
+        self.store
+            .update_subscriptions(username, device_id, &changes, now)
+            .await?;
+
+        self.store.update_episodes(username, episodes, now).await?;
+
+        info!("{username} on {device_id}, migrated: {report:?}");
+
+        Ok(report)
+    }
+}
+
+impl PodSyncAuthed {
+    pub fn with_user(self, username: &str) -> Result<PodSyncAuthed<true>> {
+        if username == self.username {
+            Ok(PodSyncAuthed {
+                sync: self.sync,
+                session_id: self.session_id,
+                username: self.username,
+            })
+        } else {
+            error!(
+                "mismatching session & username: session={{ username: {}, session_id: {} }}, username={username}",
+                self.username,
+                self.session_id,
+            );
+            Err(Error::Unauthorized)
+        }
+    }
+
+    // for endpoints without a username in their path, which act on the session's user
+    pub fn for_session_user(self) -> PodSyncAuthed<true> {
+        PodSyncAuthed {
+            sync: self.sync,
+            session_id: self.session_id,
+            username: self.username,
+        }
+    }
+}
+
+impl PodSyncAuthed<true> {
+    pub async fn logout(&self) -> Result<()> {
+        let username = &self.username;
+        info!("{username} logout");
+
+        self.sync.store.set_session(username, None).await
+    }
+
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub async fn is_admin(&self) -> Result<bool> {
+        let user = self.sync.store.user(&self.username).await?;
+
+        Ok(user.is_some_and(|user| user.admin))
+    }
+
+    // checked afresh each time, so a revoked admin is one no longer
+    pub async fn admin(self) -> Result<PodSyncAdmin> {
+        if !self.is_admin().await? {
+            error!("{} isn't an admin", self.username);
+            return Err(Error::Forbidden);
+        }
+
+        Ok(PodSyncAdmin {
+            sync: self.sync,
+            username: self.username,
+        })
+    }
+
+    pub async fn change_password(&self, current: &str, new: &str) -> Result<()> {
+        let username = &self.username;
+
+        let user = self.sync.store.user(username).await?.ok_or_else(|| {
+            error!("{username} has a session but no user");
+            Error::Internal
+        })?;
+
+        if auth::pwhash(current) != user.pwhash {
+            error!("wrong current password for {username}");
+            return Err(Error::Unauthorized);
+        }
+        valid_password(new).map_err(|reason| {
+            error!("{username} tried an invalid password: {reason}");
+            Error::BadRequest
+        })?;
+
+        info!("{username} changing password");
+        self.sync
+            .store
+            .set_password(username, &auth::pwhash(new))
+            .await
+    }
+
+    // The user's last change, for `ETag`s, and for `Last-Modified` once its second is over.
+    // Dates are only whole seconds, so until then one couldn't tell a later change apart.
+    pub async fn last_change(&self) -> Result<(Timestamp, Option<Timestamp>)> {
+        let change = self
+            .sync
+            .store
+            .last_change(&self.username)
+            .await?
+            .unwrap_or_else(Timestamp::zero);
+        let now = self.sync.now()?;
+
+        let last_modified = Timestamp::from_secs(change.as_secs());
+        let settled = last_modified.as_secs() < now.as_secs();
+
+        Ok((change, settled.then_some(last_modified)))
+    }
+
+    pub async fn export(&self) -> Result<Export> {
+        self.sync.export_user(&self.username).await
+    }
+
+    pub async fn export_opml(&self, query: QueryOpml) -> Result<String> {
+        self.sync
+            .export_opml(&self.username, query.device.as_deref())
+            .await
+    }
+
+    pub async fn export_csv(&self) -> Result<String> {
+        self.sync.export_csv(&self.username).await
+    }
+
+    // gpodder's simple API: active subscriptions, of a device or all of them
+    pub async fn subscription_list(
+        &self,
+        device_id: Option<&str>,
+        format: Format,
+        jsonp: Option<&str>,
+    ) -> Result<String> {
+        let username = &self.username;
+        let urls = self.sync.active_subscriptions(username, device_id).await?;
+
+        info!(
+            "{username} listed {} subscriptions{} as {format:?}",
+            urls.len(),
+            device_id.map(|d| format!(" on {d}")).unwrap_or_default(),
+        );
+
+        export::to_podcast_list(format, &list_title(username, device_id), &urls, jsonp)
+    }
+
+    // ... and replacing a device's subscriptions with the list uploaded
+    pub async fn put_subscription_list(
+        &self,
+        device_id: &str,
+        format: Format,
+        body: &[u8],
+    ) -> Result<()> {
+        let urls = export::from_podcast_list(format, body)?;
+        let current = self
+            .sync
+            .active_subscriptions(&self.username, Some(device_id))
+            .await?;
+
+        let changes = SubscriptionChangesFromClient {
+            add: urls
+                .iter()
+                .filter(|url| !current.contains(url))
+                .cloned()
+                .collect(),
+            remove: current
+                .into_iter()
+                .filter(|url| !urls.contains(url))
+                .collect(),
+        };
+
+        self.update_subscriptions(device_id, changes).await?;
+        Ok(())
+    }
+
+    pub async fn devices(&self) -> Result<Vec<DeviceAndSub>> {
+        let username = &self.username;
+        trace!("{username} getting devices");
+
+        let devs = self.sync.store.devices(username).await?;
+        info!("{username}, {} devices", devs.len());
+
+        Ok(devs)
+    }
+
+    pub async fn update_device(&self, device_id: &str, update: DeviceUpdate) -> Result<()> {
+        let username = &self.username;
+        let known = self.sync.store.devices(username).await?;
+
+        valid_device(device_id, &known)
+            .and_then(|()| match &update.caption {
+                Some(caption) => limits::bounded("caption", caption, MAX_CAPTION),
+                None => Ok(()),
+            })
+            .map_err(|reason| {
+                error!("{username} sent an invalid device update: {reason}");
+                Error::BadRequest
+            })?;
+
+        info!("{username} updating device {device_id}: {update:?}");
+
+        self.sync
+            .store
+            .update_device(username, device_id, update)
+            .await
+    }
+
+    pub async fn delete_device(&self, device_id: &str) -> Result<()> {
+        let username = &self.username;
+        info!("{username} deleting device {device_id}");
+
+        let now = self.sync.now()?;
+        self.sync
+            .store
+            .delete_device(username, device_id, now)
+            .await
+    }
+
+    // each device's active subscriptions
+    pub async fn device_subscriptions(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        let mut devices = BTreeMap::<_, Vec<_>>::new();
+
+        for (device, sub) in self.sync.store.all_subscriptions(&self.username).await? {
+            if sub.deleted.is_none() {
+                devices.entry(device).or_default().push(sub.url);
+            }
+        }
+
+        Ok(devices)
+    }
+
+    // The latest action of the `count` episodes most recently changed, newest first. For
+    // people to look over, so any that can't be read are left out, rather than failing.
+    pub async fn recent_episodes(&self, count: usize) -> Result<Vec<Episode>> {
+        let username = &self.username;
+        let recent = self.sync.store.recent_episodes(username, count).await?;
+
+        Ok(recent
+            .into_iter()
+            .filter_map(|raw| {
+                Episode::try_from(raw)
+                    .map_err(|e| warn!("{username} has an unreadable episode action: {e:?}"))
+                    .ok()
+            })
+            .collect())
+    }
+
+    pub async fn subscriptions(
+        &self,
+        device_id: &str,
+        since: Timestamp,
+    ) -> Result<SubscriptionChangesToClient> {
+        let username = &self.username;
+
+        trace!("{username} on {device_id}, requesting subscription changes since {since}");
+
+        let urls = self
+            .sync
+            .store
+            .subscriptions(username, device_id, since)
+            .await?;
+
+        enum E {
+            Created(String),
+            Removed(String),
+        }
+
+        impl E {
+            fn url(self) -> String {
+                match self {
+                    E::Created(u) => u,
+                    E::Removed(u) => u,
+                }
+            }
+            fn is_create(&self) -> bool {
+                matches!(self, E::Created(_))
+            }
+        }
+
+        let latest = urls.iter().map(|u| u.created).max();
+
+        let (created, deleted): (Vec<_>, Vec<_>) = urls
+            .into_iter()
+            .map(|entry| match entry.deleted {
+                Some(_) => E::Removed(entry.url),
+                None => E::Created(entry.url),
+            })
+            .partition(E::is_create);
+
+        let created: Vec<_> = created.into_iter().map(E::url).collect();
+        let deleted: Vec<_> = deleted.into_iter().map(E::url).collect();
+
+        let now = self.sync.now()?;
+        let timestamp = latest.unwrap_or(now);
+
+        info!(
+            "{username} on {device_id}, {} subs created, {} deleted, timestamp {timestamp}",
+            created.len(),
+            deleted.len(),
+        );
+
+        Ok(SubscriptionChangesToClient {
+            add: created,
+            remove: deleted,
+            timestamp,
+        })
+    }
+
+    pub async fn update_subscriptions(
+        &self,
+        device_id: &str,
+        changes: SubscriptionChangesFromClient,
+    ) -> Result<UpdatedUrls> {
+        let username = &self.username;
+        let now = self.sync.now()?;
+
+        let count = changes.add.len() + changes.remove.len();
+        if count > self.sync.limits.upload_items {
+            error!(
+                "{username} sent {count} subscription changes, over the limit of {}",
+                self.sync.limits.upload_items
+            );
+            return Err(Error::PayloadTooLarge);
+        }
+        let known = self.sync.store.devices(username).await?;
+        valid_device(device_id, &known)
+            .and_then(|()| {
+                changes
+                    .add
+                    .iter()
+                    .chain(&changes.remove)
+                    .try_for_each(|url| limits::bounded("url", url, MAX_URL))
+            })
+            .map_err(|reason| {
+                error!("{username} sent invalid subscription changes: {reason}");
+                Error::BadRequest
+            })?;
+
+        trace!("{username} updating subscription for device {device_id}");
+
+        self.sync
+            .store
+            .update_subscriptions(username, device_id, &changes, now)
+            .await?;
+
+        info!(
+            "{username} on {device_id}, added {} subscriptions, removed {}, timestamp {now}",
+            changes.add.len(),
+            changes.remove.len()
+        );
+
+        Ok(UpdatedUrls {
+            timestamp: now,
+            update_urls: changes
+                .add
+                .into_iter()
+                .map(|url| (url.clone(), url))
+                .collect(),
+            stale_actions: Default::default(),
+            rejected: Default::default(),
+        })
+    }
+
+    #[cfg(test)]
+    pub async fn episodes(&self, query: QueryEpisodes) -> Result<Episodes> {
+        let mut page = self.episode_page(query).await?;
+
+        let mut actions = vec![];
+        while let Some(episode) = page.next().await {
+            actions.push(episode?);
+        }
+
+        let (timestamp, next) = page.finish();
 
         Ok(Episodes {
             timestamp,
-            actions: episodes,
+            actions,
+            next,
         })
     }
 
-    pub async fn update_episodes(&self, body: Vec<Episode>) -> Result<UpdatedUrls> {
-        let username = &self.username;
+    pub async fn episode_page(&self, query: QueryEpisodes) -> Result<EpisodePage> {
+        let username = &self.username;
+        let since = query.since.unwrap_or_else(Timestamp::zero);
+        let podcast_filter = query.podcast;
+        let device_filter = query.device;
+        // aggregated: unique on (sub, episode)-tuple, i.e. the `episodes` table,
+        // otherwise we return every action from `episode_history`
+        let aggregated = query.aggregated.unwrap_or(true);
+        let rfc3339 = query.rfc3339.unwrap_or(false);
+
+        if query.limit == Some(0) {
+            error!("{username} asked for pages of no episodes");
+            return Err(Error::BadRequest);
+        }
+
+        // a cursor's only good for the kind of actions it came from
+        let after = query
+            .cursor
+            .as_deref()
+            .map(|token| {
+                cursor_from_token(token)
+                    .filter(|after| matches!(after, EpisodeCursor::Aggregated { .. }) == aggregated)
+                    .ok_or_else(|| {
+                        error!("{username} sent an invalid episodes cursor: {token}");
+                        Error::BadRequest
+                    })
+            })
+            .transpose()?;
+
+        trace!(
+            "{username}, requesting episode changes since {since}, aggregated={aggregated}, device={}, podcast={}, limit={:?}",
+            device_filter.as_deref().unwrap_or("<none>"),
+            podcast_filter.as_deref().unwrap_or("<none>"),
+            query.limit,
+        );
+
+        let mut rows = self
+            .sync
+            .store
+            .episode_stream(
+                username,
+                &EpisodeFilter {
+                    since,
+                    podcast: podcast_filter,
+                    device: device_filter,
+                    aggregated,
+                    after,
+                    // one more, to know whether there's another page
+                    limit: query.limit.map(|limit| limit.saturating_add(1)),
+                },
+            )
+            .peekable();
+
+        // read ahead, so a failed query is an error response rather than a truncated one
+        if let Some(Err(e)) = Pin::new(&mut rows).peek().await {
+            return Err(*e);
+        }
+
+        Ok(EpisodePage {
+            username: username.clone(),
+            rows,
+            remaining: query.limit,
+            end: None,
+            more: false,
+            time_format: if rfc3339 {
+                TimeFormat::Rfc3339
+            } else {
+                TimeFormat::NoOffset
+            },
+            latest: None,
+            count: 0,
+            now: self.sync.now()?,
+        })
+    }
+
+    pub async fn upload_episodes(
+        &self,
+        body: Vec<serde_json::Value>,
+        query: QueryUpload,
+    ) -> Result<UpdatedUrls> {
+        let username = &self.username;
+        let lenient = query.lenient.unwrap_or(false);
+
+        if body.len() > self.sync.limits.upload_items {
+            error!(
+                "{username} sent {} episode actions, over the limit of {}",
+                body.len(),
+                self.sync.limits.upload_items
+            );
+            return Err(Error::PayloadTooLarge);
+        }
+
+        let known = self.sync.store.devices(username).await?;
+        let mut changes = Vec::with_capacity(body.len());
+        let mut rejected = vec![];
+
+        for (index, value) in body.into_iter().enumerate() {
+            let episode = Episode::deserialize(&value)
+                .map_err(|e| e.to_string())
+                .and_then(|ep| {
+                    limits::bounded("podcast", &ep.podcast, MAX_URL)?;
+                    limits::bounded("episode", &ep.episode, MAX_URL)?;
+                    if let Some(guid) = &ep.guid {
+                        limits::bounded("guid", guid, MAX_URL)?;
+                    }
+                    if let Some(device) = &ep.device {
+                        valid_device(device, &known)?;
+                    }
+                    Ok(ep)
+                });
+
+            match episode {
+                Ok(ep) => changes.push(ep),
+                Err(reason) => {
+                    error!(
+                        "{username} sent invalid episode action #{index}: {reason}, action: {value}"
+                    );
+
+                    if !lenient {
+                        return Err(Error::BadRequest);
+                    }
+                    rejected.push(RejectedAction { index, reason });
+                }
+            }
+        }
+
+        if !rejected.is_empty() {
+            info!(
+                "{username} rejected {} episode actions, storing {}",
+                rejected.len(),
+                changes.len()
+            );
+        }
+
+        let updated = self.update_episodes(changes).await?;
+
+        Ok(UpdatedUrls {
+            rejected,
+            ..updated
+        })
+    }
+
+    pub async fn update_episodes(&self, body: Vec<Episode>) -> Result<UpdatedUrls> {
+        let username = &self.username;
+
+        trace!("{username} updating episodes");
+
+        let changes = body
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<result::Result<Vec<Episode>, _>>()
+            .map_err(|e| {
+                error!("couldn't construct episode changes from user: {e:?}");
+                Error::BadRequest
+            })?;
+
+        let now = self.sync.now()?;
+        let change_count = changes.len();
+
+        let changes = changes.into_iter().map(EpisodeRaw::from).collect();
+        let stale = self
+            .sync
+            .store
+            .update_episodes(username, changes, now)
+            .await?;
+
+        let stale_actions: Vec<_> = stale
+            .into_iter()
+            .filter_map(|ep| {
+                let timestamp = ep.timestamp?;
+                info!(
+                    "{username} ignoring stale action for {} / {}: {timestamp:?}",
+                    ep.podcast, ep.episode
+                );
+
+                Some(StaleAction {
+                    podcast: ep.podcast,
+                    episode: ep.episode,
+                    timestamp,
+                })
+            })
+            .collect();
+
+        info!(
+            "{username} updated {} episodes ({} stale), timestamp {now}",
+            change_count - stale_actions.len(),
+            stale_actions.len(),
+        );
+
+        let update_timestamp = now;
+        Ok(UpdatedUrls {
+            stale_actions,
+            ..UpdatedUrls::just_timestamp(update_timestamp)
+        })
+    }
+}
+
+impl PodSyncAdmin {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn registration(&self) -> Registration {
+        self.sync.registration
+    }
+
+    pub async fn users(&self) -> Result<Vec<UserOverview>> {
+        let mut users = vec![];
+
+        for summary in self.sync.store.user_summaries().await? {
+            let last_active = self.sync.store.last_change(&summary.username).await?;
+            users.push(UserOverview {
+                summary,
+                last_active,
+            });
+        }
+
+        Ok(users)
+    }
+
+    pub fn stats(&self, users: &[UserOverview]) -> Result<InstanceStats> {
+        let week_ago = self.sync.now()?.days_before(7);
+
+        Ok(users.iter().fold(
+            InstanceStats::default(),
+            |mut stats,
+             UserOverview {
+                 summary,
+                 last_active,
+             }| {
+                stats.users += 1;
+                stats.admins += summary.admin as usize;
+                stats.disabled += summary.disabled as usize;
+                stats.active += last_active.is_some_and(|at| at > week_ago) as usize;
+                stats.devices += summary.devices;
+                stats.subscriptions += summary.subscriptions;
+                stats.episodes += summary.episodes;
+                stats
+            },
+        ))
+    }
+
+    // newest first
+    pub fn failed_logins(&self) -> Result<Vec<FailedLogin>> {
+        let failed = self.sync.failed_logins.lock().map_err(|e| {
+            error!("couldn't read failed logins: {e}");
+            Error::Internal
+        })?;
+
+        Ok(failed.iter().rev().cloned().collect())
+    }
+
+    pub async fn create_user(&self, username: &str, password: &str, admin: bool) -> Result<()> {
+        let admin_name = &self.username;
+
+        valid_username(username)
+            .and_then(|()| valid_password(password))
+            .map_err(|reason| {
+                error!("{admin_name} tried to create an invalid user: {reason}");
+                Error::BadRequest
+            })?;
+
+        if self.sync.store.user(username).await?.is_some() {
+            error!("{admin_name} tried to create {username}, who already exists");
+            return Err(Error::Conflict);
+        }
+
+        info!("{admin_name} creating user {username}, admin={admin}");
+        self.sync
+            .store
+            .create_user(username, &auth::pwhash(password))
+            .await?;
+        if admin {
+            self.sync.store.set_admin(username, true).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_disabled(&self, username: &str, disabled: bool) -> Result<()> {
+        let admin_name = &self.username;
+
+        if disabled && username == admin_name {
+            error!("{admin_name} tried to disable themselves");
+            return Err(Error::BadRequest);
+        }
+        self.existing(username).await?;
+
+        info!(
+            "{admin_name} {} {username}",
+            if disabled { "disabling" } else { "enabling" }
+        );
+        self.sync.store.set_disabled(username, disabled).await
+    }
+
+    // their apps will have to log in again, with the new password
+    pub async fn reset_password(&self, username: &str, password: &str) -> Result<()> {
+        let admin_name = &self.username;
+
+        valid_password(password).map_err(|reason| {
+            error!("{admin_name} tried an invalid password for {username}: {reason}");
+            Error::BadRequest
+        })?;
+        self.existing(username).await?;
+
+        info!("{admin_name} resetting the password of {username}");
+        self.sync
+            .store
+            .set_password(username, &auth::pwhash(password))
+            .await?;
+        if username != admin_name {
+            self.sync.store.set_session(username, None).await?;
+        }
+        Ok(())
+    }
+
+    // `days` until it expires, or never
+    pub async fn create_invite(&self, uses: u32, days: Option<u32>) -> Result<Invite> {
+        let admin_name = &self.username;
+
+        if uses == 0 {
+            error!("{admin_name} tried to create an invite with no uses");
+            return Err(Error::BadRequest);
+        }
+
+        let created = self.sync.now()?;
+        let invite = Invite {
+            code: Uuid::new_v4().simple().to_string(),
+            created_by: admin_name.clone(),
+            created,
+            expires: days.map(|days| created.days_after(days)),
+            max_uses: uses.into(),
+            used: 0,
+        };
+
+        info!("{admin_name} creating an invite for {uses} users");
+        self.sync.store.create_invite(&invite).await?;
+        Ok(invite)
+    }
+
+    pub async fn invites(&self) -> Result<Vec<InviteOverview>> {
+        let now = self.sync.now()?;
+
+        Ok(self
+            .sync
+            .store
+            .invites()
+            .await?
+            .into_iter()
+            .map(|invite| InviteOverview {
+                usable: invite.usable(now),
+                invite,
+            })
+            .collect())
+    }
+
+    pub async fn delete_invite(&self, code: &str) -> Result<()> {
+        info!("{} revoking invite {code}", self.username);
+        self.sync.store.delete_invite(code).await
+    }
+
+    async fn existing(&self, username: &str) -> Result<()> {
+        if self.sync.store.user(username).await?.is_none() {
+            error!("{} named non-existant user {username}", self.username);
+            return Err(Error::BadRequest);
+        }
+        Ok(())
+    }
+}
+
+impl UpdatedUrls {
+    pub fn just_timestamp(timestamp: Timestamp) -> Self {
+        Self {
+            timestamp,
+            update_urls: Default::default(),
+            stale_actions: Default::default(),
+            rejected: Default::default(),
+        }
+    }
+}
+
+impl EpisodePage {
+    pub async fn next(&mut self) -> Option<Result<Episode>> {
+        let row = match self.rows.next().await? {
+            Ok(row) => row,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if let Some(remaining) = &mut self.remaining {
+            if *remaining == 0 {
+                self.more = true;
+                return None;
+            }
+
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.end = EpisodeCursor::of(&row);
+            }
+        }
+
+        self.count += 1;
+        self.latest = self.latest.max(row.episode.modified);
+
+        let episode = Episode::try_from(row.episode).map_err(|e| {
+            error!("couldn't construct episode changes from DB: {e:?}");
+            Error::Internal
+        });
+
+        // workaround a bug in antennapod - populate the timestamp (EpisodeActionFilter.java:75)
+        Some(episode.map(|mut ep| {
+            let timestamp = ep.timestamp.take().unwrap_or_default();
+            ep.timestamp = Some(timestamp.with_format(self.time_format));
+            ep
+        }))
+    }
+
+    // the response's `timestamp`, and `next` if there's another page
+    pub fn finish(self) -> (Timestamp, Option<String>) {
+        let timestamp = self.latest.unwrap_or(self.now);
+        let next = self
+            .end
+            .filter(|_| self.more)
+            .map(|end| cursor_to_token(&end));
+
+        info!(
+            "{}, {} episodes changes, timestamp {timestamp}{}",
+            self.username,
+            self.count,
+            if next.is_some() { ", more to come" } else { "" },
+        );
+
+        (timestamp, next)
+    }
+}
+
+// Devices the user already has keep their ids, even those from before we limited their
+// characters, so only new ones are held to it
+fn valid_device(device_id: &str, known: &[DeviceAndSub]) -> result::Result<(), String> {
+    limits::bounded("device id", device_id, MAX_DEVICE_ID)?;
+
+    if !path_format::valid_device_id(device_id) && !known.iter().any(|d| d.id == device_id) {
+        return Err(format!(
+            "device id {device_id:?} has characters beyond ASCII letters, digits, _ . -"
+        ));
+    }
+    Ok(())
+}
+
+fn valid_username(username: &str) -> result::Result<(), String> {
+    limits::bounded("username", username, MAX_USERNAME)?;
+
+    if !path_format::valid_username(username) {
+        return Err(format!(
+            "username {username:?} has characters beyond ASCII letters, digits, _ . + -"
+        ));
+    }
+    Ok(())
+}
+
+fn valid_password(password: &str) -> result::Result<(), String> {
+    if password.is_empty() {
+        return Err("password is empty".into());
+    }
+    Ok(())
+}
+
+fn list_title(username: &str, device_id: Option<&str>) -> String {
+    match device_id {
+        Some(device_id) => format!("{username}'s subscriptions on {device_id}"),
+        None => format!("{username}'s subscriptions"),
+    }
+}
+
+// cursors are opaque to clients, who just hand them back
+fn cursor_to_token(cursor: &EpisodeCursor) -> String {
+    base64url_encode(&serde_json::to_string(cursor).expect("cursors serialize"))
+}
+
+fn cursor_from_token(token: &str) -> Option<EpisodeCursor> {
+    let json = base64_decode(&token.replace('-', "+").replace('_', "/"));
+    serde_json::from_slice(&json).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use uuid::Uuid;
+
+    use crate::device::DeviceType;
+    use crate::episode::{EpisodeAction, EpisodeActionRaw, Time};
+    use crate::migrate;
+    use crate::store::{EpisodeMeta, Fixtures};
+
+    use crate::mock;
+
+    fn create_session() -> SessionId {
+        Uuid::try_parse("550e8400-e29b-41d4-a716-446655440000")
+            .unwrap()
+            .into()
+    }
+
+    async fn create_podsync(backend: mock::Backend, username: &str) -> PodSyncAuthed<true> {
+        create_podsync_with(backend, username, Default::default())
+            .await
+            .0
+    }
+
+    async fn create_podsync_with(
+        backend: mock::Backend,
+        username: &str,
+        clock: Arc<mock::Clock>,
+    ) -> (PodSyncAuthed<true>, Arc<dyn Fixtures>) {
+        let (store, fixtures) = mock::create_store(backend).await;
+        let podsync = Arc::new(PodSync::with_clock(store, clock));
+        let podsync = PodSyncAuthed {
+            sync: podsync,
+            session_id: create_session(),
+            username: username.into(),
+        };
+
+        (podsync, fixtures)
+    }
+
+    // dev1 playing a minute-long episode of pod1, up to `position`, at `timestamp`
+    fn play(episode: &str, position: i64, timestamp: i64) -> Episode {
+        Episode {
+            podcast: "pod1".into(),
+            episode: episode.into(),
+            device: Some("dev1".into()),
+            timestamp: Some(Time::from_i64(timestamp)),
+            guid: None,
+            action: EpisodeAction::Play {
+                started: Some(0),
+                position,
+                total: Some(60),
+            },
+        }
+    }
+
+    mock::store_tests!(
+        episode_hashing,
+        episode_history,
+        episode_stale_actions,
+        episode_concurrent_uploads,
+        subscription_repeats,
+        episode_pages,
+        episode_upload_lenient,
+        upload_string_limits,
+        legacy_device_ids,
+        episode_action_types,
+        episode_rehashing,
+        episode_since,
+        export_import,
+        export_opml_csv,
+        migrate_dry_run,
+        doctor,
+        prune,
+        registration,
+    );
+
+    async fn episode_hashing(backend: mock::Backend) {
+        let username = "user1";
+        let podcast = "pod1";
+        let episode = "ep1";
+        let device = "dev1";
+
+        let (podsync, fixtures) = create_podsync_with(backend, username, Default::default()).await;
+
+        // given an "old" episode:
+        let old = |device: &str, podcast: &str, episode: &str| EpisodeRaw {
+            device: Some(device.into()),
+            podcast: podcast.into(),
+            episode: episode.into(),
+            timestamp: None,
+            guid: None,
+            action: EpisodeActionRaw::New,
+            started: None,
+            position: None,
+            total: None,
+            modified: None,
+        };
+        let old_meta = |modified| EpisodeMeta {
+            modified: Timestamp::from_millis(modified),
+            content_hash: "".into(),
+            hash_version: 0,
+        };
+        fixtures
+            .insert_episode(
+                username,
+                old(device, podcast, episode),
+                old_meta(1), // `modified` value we expect to be overwritten
+            )
+            .await;
+        fixtures
+            .insert_episode(
+                "u2", // this row won't be picked up - different user
+                old("dev2", "pod2", "ep2"),
+                old_meta(2),
+            )
+            .await;
+
+        // when we get a change to it:
+        let change = Episode {
+            podcast: podcast.into(),
+            episode: episode.into(),
+            device: None,
+            timestamp: None,
+            guid: None,
+            action: EpisodeAction::New,
+        };
+        podsync.update_episodes(vec![change.clone()]).await.unwrap();
+
+        // then we expect an update to specific fields:
+        {
+            let Episodes { actions: eps, .. } = podsync
+                .episodes(QueryEpisodes {
+                    since: None,
+                    aggregated: None,
+                    podcast: None,
+                    device: None,
+                    rfc3339: None,
+                    limit: None,
+                    cursor: None,
+                })
+                .await
+                .unwrap();
+
+            let [ref ep] = eps[..] else { panic!("expected single episode") };
+
+            assert_eq!(
+                ep,
+                &Episode {
+                    podcast: podcast.into(),
+                    episode: episode.into(),
+                    device: Some(device.into()),
+                    timestamp: Some(Time::from_i64(0)),
+                    guid: None,
+                    action: EpisodeAction::New,
+                }
+            );
+        }
+
+        // and our modified timestamp to have changed, along with the hash:
+        let new_hash;
+        {
+            let episodes = fixtures.episode_meta(username).await;
+            let [EpisodeMeta { ref modified, content_hash: ref hash, .. }] = episodes[..] else { panic!("expected single episode") };
+
+            assert_eq!(modified, &mock::NOW);
+            assert!(hash.len() > 0); // default is ""
+
+            new_hash = hash.clone();
+        }
+
+        // but the same update will not change the modified field, nor the hash
+        {
+            // knock the modified field back away from now():
+            fixtures
+                .set_episode_meta(
+                    username,
+                    EpisodeMeta {
+                        modified: Timestamp::from_millis(23),
+                        content_hash: new_hash.clone(),
+                        hash_version: HASH_VERSION,
+                    },
+                )
+                .await;
+
+            podsync.update_episodes(vec![change.clone()]).await.unwrap();
+
+            let episodes = fixtures.episode_meta(username).await;
+            let [EpisodeMeta { ref modified, content_hash: ref hash, .. }] = episodes[..] else { panic!("expected single episode") };
+
+            assert_eq!(modified, &Timestamp::from_millis(23));
+            assert_eq!(hash, &new_hash);
+        }
+
+        // and the other rows are unaffected:
+        {
+            let episodes = fixtures.episode_meta("u2").await;
+
+            let [EpisodeMeta { ref modified, content_hash: ref hash, .. }] = episodes[..] else { panic!("expected single episode") };
+
+            assert_eq!(modified, &Timestamp::from_millis(2));
+            assert_eq!(hash, "");
+        }
+    }
+
+    async fn episode_history(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+
+        let change = |action| Episode {
+            podcast: "pod1".into(),
+            episode: "ep1".into(),
+            device: Some("dev1".into()),
+            timestamp: None,
+            guid: None,
+            action,
+        };
+        let play = EpisodeAction::Play {
+            started: Some(0),
+            position: 30,
+            total: Some(60),
+        };
+
+        podsync
+            .update_episodes(vec![change(EpisodeAction::Download)])
+            .await
+            .unwrap();
+        // resending the same action in an upload isn't recorded twice
+        podsync
+            .update_episodes(vec![change(play.clone()), change(play.clone())])
+            .await
+            .unwrap();
+        // but doing it again later is, even without timestamps to tell them apart
+        for action in [EpisodeAction::Delete, EpisodeAction::Download] {
+            podsync.update_episodes(vec![change(action)]).await.unwrap();
+        }
+
+        let query = |aggregated| QueryEpisodes {
+            aggregated,
+            ..Default::default()
+        };
+
+        // aggregated (and the default) gives us just the latest state
+        for aggregated in [None, Some(true)] {
+            let Episodes { actions, .. } = podsync.episodes(query(aggregated)).await.unwrap();
+            let actions: Vec<_> = actions.into_iter().map(|ep| ep.action).collect();
+            assert_eq!(actions, vec![EpisodeAction::Download]);
+        }
+
+        // otherwise we get every action, in upload order
+        let Episodes { actions, .. } = podsync.episodes(query(Some(false))).await.unwrap();
+        let actions: Vec<_> = actions.into_iter().map(|ep| ep.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                EpisodeAction::Download,
+                play,
+                EpisodeAction::Delete,
+                EpisodeAction::Download,
+            ]
+        );
+    }
+
+    async fn episode_concurrent_uploads(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+        let episodes: Vec<_> = (0..20).map(|i| format!("ep{i}")).collect();
+        let uploads = |position, timestamp| {
+            let plays = episodes.iter().map(|ep| play(ep, position, timestamp));
+            plays.collect::<Vec<_>>()
+        };
+
+        // two devices upload at once, one with older positions - first as the episodes
+        // are new, then over what's held
+        for round in 0..10 {
+            let (older, newer) = tokio::join!(
+                podsync.update_episodes(uploads(10, round * 4 + 10)),
+                podsync.update_episodes(uploads(40, round * 4 + 12)),
+            );
+            older.unwrap();
+            newer.unwrap();
+
+            // whichever went first, the newer positions win
+            let actions = podsync
+                .episodes(QueryEpisodes::default())
+                .await
+                .unwrap()
+                .actions;
+            assert_eq!(actions.len(), episodes.len());
+            for action in actions {
+                assert_eq!(action, play(&action.episode, 40, round * 4 + 12));
+            }
+        }
+    }
+
+    async fn episode_stale_actions(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+
+        // a laptop reports its position:
+        let updated = podsync
+            .update_episodes(vec![play("ep1", 40, 30)])
+            .await
+            .unwrap();
+        assert_eq!(updated.stale_actions, vec![]);
+
+        // then a phone comes online with an older one:
+        let updated = podsync
+            .update_episodes(vec![play("ep1", 10, 20)])
+            .await
+            .unwrap();
+        assert_eq!(
+            updated.stale_actions,
+            vec![StaleAction {
+                podcast: "pod1".into(),
+                episode: "ep1".into(),
+                timestamp: Time::from_i64(20),
+            }]
+        );
+
+        // and the laptop's position is kept
+        let Episodes { actions, .. } = podsync.episodes(QueryEpisodes::default()).await.unwrap();
+        assert_eq!(actions, vec![play("ep1", 40, 30)]);
+
+        // within an upload, each action is judged against those before it
+        let updated = podsync
+            .update_episodes(vec![
+                play("ep1", 50, 40),
+                play("ep1", 45, 35),
+                play("ep1", 55, 45),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            updated.stale_actions,
+            vec![StaleAction {
+                podcast: "pod1".into(),
+                episode: "ep1".into(),
+                timestamp: Time::from_i64(35),
+            }]
+        );
+        let Episodes { actions, .. } = podsync.episodes(QueryEpisodes::default()).await.unwrap();
+        assert_eq!(actions, vec![play("ep1", 55, 45)]);
+    }
+
+    async fn subscription_repeats(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+
+        let update = |add: &[&str], remove: &[&str]| SubscriptionChangesFromClient {
+            add: add.iter().map(|url| url.to_string()).collect(),
+            remove: remove.iter().map(|url| url.to_string()).collect(),
+        };
+        let active = || async {
+            let mut add = podsync
+                .subscriptions("dev1", Timestamp::zero())
+                .await
+                .unwrap()
+                .add;
+            add.sort();
+            add
+        };
+
+        podsync
+            .update_subscriptions("dev1", update(&["pod1", "pod2", "pod1"], &[]))
+            .await
+            .unwrap();
+        assert_eq!(active().await, vec!["pod1", "pod2"]);
+
+        // removed then re-added in one go, it's still held once
+        podsync
+            .update_subscriptions("dev1", update(&["pod2", "pod3"], &["pod2", "pod2"]))
+            .await
+            .unwrap();
+        assert_eq!(active().await, vec!["pod1", "pod2", "pod3"]);
+    }
+
+    async fn episode_upload_lenient(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+
+        let body = || {
+            vec![
+                serde_json::json!({
+                    "podcast": "pod1",
+                    "episode": "ep1",
+                    "action": "download",
+                }),
+                serde_json::json!({
+                    "podcast": "pod1",
+                    "episode": "ep2",
+                    "action": "play", // no position
+                }),
+            ]
+        };
+
+        // by default, one bad action rejects the batch
+        let err = podsync
+            .upload_episodes(body(), QueryUpload { lenient: None })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest));
+
+        // otherwise we're told what was rejected
+        let updated = podsync
+            .upload_episodes(
+                body(),
+                QueryUpload {
+                    lenient: Some(true),
+                },
+            )
+            .await
+            .unwrap();
+        let [RejectedAction { index: 1, .. }] = updated.rejected[..] else {
+            panic!(
+                "expected the play action to be rejected: {:?}",
+                updated.rejected
+            )
+        };
+
+        // and the rest is stored
+        let Episodes { actions, .. } = podsync.episodes(QueryEpisodes::default()).await.unwrap();
+        let [Episode {
+            ref episode,
+            action: EpisodeAction::Download,
+            ..
+        }] = actions[..]
+        else {
+            panic!("expected a single download: {actions:?}")
+        };
+        assert_eq!(episode, "ep1");
+    }
+
+    async fn upload_string_limits(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+        let long = "x".repeat(MAX_URL + 1);
+
+        let err = podsync
+            .update_subscriptions(
+                "dev1",
+                SubscriptionChangesFromClient {
+                    add: vec!["pod1".into(), long.clone()],
+                    remove: vec![],
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest));
+
+        let err = podsync
+            .update_device(
+                &long[..MAX_DEVICE_ID + 1],
+                DeviceUpdate {
+                    caption: None,
+                    r#type: None,
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest));
+
+        let err = podsync
+            .update_device(
+                "dev1",
+                DeviceUpdate {
+                    caption: Some(long[..MAX_CAPTION + 1].into()),
+                    r#type: None,
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest));
+
+        let updated = podsync
+            .upload_episodes(
+                vec![
+                    serde_json::json!({
+                        "podcast": "pod1",
+                        "episode": "ep1",
+                        "action": "download",
+                    }),
+                    serde_json::json!({
+                        "podcast": "pod1",
+                        "episode": long,
+                        "action": "download",
+                    }),
+                ],
+                QueryUpload {
+                    lenient: Some(true),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            updated.rejected,
+            vec![RejectedAction {
+                index: 1,
+                reason: format!("episode is over {MAX_URL} bytes"),
+            }]
+        );
+    }
+
+    async fn legacy_device_ids(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+        let update = || DeviceUpdate {
+            caption: Some("phone".into()),
+            r#type: None,
+        };
+
+        // registered before device ids were limited to ASCII letters, digits, _ . -
+        podsync
+            .sync
+            .store
+            .update_device("user1", "my phone", update())
+            .await
+            .unwrap();
+
+        let changes = || SubscriptionChangesFromClient {
+            add: vec!["pod1".into()],
+            remove: vec![],
+        };
+        podsync
+            .update_subscriptions("my phone", changes())
+            .await
+            .unwrap();
+        podsync.update_device("my phone", update()).await.unwrap();
+
+        let action = |device: &str| {
+            serde_json::json!({
+                "podcast": "pod1",
+                "episode": "ep1",
+                "action": "download",
+                "device": device,
+            })
+        };
+        let updated = podsync
+            .upload_episodes(
+                vec![action("my phone"), action("new phone")],
+                QueryUpload {
+                    lenient: Some(true),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            updated.rejected.iter().map(|r| r.index).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        // but new devices are held to it
+        let err = podsync
+            .update_subscriptions("new phone", changes())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest));
+        let err = podsync
+            .update_device("new phone", update())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadRequest));
+    }
+
+    async fn episode_action_types(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+
+        let body = vec![
+            serde_json::json!({
+                "podcast": "pod1",
+                "episode": "ep1",
+                "guid": "guid1",
+                "action": "flattr",
+            }),
+            serde_json::json!({
+                "podcast": "pod1",
+                "episode": "ep2",
+                "action": "Bookmark",
+            }),
+            serde_json::json!({
+                "podcast": "pod1",
+                "episode": "ep3",
+                "action": "PLAY",
+                "position": 20,
+            }),
+        ];
+        podsync
+            .upload_episodes(body, QueryUpload { lenient: None })
+            .await
+            .unwrap();
 
-        trace!("{username} updating episodes");
+        let Episodes { actions, .. } = podsync.episodes(QueryEpisodes::default()).await.unwrap();
+        let actions = serde_json::to_value(actions).unwrap();
 
-        let changes = body
-            .into_iter()
-            .map(TryInto::try_into)
-            .collect::<result::Result<Vec<Episode>, _>>()
-            .map_err(|e| {
-                error!("couldn't construct episode changes from user: {e:?}");
-                Error::BadRequest
-            })?;
+        assert_eq!(actions[0]["action"], "flattr");
+        assert_eq!(actions[0]["guid"], "guid1");
+        assert_eq!(actions[1]["action"], "Bookmark");
+        assert_eq!(actions[2]["action"], "play");
+        assert_eq!(actions[2]["position"], 20);
+        assert_eq!(actions[2].get("started"), None);
+        assert_eq!(actions[2].get("total"), None);
+    }
 
-        let now = now()?;
-        let change_count = changes.len();
+    async fn episode_rehashing(backend: mock::Backend) {
+        let (podsync, fixtures) = create_podsync_with(backend, "user1", Default::default()).await;
 
-        self.transact(|mut tx| async {
-            for change in changes {
-                let hash = change.hash();
+        let change = Episode {
+            podcast: "pod1".into(),
+            episode: "ep1".into(),
+            device: None,
+            timestamp: None,
+            guid: None,
+            action: EpisodeAction::Download,
+        };
+        podsync.update_episodes(vec![change.clone()]).await.unwrap();
 
-                let EpisodeRaw {
-                    podcast,
-                    episode,
-                    timestamp,
-                    guid,
-                    action,
-                    started,
-                    position,
-                    total,
-                    device,
-                    modified: _,
-                } = change.into();
-
-                query!(
-                    "
-                    INSERT INTO episodes
-                    (
-                        username, device,
-                        podcast, episode,
-                        timestamp, guid,
-                        action,
-                        started, position, total,
-                        modified
-                    )
-                    VALUES
-                    (
-                        ?, ?,
-                        ?, ?,
-                        ?, ?,
-                        ?,
-                        ?, ?, ?,
-                        ?
-                    )
-                    ON CONFLICT
-                    DO
-                        UPDATE SET
-                            timestamp = coalesce(?, episodes.timestamp),
-                            guid = coalesce(?, episodes.guid),
-                            action = coalesce(?, episodes.action),
-                            started = coalesce(?, episodes.started),
-                            position = coalesce(?, episodes.position),
-                            total = coalesce(?, episodes.total),
-                            modified = ?,
-                            content_hash = ?
-                        -- only update if we've changed the contents
-                        WHERE content_hash <> ?
-                    ",
-                    // values
-                    username,
-                    device,
-                    podcast,
-                    episode,
-                    timestamp,
-                    guid,
-                    action,
-                    started,
-                    position,
-                    total,
-                    now,
-                    // update
-                    timestamp,
-                    guid,
-                    action,
-                    started,
-                    position,
-                    total,
-                    now,
-                    hash,
-                    // update where
-                    hash,
-                )
-                .execute(&mut tx)
-                .await
-                .map_err(|e| {
-                    error!("error querying mid-transaction: {:?}", e);
-                    Error::Internal
-                })?;
-            }
+        // given a row from an older build:
+        fixtures
+            .set_episode_meta(
+                "user1",
+                EpisodeMeta {
+                    modified: Timestamp::from_millis(23),
+                    content_hash: "legacy".into(),
+                    hash_version: 0,
+                },
+            )
+            .await;
+
+        podsync.sync.rehash_episodes().await.unwrap();
+
+        // it's rehashed, without bumping `modified`
+        assert_eq!(
+            fixtures.episode_meta("user1").await,
+            vec![EpisodeMeta {
+                modified: Timestamp::from_millis(23),
+                content_hash: change.hash(),
+                hash_version: HASH_VERSION,
+            }]
+        );
+    }
 
-            Ok((tx, ()))
-        })
-        .await?;
+    async fn episode_since(backend: mock::Backend) {
+        let clock = Arc::new(mock::Clock::default());
+        let podsync = create_podsync_with(backend, "user1", Arc::clone(&clock))
+            .await
+            .0;
+
+        let change = |episode: &str| Episode {
+            podcast: "pod1".into(),
+            episode: episode.into(),
+            device: None,
+            timestamp: None,
+            guid: None,
+            action: EpisodeAction::Download,
+        };
 
-        info!("{username} updated {change_count} episodes, timestamp {now}");
+        let updated = podsync.update_episodes(vec![change("ep1")]).await.unwrap();
+        assert_eq!(updated.timestamp, mock::NOW);
 
-        let update_timestamp = now;
-        Ok(UpdatedUrls::just_timestamp(update_timestamp))
+        clock.advance(1_500);
+        podsync.update_episodes(vec![change("ep2")]).await.unwrap();
+
+        let Episodes {
+            timestamp, actions, ..
+        } = podsync
+            .episodes(QueryEpisodes {
+                since: Some(updated.timestamp),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let [Episode { ref episode, .. }] = actions[..] else {
+            panic!("expected a single episode: {actions:?}")
+        };
+        assert_eq!(episode, "ep2");
+        assert_eq!(timestamp, Timestamp::from_millis(26_500));
+        assert_eq!(timestamp.as_secs(), 26);
     }
-}
 
-fn now() -> Result<Timestamp> {
-    Timestamp::now().map_err(|e| {
-        error!("couldn't get time: {e:?}");
-        Error::Internal
-    })
-}
+    async fn export_import(backend: mock::Backend) {
+        let clock = Arc::new(mock::Clock::default());
+        let (podsync, fixtures) = create_podsync_with(backend, "user1", Arc::clone(&clock)).await;
+        let sync = &podsync.sync;
+        sync.store.create_user("user1", "").await.unwrap();
+        sync.store.create_user("user2", "").await.unwrap();
+
+        podsync
+            .update_device(
+                "dev1",
+                DeviceUpdate {
+                    caption: Some("phone".into()),
+                    r#type: Some(DeviceType::Mobile),
+                },
+            )
+            .await
+            .unwrap();
+        podsync
+            .update_subscriptions(
+                "dev1",
+                SubscriptionChangesFromClient {
+                    add: vec!["pod1".into(), "pod2".into()],
+                    remove: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        clock.advance(1_500);
+        podsync
+            .update_subscriptions(
+                "dev1",
+                SubscriptionChangesFromClient {
+                    add: vec![],
+                    remove: vec!["pod2".into()],
+                },
+            )
+            .await
+            .unwrap();
 
-impl UpdatedUrls {
-    pub fn just_timestamp(timestamp: Timestamp) -> Self {
-        Self {
-            timestamp,
-            update_urls: Default::default(),
+        podsync
+            .update_episodes(vec![play("ep1", 40, 30)])
+            .await
+            .unwrap();
+        // stale, but still in the history
+        podsync
+            .update_episodes(vec![play("ep1", 10, 20)])
+            .await
+            .unwrap();
+        // without timestamps, none is ever stale, and the repeat is kept
+        let untimed = |position| Episode {
+            timestamp: None,
+            ..play("ep2", position, 0)
+        };
+        for position in [5, 15, 5] {
+            podsync
+                .update_episodes(vec![untimed(position)])
+                .await
+                .unwrap();
         }
+
+        let export = sync.export_user("user1").await.unwrap();
+        assert_eq!(export.devices.len(), 1);
+        assert_eq!(export.subscriptions.len(), 2);
+        assert_eq!(export.episodes.len(), 5);
+
+        // it survives a trip through JSON, keeping the subscriptions' milliseconds
+        let json = serde_json::to_string(&export).unwrap();
+        let import = |json: &str| serde_json::from_str::<Export>(json).unwrap();
+        assert_eq!(import(&json), export);
+
+        let summary = sync.import_user("user2", import(&json)).await.unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                devices: 1,
+                subscriptions: 2,
+                episodes: 5,
+                stale_episodes: 1,
+            }
+        );
+
+        // as if taken from user1 at the same time
+        let exported = export.exported;
+        let reexport = |username| async move {
+            let mut export = sync.export_user(username).await.unwrap();
+            export.username = "user1".into();
+            export.exported = exported;
+            export
+        };
+        assert_eq!(reexport("user2").await, export);
+
+        // importing again, later, changes nothing
+        let meta = fixtures.episode_meta("user2").await;
+        clock.advance(1_000);
+        let summary = sync.import_user("user2", import(&json)).await.unwrap();
+        assert_eq!(summary.subscriptions, 0);
+        assert_eq!(reexport("user2").await, export);
+        assert_eq!(fixtures.episode_meta("user2").await, meta);
+
+        // nor can we import into a user that doesn't exist, or from a future podsync
+        assert!(matches!(
+            sync.import_user("user3", import(&json)).await,
+            Err(Error::BadRequest)
+        ));
+        let future = Export {
+            version: EXPORT_VERSION + 1,
+            ..import(&json)
+        };
+        assert!(matches!(
+            sync.import_user("user2", future).await,
+            Err(Error::BadRequest)
+        ));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    async fn export_opml_csv(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+        let sync = &podsync.sync;
+        sync.store.create_user("user1", "").await.unwrap();
+
+        for (device, add, remove) in [
+            ("dev1", vec!["pod1", "pod2"], vec![]),
+            ("dev2", vec!["pod2", "pod3"], vec![]),
+            ("dev2", vec![], vec!["pod3"]),
+        ] {
+            podsync
+                .update_subscriptions(
+                    device,
+                    SubscriptionChangesFromClient {
+                        add: add.into_iter().map(Into::into).collect(),
+                        remove: remove.into_iter().map(Into::into).collect(),
+                    },
+                )
+                .await
+                .unwrap();
+        }
 
-    use uuid::Uuid;
+        let feeds = |opml: String| migrate::from_opml(&opml).unwrap().subscriptions;
 
-    use crate::episode::{EpisodeAction, Time};
+        // merged, without the removed pod3
+        let opml = sync.export_opml("user1", None).await.unwrap();
+        assert!(opml.contains("<title>user1&apos;s subscriptions</title>"));
+        assert_eq!(feeds(opml), vec!["pod1", "pod2"]);
 
-    use crate::mock;
+        let opml = sync.export_opml("user1", Some("dev2")).await.unwrap();
+        assert_eq!(feeds(opml), vec!["pod2"]);
 
-    fn create_session() -> SessionId {
-        Uuid::try_parse("550e8400-e29b-41d4-a716-446655440000")
-            .unwrap()
-            .into()
-    }
+        podsync
+            .update_episodes(vec![play("ep1", 10, 20), play("ep1", 40, 30)])
+            .await
+            .unwrap();
 
-    async fn create_podsync(username: &str) -> PodSyncAuthed<true> {
-        let db = mock::create_db().await;
-        let podsync = Arc::new(PodSync(db));
-        PodSyncAuthed {
-            sync: podsync,
-            session_id: create_session(),
-            username: username.into(),
-        }
+        // just the latest action for each episode
+        let csv = sync.export_csv("user1").await.unwrap();
+        let rows: Vec<_> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![format!(
+                "pod1,ep1,,play,0,40,60,1970-01-01T00:00:30Z,dev1,{}",
+                mock::NOW
+            )]
+        );
+
+        assert!(matches!(
+            sync.export_opml("user2", None).await,
+            Err(Error::BadRequest)
+        ));
+        assert!(matches!(
+            sync.export_csv("user2").await,
+            Err(Error::BadRequest)
+        ));
     }
 
-    #[tokio::test]
-    async fn episode_hashing() {
-        let username = "user1";
-        let podcast = "pod1";
-        let episode = "ep1";
-        let device = "dev1";
+    async fn migrate_dry_run(backend: mock::Backend) {
+        let podsync = create_podsync(backend, "user1").await;
+        let sync = &podsync.sync;
+        sync.store.create_user("user1", "").await.unwrap();
+
+        podsync
+            .update_subscriptions(
+                "dev1",
+                SubscriptionChangesFromClient {
+                    add: vec!["pod1".into()],
+                    remove: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        podsync
+            .update_episodes(vec![play("ep1", 10, 20), play("ep2", 10, 20)])
+            .await
+            .unwrap();
 
-        let podsync = create_podsync(username).await;
+        let migration = || Migration {
+            subscriptions: vec!["pod1".into(), "pod2".into()],
+            episodes: vec![
+                play("ep1", 30, 30), // newer
+                play("ep2", 5, 10),  // older
+                play("ep3", 10, 20),
+            ],
+            rejected: 1,
+        };
+        let expected = MigrationReport {
+            subscriptions_added: vec!["pod2".into()],
+            subscriptions_held: 1,
+            episodes_new: 1,
+            episodes_updated: 1,
+            episodes_unchanged: 0,
+            episodes_stale: 1,
+            episodes_rejected: 1,
+        };
 
-        // given an "old" episode:
-        query!(
-            r#"
-            INSERT INTO episodes
-            (
-                username, device,
-                podcast, episode,
-                timestamp, guid,
-                action,
-                started, position, total,
-                modified
+        let before = sync.export_user("user1").await.unwrap();
+        let report = sync
+            .migrate("user1", "dev1", migration(), true)
+            .await
+            .unwrap();
+        assert_eq!(report, expected);
+        assert_eq!(sync.export_user("user1").await.unwrap(), before);
+
+        let report = sync
+            .migrate("user1", "dev1", migration(), false)
+            .await
+            .unwrap();
+        assert_eq!(report, expected);
+
+        let subs = podsync
+            .subscriptions("dev1", Timestamp::zero())
+            .await
+            .unwrap();
+        assert_eq!(subs.add, vec!["pod1", "pod2"]);
+
+        // and having migrated, there's nothing left to do
+        let report = sync
+            .migrate("user1", "dev1", migration(), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                subscriptions_added: vec![],
+                subscriptions_held: 2,
+                episodes_new: 0,
+                episodes_updated: 0,
+                episodes_unchanged: 2,
+                episodes_stale: 1,
+                episodes_rejected: 1,
+            }
+        );
+    }
+
+    async fn doctor(backend: mock::Backend) {
+        let clock = Arc::new(mock::Clock::default());
+        let (podsync, fixtures) = create_podsync_with(backend, "user1", Arc::clone(&clock)).await;
+        let sync = &podsync.sync;
+
+        // dev2 only ever had subscriptions uploaded, and dev1 has a duplicate
+        podsync
+            .update_device(
+                "dev1",
+                DeviceUpdate {
+                    caption: None,
+                    r#type: None,
+                },
             )
-            VALUES
-            (
-                ?, ?,
-                ?, ?,
-                NULL, NULL,
-                "New",
-                NULL, NULL, NULL,
-                1 -- `modified` value we expect to be overwritten
-                -- `hash` is defaulted to ""
-            ),
-            (
-                "u2", "dev2", -- this row won't be picked up - different user
-                "pod2", "ep2",
-                NULL, NULL,
-                "New",
-                NULL, NULL, NULL,
-                2
+            .await
+            .unwrap();
+        let sub = |url: &str| Subscription {
+            url: url.into(),
+            created: mock::NOW,
+            deleted: None,
+        };
+        fixtures
+            .insert_subscription("user1", "dev1", sub("pod1"))
+            .await;
+        fixtures
+            .insert_subscription("user1", "dev1", sub("pod1"))
+            .await;
+        fixtures
+            .insert_subscription("user1", "dev2", sub("pod2"))
+            .await;
+
+        // a play without a position hides the download before it, and fails all episodes
+        let raw = |episode: &str, action, position, timestamp| EpisodeRaw {
+            podcast: "pod1".into(),
+            episode: episode.into(),
+            timestamp: Some(Time::from_i64(timestamp)),
+            guid: None,
+            action,
+            started: None,
+            position,
+            total: None,
+            device: None,
+            modified: None,
+        };
+        for (action, timestamp) in [
+            (EpisodeActionRaw::Download, 10),
+            (EpisodeActionRaw::Play, 20),
+        ] {
+            sync.store
+                .update_episodes(
+                    "user1",
+                    vec![raw("ep1", action, None, timestamp)],
+                    mock::NOW,
+                )
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            podsync.episodes(QueryEpisodes::default()).await,
+            Err(Error::Internal)
+        ));
+
+        // and a play from before content hashes
+        fixtures
+            .insert_episode(
+                "user1",
+                raw("ep2", EpisodeActionRaw::Play, Some(10), 30),
+                EpisodeMeta {
+                    modified: mock::NOW,
+                    content_hash: "".into(),
+                    hash_version: HASH_VERSION,
+                },
             )
-        "#,
-            username,
-            device,
-            podcast,
-            episode,
-        )
-        .execute(&podsync.sync.0)
-        .await
-        .unwrap();
+            .await;
+
+        let found = Diagnosis {
+            duplicate_subscriptions: vec![("user1".into(), "dev1".into(), "pod1".into())],
+            unknown_devices: vec![("user1".into(), "dev2".into())],
+            positionless_plays: vec![("user1".into(), "pod1".into(), "ep1".into())],
+            positionless_history: 1,
+            unhashed_episodes: 1,
+        };
+        // checking changes nothing
+        assert_eq!(sync.doctor(false).await.unwrap(), found);
+        clock.advance(1_000);
+        assert_eq!(sync.doctor(true).await.unwrap(), found);
+        assert!(sync.doctor(false).await.unwrap().is_healthy());
+
+        let devices = podsync.devices().await.unwrap();
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| (&d.id[..], d.subscriptions))
+                .collect::<Vec<_>>(),
+            vec![("dev1", 1), ("dev2", 1)]
+        );
 
-        // when we get a change to it:
-        let change = Episode {
+        // ep1 is back to its download
+        let mut episodes = podsync
+            .episodes(QueryEpisodes::default())
+            .await
+            .unwrap()
+            .actions;
+        episodes.sort_by(|a, b| a.episode.cmp(&b.episode));
+        assert_eq!(
+            episodes.iter().map(|ep| &ep.action).collect::<Vec<_>>(),
+            vec![
+                &EpisodeAction::Download,
+                &EpisodeAction::Play {
+                    started: None,
+                    position: 10,
+                    total: None,
+                },
+            ]
+        );
+        // as a change since, so clients fetch it again
+        let since = podsync
+            .episodes(QueryEpisodes {
+                since: Some(Timestamp::from_millis(mock::NOW.as_millis() + 1)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            since
+                .actions
+                .iter()
+                .map(|ep| &ep.episode[..])
+                .collect::<Vec<_>>(),
+            ["ep1"]
+        );
+        assert!(fixtures
+            .episode_meta("user1")
+            .await
+            .iter()
+            .all(|meta| !meta.content_hash.is_empty()));
+    }
+
+    async fn prune(backend: mock::Backend) {
+        let clock = Arc::new(mock::Clock::default());
+        let podsync = create_podsync_with(backend, "user1", Arc::clone(&clock))
+            .await
+            .0;
+        let sync = &podsync.sync;
+
+        // pod1 is re-subscribed, pod2 removed twice and pod3 kept throughout
+        for (add, remove) in [
+            (vec!["pod1", "pod2", "pod3"], vec![]),
+            (vec![], vec!["pod1", "pod2"]),
+            (vec!["pod1", "pod2"], vec![]),
+            (vec![], vec!["pod2"]),
+        ] {
+            podsync
+                .update_subscriptions(
+                    "dev1",
+                    SubscriptionChangesFromClient {
+                        add: add.into_iter().map(Into::into).collect(),
+                        remove: remove.into_iter().map(Into::into).collect(),
+                    },
+                )
+                .await
+                .unwrap();
+            clock.advance(1_000);
+        }
+
+        let change = |podcast: &str| Episode {
             podcast: podcast.into(),
-            episode: episode.into(),
+            episode: "ep1".into(),
             device: None,
             timestamp: None,
             guid: None,
-            action: EpisodeAction::New,
+            action: EpisodeAction::Download,
         };
-        podsync.update_episodes(vec![change.clone()]).await.unwrap();
+        podsync
+            .update_episodes(vec![change("pod1"), change("pod2")])
+            .await
+            .unwrap();
 
-        // then we expect an update to specific fields:
-        {
-            let Episodes { actions: eps, .. } = podsync
-                .episodes(QueryEpisodes {
-                    since: None,
-                    aggregated: None,
-                    podcast: None,
-                    device: None,
-                })
+        let subscriptions = || async {
+            let mut changes = podsync
+                .subscriptions("dev1", Timestamp::zero())
                 .await
                 .unwrap();
+            changes.add.sort();
+            changes.remove.sort();
+            (changes.add, changes.remove)
+        };
+        assert_eq!(
+            subscriptions().await,
+            (
+                vec!["pod1".into(), "pod3".into()],
+                vec!["pod1".into(), "pod2".into(), "pod2".into()]
+            )
+        );
 
-            let [ref ep] = eps[..] else { panic!("expected single episode") };
+        clock.advance(10 * 24 * 60 * 60 * 1000);
+
+        // nothing's old enough yet
+        assert_eq!(
+            sync.prune(Some(30), Some(30)).await.unwrap(),
+            Pruned::default()
+        );
+
+        // the superseded removals go, pod2's latest stays as its tombstone
+        assert_eq!(
+            sync.prune(Some(7), Some(7)).await.unwrap(),
+            Pruned {
+                subscriptions: 2,
+                episodes: 1,
+                history: 1,
+            }
+        );
+        assert_eq!(
+            subscriptions().await,
+            (vec!["pod1".into(), "pod3".into()], vec!["pod2".into()])
+        );
 
+        for aggregated in [true, false] {
+            let actions = podsync
+                .episodes(QueryEpisodes {
+                    aggregated: Some(aggregated),
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .actions;
             assert_eq!(
-                ep,
-                &Episode {
-                    podcast: podcast.into(),
-                    episode: episode.into(),
-                    device: Some(device.into()),
-                    timestamp: Some(Time::from_i64(0)),
-                    guid: None,
-                    action: EpisodeAction::New,
-                }
+                actions.iter().map(|ep| &ep.podcast[..]).collect::<Vec<_>>(),
+                vec!["pod1"]
             );
         }
+    }
 
-        struct SmallEp {
-            modified: Timestamp,
-            hash: String,
-        }
-        let query_episodes = || async {
-            query_as!(
-                SmallEp,
-                r#"
-                SELECT modified as "modified: _", content_hash as "hash!: _"
-                FROM episodes
-                WHERE username = ?
-                "#,
-                username
-            )
-            .fetch_all(&podsync.sync.0)
+    async fn episode_pages(backend: mock::Backend) {
+        let clock = Arc::new(mock::Clock::default());
+        let podsync = create_podsync_with(backend, "user1", Arc::clone(&clock))
             .await
-            .unwrap()
+            .0;
+
+        let download = |episode: &str| Episode {
+            podcast: "pod1".into(),
+            episode: episode.into(),
+            device: None,
+            timestamp: None,
+            guid: None,
+            action: EpisodeAction::Download,
         };
+        podsync
+            .update_episodes(["ep1", "ep2", "ep3", "ep4"].map(download).to_vec())
+            .await
+            .unwrap();
+        // which moves ep2 to the end
+        clock.advance(1_500);
+        podsync
+            .update_episodes(vec![Episode {
+                action: EpisodeAction::Delete,
+                ..download("ep2")
+            }])
+            .await
+            .unwrap();
 
-        // and our modified timestamp to have changed, along with the hash:
-        let new_hash;
-        {
-            let episodes = query_episodes().await;
-            let [SmallEp { ref modified, ref hash }] = episodes[..] else { panic!("expected single episode") };
+        let podsync = &podsync;
+        let pages = |aggregated, limit| async move {
+            let mut pages = vec![];
+            let mut cursor = None;
 
-            assert_eq!(modified, &Timestamp::now().unwrap());
-            assert!(hash.len() > 0); // default is ""
+            loop {
+                let Episodes {
+                    timestamp,
+                    actions,
+                    next,
+                } = podsync
+                    .episodes(QueryEpisodes {
+                        aggregated: Some(aggregated),
+                        limit: Some(limit),
+                        cursor,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+
+                pages.push((
+                    actions.into_iter().map(|ep| ep.episode).collect::<Vec<_>>(),
+                    timestamp.as_secs(),
+                ));
+
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => break pages,
+                }
+            }
+        };
 
-            new_hash = hash.clone();
-        }
+        assert_eq!(
+            pages(true, 2).await,
+            vec![
+                (vec!["ep1".to_string(), "ep3".into()], 25),
+                (vec!["ep4".into(), "ep2".into()], 26),
+            ]
+        );
+        // a page that's just big enough has no next
+        assert_eq!(pages(true, 4).await.len(), 1);
+        assert_eq!(
+            pages(false, 3).await,
+            vec![
+                (vec!["ep1".to_string(), "ep2".into(), "ep3".into()], 25),
+                (vec!["ep4".into(), "ep2".into()], 26),
+            ]
+        );
 
-        // but the same update will not change the modified field, nor the hash
-        {
-            // knock the modified field back away from now():
-            query!(
-                "UPDATE episodes SET modified = 23 WHERE username = ?",
-                username
-            )
-            .execute(&podsync.sync.0)
+        // cursors are only good for the kind of actions they came from
+        let next = podsync
+            .episodes(QueryEpisodes {
+                limit: Some(1),
+                ..Default::default()
+            })
             .await
-            .unwrap();
+            .unwrap()
+            .next;
+        for query in [
+            QueryEpisodes {
+                aggregated: Some(false),
+                cursor: next,
+                ..Default::default()
+            },
+            QueryEpisodes {
+                limit: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                podsync.episodes(query).await,
+                Err(Error::BadRequest)
+            ));
+        }
+    }
 
-            podsync.update_episodes(vec![change.clone()]).await.unwrap();
+    async fn registration(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        let clock = Arc::new(mock::Clock::default());
+        let with = |registration| {
+            Arc::new(
+                PodSync::with_clock(Arc::clone(&store), Arc::clone(&clock) as _)
+                    .with_registration(registration),
+            )
+        };
 
-            let episodes = query_episodes().await;
-            let [SmallEp { ref modified, ref hash }] = episodes[..] else { panic!("expected single episode") };
+        let closed = with(Registration::Closed);
+        assert!(matches!(
+            closed.register("alice", "pw", None).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(store.user("alice").await.unwrap().is_none());
 
-            assert_eq!(modified, &Timestamp::from_i64(23));
-            assert_eq!(hash, &new_hash);
+        let open = with(Registration::Open);
+        open.register("alice", "pw", Some("ignored")).await.unwrap();
+        open.login(AuthAttempt::new("alice".into(), "pw".into()), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            open.register("alice", "other", None).await,
+            Err(Error::Conflict)
+        ));
+        // names have to work in the API's paths
+        for username in ["a/b", "bob.json/..", ".hidden", ""] {
+            assert!(matches!(
+                open.register(username, "pw", None).await,
+                Err(Error::BadRequest)
+            ));
         }
-
-        // and the other rows are unaffected:
-        {
-            let episodes = query_as!(
-                SmallEp,
-                r#"
-                SELECT modified as "modified: _", content_hash as "hash!: _"
-                FROM episodes
-                WHERE username = "u2"
-                "#
-            )
-            .fetch_all(&podsync.sync.0)
+        assert!(matches!(
+            open.register("bob", "", None).await,
+            Err(Error::BadRequest)
+        ));
+
+        let invited = with(Registration::Invite);
+        let admin = PodSyncAdmin {
+            sync: Arc::clone(&invited),
+            username: "alice".into(),
+        };
+        assert!(matches!(
+            admin.create_invite(0, None).await,
+            Err(Error::BadRequest)
+        ));
+        let twice = admin.create_invite(2, None).await.unwrap();
+        clock.advance(1_000);
+        let week = admin.create_invite(1, Some(7)).await.unwrap();
+        let week_created = Timestamp::from_millis(mock::NOW.as_millis() + 1_000);
+        assert_eq!(week.expires, Some(week_created.days_after(7)));
+
+        assert!(matches!(
+            invited.register("bob", "pw", None).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            invited.register("bob", "pw", Some("nonsense")).await,
+            Err(Error::Forbidden)
+        ));
+
+        invited
+            .register("bob", "pw", Some(&twice.code))
             .await
             .unwrap();
+        // a taken name doesn't use the invite up
+        assert!(matches!(
+            invited.register("bob", "pw", Some(&twice.code)).await,
+            Err(Error::Conflict)
+        ));
+        invited
+            .register("carol", "pw", Some(&twice.code))
+            .await
+            .unwrap();
+        assert!(matches!(
+            invited.register("dave", "pw", Some(&twice.code)).await,
+            Err(Error::Forbidden)
+        ));
+
+        // newest first
+        let invites = admin.invites().await.unwrap();
+        assert_eq!(
+            invites
+                .iter()
+                .map(|i| (i.invite.code.as_str(), i.invite.used, i.usable))
+                .collect::<Vec<_>>(),
+            [
+                (week.code.as_str(), 0, true),
+                (twice.code.as_str(), 2, false)
+            ]
+        );
 
-            let [SmallEp { ref modified, ref hash }] = episodes[..] else { panic!("expected single episode") };
-
-            assert_eq!(modified, &Timestamp::from_i64(2));
-            assert_eq!(hash, "");
-        }
+        clock.advance(7 * 24 * 60 * 60 * 1000);
+        assert!(matches!(
+            invited.register("dave", "pw", Some(&week.code)).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(store.user("dave").await.unwrap().is_none());
+
+        let unused = admin.create_invite(1, None).await.unwrap();
+        admin.delete_invite(&unused.code).await.unwrap();
+        assert!(matches!(
+            invited.register("dave", "pw", Some(&unused.code)).await,
+            Err(Error::Forbidden)
+        ));
+        assert_eq!(admin.invites().await.unwrap().len(), 2);
     }
 }
@@ -0,0 +1,236 @@
+// A synthetic instance with a few large users, timing the queries each sync makes
+// with and without the indexes, and a first sync's uploads. Ignored by default, run with:
+//
+//     cargo test --release store::bench -- --ignored --nocapture
+
+use std::time::{Duration, Instant};
+
+use sqlx::{query, SqlitePool};
+
+use super::{EpisodeFilter, SqliteStore, Store};
+use crate::episode::{EpisodeActionRaw, EpisodeRaw, Time};
+use crate::mock;
+use crate::subscription::SubscriptionChangesFromClient;
+use crate::time::Timestamp;
+
+const USERS: usize = 10;
+const EPISODES: usize = 50_000;
+const SUBSCRIPTIONS: usize = 2_000;
+const RUNS: u32 = 20;
+const UPLOAD: usize = 10_000;
+
+const INDEXES: &[&str] = &[
+    "episodes_by_modified",
+    "episode_history_by_modified",
+    "subscriptions_by_created",
+    "subscriptions_by_deleted",
+];
+
+#[tokio::test]
+#[ignore]
+async fn large_user() {
+    let db = mock::create_db().await;
+    seed(&db).await;
+
+    let store = SqliteStore::new(db.clone());
+    let indexed = time_syncs(&store).await;
+
+    for index in INDEXES {
+        query(&format!("DROP INDEX {index}"))
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+    let unindexed = time_syncs(&store).await;
+
+    println!(
+        "{USERS} users, each with {EPISODES} episodes and {SUBSCRIPTIONS} subscriptions, \
+        mean of {RUNS} runs"
+    );
+    for ((name, indexed), (_, unindexed)) in indexed.iter().zip(&unindexed) {
+        println!(
+            "{name:<24} {:>10.3}ms indexed {:>10.3}ms without",
+            indexed.as_secs_f64() * 1000.0,
+            unindexed.as_secs_f64() * 1000.0,
+        );
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn large_upload() {
+    let store = SqliteStore::new(mock::create_db().await);
+
+    let urls: Vec<String> = (0..UPLOAD).map(|i| format!("pod{i}")).collect();
+    let actions: Vec<_> = (0..UPLOAD)
+        .map(|i| EpisodeRaw {
+            podcast: format!("pod{}", i % 100),
+            episode: format!("ep{i}"),
+            timestamp: Time::from_unix_millis(1_677_664_800_000 + i as i64 * 1000),
+            guid: None,
+            action: EpisodeActionRaw::Play,
+            started: None,
+            position: Some((i % 3600) as i64),
+            total: None,
+            device: Some("dev1".into()),
+            modified: None,
+        })
+        .collect();
+
+    let subscriptions = |add: &[String], remove: &[String]| SubscriptionChangesFromClient {
+        add: add.to_vec(),
+        remove: remove.to_vec(),
+    };
+    let timings = [
+        (
+            "subscribe",
+            elapsed(store.update_subscriptions(
+                "user1",
+                "dev1",
+                &subscriptions(&urls, &[]),
+                mock::NOW,
+            ))
+            .await,
+        ),
+        (
+            "unsubscribe",
+            elapsed(store.update_subscriptions(
+                "user1",
+                "dev1",
+                &subscriptions(&[], &urls),
+                mock::NOW,
+            ))
+            .await,
+        ),
+        (
+            "episodes",
+            elapsed(store.update_episodes("user1", actions.clone(), mock::NOW)).await,
+        ),
+        (
+            "episodes (resent)",
+            elapsed(store.update_episodes("user1", actions, mock::NOW)).await,
+        ),
+    ];
+
+    println!("uploads of {UPLOAD}");
+    for (name, elapsed) in timings {
+        println!(
+            "{name:<24} {:>10.3}ms {:>10.0}/s",
+            elapsed.as_secs_f64() * 1000.0,
+            UPLOAD as f64 / elapsed.as_secs_f64(),
+        );
+    }
+}
+
+// every user's episodes are modified a second apart, and a quarter of their
+// subscriptions are deleted, so a sync of the last minute finds a handful of each
+async fn seed(db: &SqlitePool) {
+    let rows = USERS * EPISODES;
+
+    for table in ["episodes", "episode_history"] {
+        query(&format!(
+            "
+            WITH RECURSIVE n(i) AS (
+                SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i + 1 < {rows}
+            )
+            INSERT INTO {table}
+            (
+                username, device,
+                podcast, episode,
+                action, position,
+                modified, content_hash, hash_version
+            )
+            SELECT
+                'user' || (i % {USERS}), 'dev1',
+                'pod' || (i % 100), 'ep' || i,
+                'Play', i % 3600,
+                (i / {USERS}) * 1000, 'hash' || i, 1
+            FROM n
+            "
+        ))
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    let rows = USERS * SUBSCRIPTIONS;
+    query(&format!(
+        "
+        WITH RECURSIVE n(i) AS (
+            SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i + 1 < {rows}
+        )
+        INSERT INTO subscriptions
+        (username, device, url, created, deleted)
+        SELECT
+            'user' || (i % {USERS}), 'dev' || (i % 2), 'pod' || i,
+            (i / {USERS}) * 1000,
+            CASE WHEN (i / {USERS}) % 4 = 0 THEN (i / {USERS} + {SUBSCRIPTIONS}) * 1000 END
+        FROM n
+        "
+    ))
+    .execute(db)
+    .await
+    .unwrap();
+
+    query("ANALYZE").execute(db).await.unwrap();
+}
+
+async fn time_syncs(store: &SqliteStore) -> Vec<(&'static str, Duration)> {
+    let recently = |count: usize| Timestamp::from_millis((count as i64 - 60) * 1000);
+    let episodes = |aggregated| EpisodeFilter {
+        since: recently(EPISODES),
+        podcast: None,
+        device: None,
+        aggregated,
+        after: None,
+        limit: None,
+    };
+
+    let mut timings = vec![];
+
+    for (name, aggregated) in [("episodes", true), ("episodes (history)", false)] {
+        let filter = episodes(aggregated);
+        timings.push((
+            name,
+            mean(|| async {
+                let actions = store.episodes("user3", &filter).await.unwrap();
+                assert_eq!(actions.len(), 59);
+            })
+            .await,
+        ));
+    }
+
+    timings.push((
+        "subscriptions",
+        mean(|| async {
+            let subs = store
+                .subscriptions("user3", "dev1", recently(SUBSCRIPTIONS * 2))
+                .await
+                .unwrap();
+            assert!(!subs.is_empty());
+        })
+        .await,
+    ));
+
+    timings
+}
+
+async fn elapsed<T>(
+    upload: impl std::future::Future<Output = crate::podsync::Result<T>>,
+) -> Duration {
+    let start = Instant::now();
+    upload.await.unwrap();
+    start.elapsed()
+}
+
+async fn mean<F, Fut>(sync: F) -> Duration
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        sync().await;
+    }
+    start.elapsed() / RUNS
+}
@@ -0,0 +1,893 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use log::error;
+
+use super::{
+    is_stale, unheld, without_resends, Diagnosis, EpisodeCursor, EpisodeFilter, EpisodeRow,
+    EpisodeStream, Pruned, Store, UserSummary,
+};
+use crate::device::{DeviceAndSub, DeviceType, DeviceUpdate};
+use crate::episode::{EpisodeActionRaw, EpisodeRaw, HASH_VERSION};
+use crate::podsync::{Error, Result};
+use crate::subscription::{Subscription, SubscriptionChangesFromClient};
+use crate::time::Timestamp;
+use crate::user::{Invite, User};
+
+// Keeps everything in memory, for tests and `--ephemeral` instances.
+// Holding the lock for the whole of each call gives us our atomicity.
+#[derive(Default)]
+pub struct MemoryStore(Mutex<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    users: Vec<User>,
+    invites: Vec<Invite>,
+    devices: Vec<Device>,
+    subscriptions: Vec<SubscriptionEntry>,
+    episodes: Vec<EpisodeEntry>,
+    history: Vec<EpisodeEntry>,
+    history_seq: i64,
+}
+
+struct Device {
+    username: String,
+    id: String,
+    caption: Option<String>,
+    r#type: DeviceType,
+}
+
+struct SubscriptionEntry {
+    username: String,
+    device: String,
+    sub: Subscription,
+}
+
+#[derive(Clone)]
+struct EpisodeEntry {
+    username: String,
+    episode: EpisodeRaw, // `modified` is always present
+    content_hash: String,
+    hash_version: i64,
+    // the order it reached the history in, 0 in `episodes`
+    id: i64,
+}
+
+impl MemoryStore {
+    fn lock(&self) -> Result<MutexGuard<'_, Inner>> {
+        self.0.lock().map_err(|e| {
+            error!("memory store poisoned: {e:?}");
+            Error::Internal
+        })
+    }
+}
+
+impl Inner {
+    fn append_history(&mut self, username: &str, changes: &[&EpisodeRaw], now: Timestamp) {
+        for change in changes {
+            self.history_seq += 1;
+            self.history.push(EpisodeEntry {
+                id: self.history_seq,
+                ..EpisodeEntry::new(username, change, now)
+            });
+        }
+    }
+
+    // returns the changes which were stale, and so not applied
+    fn upsert_episodes(
+        &mut self,
+        username: &str,
+        changes: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Vec<EpisodeRaw> {
+        let mut stale = vec![];
+
+        for change in changes {
+            let entry = EpisodeEntry::new(username, &change, now);
+            let content_hash = entry.content_hash.clone();
+
+            let current = self
+                .episodes
+                .iter_mut()
+                .find(|e| e.is(username, &change.podcast, &change.episode));
+
+            let current = match current {
+                Some(current) => current,
+                None => {
+                    self.episodes.push(entry);
+                    continue;
+                }
+            };
+
+            if is_stale(
+                current.episode.timestamp.as_ref(),
+                change.timestamp.as_ref(),
+            ) {
+                stale.push(change);
+                continue;
+            }
+
+            // only update if we've changed the contents
+            if current.content_hash == content_hash {
+                continue;
+            }
+
+            // the device an episode was first seen on is kept
+            let ep = &mut current.episode;
+            ep.timestamp = change.timestamp.or(ep.timestamp.take());
+            ep.guid = change.guid.or(ep.guid.take());
+            ep.action = change.action;
+            ep.started = change.started.or(ep.started);
+            ep.position = change.position.or(ep.position);
+            ep.total = change.total.or(ep.total);
+            ep.modified = Some(now);
+            current.content_hash = content_hash;
+            current.hash_version = HASH_VERSION;
+        }
+
+        stale
+    }
+}
+
+impl EpisodeEntry {
+    fn new(username: &str, change: &EpisodeRaw, now: Timestamp) -> Self {
+        Self {
+            username: username.into(),
+            episode: EpisodeRaw {
+                modified: Some(now),
+                ..change.clone()
+            },
+            content_hash: change.content_hash(),
+            hash_version: HASH_VERSION,
+            id: 0,
+        }
+    }
+
+    fn is(&self, username: &str, podcast: &str, episode: &str) -> bool {
+        self.username == username
+            && self.episode.podcast == podcast
+            && self.episode.episode == episode
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn user(&self, username: &str) -> Result<Option<User>> {
+        let inner = self.lock()?;
+
+        Ok(inner.users.iter().find(|u| u.username == username).cloned())
+    }
+
+    async fn users_with_session(&self, session_id: &str) -> Result<Vec<User>> {
+        let inner = self.lock()?;
+
+        Ok(inner
+            .users
+            .iter()
+            .filter(|u| u.session_id.as_deref() == Some(session_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn set_session(&self, username: &str, session_id: Option<&str>) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        for user in inner.users.iter_mut().filter(|u| u.username == username) {
+            user.session_id = session_id.map(Into::into);
+        }
+        Ok(())
+    }
+
+    async fn set_password(&self, username: &str, pwhash: &str) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        for user in inner.users.iter_mut().filter(|u| u.username == username) {
+            user.pwhash = pwhash.into();
+        }
+        Ok(())
+    }
+
+    async fn set_admin(&self, username: &str, admin: bool) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        for user in inner.users.iter_mut().filter(|u| u.username == username) {
+            user.admin = admin;
+        }
+        Ok(())
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        for user in inner.users.iter_mut().filter(|u| u.username == username) {
+            user.disabled = disabled;
+            if disabled {
+                user.session_id = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>> {
+        let inner = self.lock()?;
+
+        let mut users: Vec<_> = inner
+            .users
+            .iter()
+            .map(|user| {
+                let username = &user.username;
+                let count = |n: usize| n as i64;
+
+                UserSummary {
+                    username: username.clone(),
+                    admin: user.admin,
+                    disabled: user.disabled,
+                    devices: count(
+                        inner
+                            .devices
+                            .iter()
+                            .filter(|d| &d.username == username)
+                            .count(),
+                    ),
+                    subscriptions: count(
+                        inner
+                            .subscriptions
+                            .iter()
+                            .filter(|s| &s.username == username && s.sub.deleted.is_none())
+                            .count(),
+                    ),
+                    episodes: count(
+                        inner
+                            .episodes
+                            .iter()
+                            .filter(|e| &e.username == username)
+                            .count(),
+                    ),
+                }
+            })
+            .collect();
+
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    async fn register(
+        &self,
+        username: &str,
+        pwhash: &str,
+        invite: Option<&str>,
+        now: Timestamp,
+    ) -> Result<()> {
+        let mut inner = self.lock()?;
+        let inner = &mut *inner;
+
+        let invite = match invite {
+            Some(code) => {
+                let invite = inner
+                    .invites
+                    .iter_mut()
+                    .find(|invite| invite.code == code && invite.usable(now));
+                if invite.is_none() {
+                    error!("invite {code} is unknown, used up or expired");
+                    return Err(Error::Forbidden);
+                }
+                invite
+            }
+            None => None,
+        };
+        // checked before the invite's used, as there's no rolling back
+        let taken = inner.users.iter().any(|u| u.username == username);
+        if taken {
+            error!("can't register {username}, who already exists");
+            return Err(Error::Conflict);
+        }
+
+        if let Some(invite) = invite {
+            invite.used += 1;
+        }
+        inner.users.push(User {
+            username: username.into(),
+            pwhash: pwhash.into(),
+            session_id: None,
+            admin: false,
+            disabled: false,
+        });
+        Ok(())
+    }
+
+    async fn create_invite(&self, invite: &Invite) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        inner.invites.push(invite.clone());
+        Ok(())
+    }
+
+    async fn invites(&self) -> Result<Vec<Invite>> {
+        let inner = self.lock()?;
+
+        let mut invites = inner.invites.clone();
+        invites.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| a.code.cmp(&b.code)));
+        Ok(invites)
+    }
+
+    async fn delete_invite(&self, code: &str) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        inner.invites.retain(|invite| invite.code != code);
+        Ok(())
+    }
+
+    async fn create_user(&self, username: &str, pwhash: &str) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        if inner.users.iter().any(|u| u.username == username) {
+            error!("error creating user {username}: already exists");
+            return Err(Error::Internal);
+        }
+
+        inner.users.push(User {
+            username: username.into(),
+            pwhash: pwhash.into(),
+            session_id: None,
+            admin: false,
+            disabled: false,
+        });
+        Ok(())
+    }
+
+    async fn devices(&self, username: &str) -> Result<Vec<DeviceAndSub>> {
+        let inner = self.lock()?;
+
+        let mut devices: Vec<_> = inner
+            .devices
+            .iter()
+            .filter(|d| d.username == username)
+            .map(|d| DeviceAndSub {
+                id: d.id.clone(),
+                caption: d.caption.clone().unwrap_or_default(),
+                r#type: d.r#type,
+                subscriptions: inner
+                    .subscriptions
+                    .iter()
+                    .filter(|s| {
+                        s.username == username && s.device == d.id && s.sub.deleted.is_none()
+                    })
+                    .count() as u32,
+            })
+            .collect();
+
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(devices)
+    }
+
+    async fn update_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        update: DeviceUpdate,
+    ) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        let existing = inner
+            .devices
+            .iter_mut()
+            .find(|d| d.username == username && d.id == device_id);
+
+        match existing {
+            Some(dev) => {
+                if let Some(caption) = update.caption {
+                    dev.caption = Some(caption);
+                }
+                if let Some(r#type) = update.r#type {
+                    dev.r#type = r#type;
+                }
+            }
+            None => inner.devices.push(Device {
+                username: username.into(),
+                id: device_id.into(),
+                caption: update.caption,
+                r#type: update.r#type.unwrap_or_default(),
+            }),
+        }
+        Ok(())
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str, now: Timestamp) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        for s in inner
+            .subscriptions
+            .iter_mut()
+            .filter(|s| s.username == username && s.device == device_id && s.sub.deleted.is_none())
+        {
+            s.sub.deleted = Some(now);
+        }
+        inner
+            .devices
+            .retain(|d| !(d.username == username && d.id == device_id));
+        Ok(())
+    }
+
+    async fn subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Subscription>> {
+        let inner = self.lock()?;
+
+        Ok(inner
+            .subscriptions
+            .iter()
+            .filter(|s| s.username == username && s.device == device_id)
+            .map(|s| &s.sub)
+            .filter(|sub| sub.created > since || matches!(sub.deleted, Some(d) if d > since))
+            .cloned()
+            .collect())
+    }
+
+    async fn update_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        changes: &SubscriptionChangesFromClient,
+        now: Timestamp,
+    ) -> Result<()> {
+        let mut inner = self.lock()?;
+
+        for url in &changes.remove {
+            for s in inner.subscriptions.iter_mut().filter(|s| {
+                s.username == username
+                    && s.device == device_id
+                    && &s.sub.url == url
+                    && s.sub.deleted.is_none()
+            }) {
+                s.sub.deleted = Some(now);
+            }
+        }
+
+        for url in &changes.add {
+            let active = inner.subscriptions.iter().any(|s| {
+                s.username == username
+                    && s.device == device_id
+                    && &s.sub.url == url
+                    && s.sub.deleted.is_none()
+            });
+
+            if !active {
+                inner.subscriptions.push(SubscriptionEntry {
+                    username: username.into(),
+                    device: device_id.into(),
+                    sub: Subscription {
+                        url: url.clone(),
+                        created: now,
+                        deleted: None,
+                    },
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn all_subscriptions(&self, username: &str) -> Result<Vec<(String, Subscription)>> {
+        let inner = self.lock()?;
+
+        let mut subs: Vec<_> = inner
+            .subscriptions
+            .iter()
+            .filter(|s| s.username == username)
+            .map(|s| (s.device.clone(), s.sub.clone()))
+            .collect();
+        subs.sort_by(|(a_dev, a), (b_dev, b)| {
+            (a_dev, a.created, &a.url).cmp(&(b_dev, b.created, &b.url))
+        });
+
+        Ok(subs)
+    }
+
+    async fn import_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        subscriptions: &[Subscription],
+    ) -> Result<usize> {
+        let mut inner = self.lock()?;
+        let mut count = 0;
+
+        for sub in subscriptions {
+            let held = inner.subscriptions.iter().any(|s| {
+                s.username == username
+                    && s.device == device_id
+                    && s.sub.url == sub.url
+                    && s.sub.deleted == sub.deleted
+            });
+
+            if !held {
+                inner.subscriptions.push(SubscriptionEntry {
+                    username: username.into(),
+                    device: device_id.into(),
+                    sub: sub.clone(),
+                });
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn recent_episodes(&self, username: &str, count: usize) -> Result<Vec<EpisodeRaw>> {
+        let inner = self.lock()?;
+
+        let mut recent: Vec<_> = inner
+            .episodes
+            .iter()
+            .filter(|e| e.username == username)
+            .map(|e| &e.episode)
+            .collect();
+        recent.sort_by(|a, b| {
+            (b.modified, &b.podcast, &b.episode).cmp(&(a.modified, &a.podcast, &a.episode))
+        });
+
+        Ok(recent.into_iter().take(count).cloned().collect())
+    }
+
+    fn episode_stream(&self, username: &str, filter: &EpisodeFilter) -> EpisodeStream {
+        let inner = match self.lock() {
+            Ok(inner) => inner,
+            Err(e) => return stream::iter([Err(e)]).boxed(),
+        };
+
+        let source = if filter.aggregated {
+            &inner.episodes
+        } else {
+            &inner.history
+        };
+
+        let mut rows: Vec<_> = source
+            .iter()
+            .filter(|e| e.username == username)
+            .filter(|e| matches!(e.episode.modified, Some(m) if m > filter.since))
+            .filter(|e| {
+                filter
+                    .podcast
+                    .iter()
+                    .all(|podcast| &e.episode.podcast == podcast)
+            })
+            .filter(|e| {
+                filter
+                    .device
+                    .iter()
+                    .all(|device| e.episode.device.as_ref() == Some(device))
+            })
+            .map(|e| EpisodeRow {
+                episode: e.episode.clone(),
+                history_id: (!filter.aggregated).then_some(e.id),
+            })
+            .collect();
+
+        rows.sort_by_cached_key(EpisodeCursor::of);
+        if let Some(after) = &filter.after {
+            rows.retain(|row| EpisodeCursor::of(row).as_ref() > Some(after));
+        }
+        if let Some(limit) = filter.limit {
+            rows.truncate(limit);
+        }
+
+        stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+
+    async fn import_episodes(
+        &self,
+        username: &str,
+        history: &[EpisodeRaw],
+        latest: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>> {
+        let mut inner = self.lock()?;
+
+        let held: Vec<_> = inner
+            .history
+            .iter()
+            .filter(|e| e.username == username)
+            .map(|e| {
+                (
+                    e.episode.podcast.clone(),
+                    e.episode.episode.clone(),
+                    e.content_hash.clone(),
+                )
+            })
+            .collect();
+        inner.append_history(username, &unheld(history, held), now);
+        Ok(inner.upsert_episodes(username, latest, now))
+    }
+
+    async fn update_episodes(
+        &self,
+        username: &str,
+        changes: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>> {
+        let mut inner = self.lock()?;
+
+        // stale or not, the actions still happened, so go in the history
+        inner.append_history(username, &without_resends(&changes), now);
+        Ok(inner.upsert_episodes(username, changes, now))
+    }
+
+    async fn last_change(&self, username: &str) -> Result<Option<Timestamp>> {
+        let inner = self.lock()?;
+
+        let episodes = inner
+            .episodes
+            .iter()
+            .chain(&inner.history)
+            .filter(|e| e.username == username)
+            .filter_map(|e| e.episode.modified);
+        let subscriptions = inner
+            .subscriptions
+            .iter()
+            .filter(|s| s.username == username)
+            .flat_map(|s| [Some(s.sub.created), s.sub.deleted])
+            .flatten();
+
+        Ok(episodes.chain(subscriptions).max())
+    }
+
+    async fn rehash_episodes(&self) -> Result<usize> {
+        let mut inner = self.lock()?;
+        let inner = &mut *inner;
+        let mut count = 0;
+
+        for entry in inner
+            .episodes
+            .iter_mut()
+            .chain(inner.history.iter_mut())
+            .filter(|e| e.hash_version < HASH_VERSION)
+        {
+            entry.content_hash = entry.episode.content_hash();
+            entry.hash_version = HASH_VERSION;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    async fn backup(&self, _dest: &Path) -> Result<()> {
+        error!("an in-memory store can't be backed up");
+        Err(Error::BadRequest)
+    }
+
+    async fn diagnose(&self, fix: bool, now: Timestamp) -> Result<Diagnosis> {
+        let mut inner = self.lock()?;
+        let inner = &mut *inner;
+
+        let mut active = BTreeMap::<_, usize>::new();
+        for entry in inner
+            .subscriptions
+            .iter()
+            .filter(|e| e.sub.deleted.is_none())
+        {
+            *active
+                .entry((
+                    entry.username.clone(),
+                    entry.device.clone(),
+                    entry.sub.url.clone(),
+                ))
+                .or_default() += 1;
+        }
+
+        let unknown_devices: BTreeSet<_> = inner
+            .subscriptions
+            .iter()
+            .filter(|e| e.sub.deleted.is_none())
+            .filter(|e| {
+                !inner
+                    .devices
+                    .iter()
+                    .any(|d| d.username == e.username && d.id == e.device)
+            })
+            .map(|e| (e.username.clone(), e.device.clone()))
+            .collect();
+
+        let positionless = |e: &EpisodeEntry| {
+            matches!(e.episode.action, EpisodeActionRaw::Play) && e.episode.position.is_none()
+        };
+
+        let mut positionless_plays: Vec<_> = inner
+            .episodes
+            .iter()
+            .filter(|e| positionless(e))
+            .map(|e| {
+                (
+                    e.username.clone(),
+                    e.episode.podcast.clone(),
+                    e.episode.episode.clone(),
+                )
+            })
+            .collect();
+        positionless_plays.sort();
+
+        let diagnosis = Diagnosis {
+            duplicate_subscriptions: active
+                .into_iter()
+                .filter(|&(_, count)| count > 1)
+                .map(|(key, _)| key)
+                .collect(),
+            unknown_devices: unknown_devices.into_iter().collect(),
+            positionless_plays,
+            positionless_history: inner.history.iter().filter(|e| positionless(e)).count(),
+            unhashed_episodes: inner
+                .episodes
+                .iter()
+                .chain(inner.history.iter())
+                .filter(|e| e.content_hash.is_empty())
+                .count(),
+        };
+
+        if !fix {
+            return Ok(diagnosis);
+        }
+
+        let mut seen = HashSet::new();
+        inner.subscriptions.retain(|e| {
+            e.sub.deleted.is_some()
+                || seen.insert((e.username.clone(), e.device.clone(), e.sub.url.clone()))
+        });
+
+        for (username, device) in &diagnosis.unknown_devices {
+            inner.devices.push(Device {
+                username: username.clone(),
+                id: device.clone(),
+                caption: None,
+                r#type: DeviceType::default(),
+            });
+        }
+
+        inner.history.retain(|e| !positionless(e));
+        for (username, podcast, episode) in &diagnosis.positionless_plays {
+            inner.episodes.retain(|e| !e.is(username, podcast, episode));
+
+            // as `update_episodes` would have it, the action with the latest timestamp
+            let latest = inner
+                .history
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.is(username, podcast, episode))
+                .max_by_key(|(i, e)| (e.episode.timestamp.clone(), *i))
+                .map(|(_, e)| {
+                    let mut entry = EpisodeEntry { id: 0, ..e.clone() };
+                    entry.episode.modified = Some(now);
+                    entry
+                });
+
+            inner.episodes.extend(latest);
+        }
+
+        for entry in inner
+            .episodes
+            .iter_mut()
+            .chain(inner.history.iter_mut())
+            .filter(|e| e.content_hash.is_empty())
+        {
+            entry.content_hash = entry.episode.content_hash();
+            entry.hash_version = HASH_VERSION;
+        }
+
+        Ok(diagnosis)
+    }
+
+    async fn prune(
+        &self,
+        deleted_before: Option<Timestamp>,
+        unsubscribed_before: Option<Timestamp>,
+    ) -> Result<Pruned> {
+        let mut inner = self.lock()?;
+        let inner = &mut *inner;
+        let mut pruned = Pruned::default();
+
+        if let Some(deleted_before) = deleted_before {
+            let subscriptions = &inner.subscriptions;
+            let superseded: Vec<bool> = subscriptions
+                .iter()
+                .map(|old| {
+                    matches!(old.sub.deleted, Some(deleted) if deleted < deleted_before)
+                        && subscriptions.iter().any(|newer| {
+                            newer.username == old.username
+                                && newer.device == old.device
+                                && newer.sub.url == old.sub.url
+                                && match newer.sub.deleted {
+                                    None => true,
+                                    Some(deleted) => Some(deleted) > old.sub.deleted,
+                                }
+                        })
+                })
+                .collect();
+
+            let before = inner.subscriptions.len();
+            let mut superseded = superseded.into_iter();
+            inner
+                .subscriptions
+                .retain(|_| !superseded.next().expect("one per subscription"));
+            pruned.subscriptions = before - inner.subscriptions.len();
+        }
+
+        if let Some(unsubscribed_before) = unsubscribed_before {
+            let subscriptions = &inner.subscriptions;
+            let prunable = |e: &EpisodeEntry| {
+                matches!(e.episode.modified, Some(m) if m < unsubscribed_before)
+                    && !subscriptions.iter().any(|s| {
+                        s.username == e.username
+                            && s.sub.url == e.episode.podcast
+                            && s.sub.deleted.is_none()
+                    })
+            };
+
+            let (episodes, history) = (inner.episodes.len(), inner.history.len());
+            inner.episodes.retain(|e| !prunable(e));
+            inner.history.retain(|e| !prunable(e));
+
+            pruned.episodes = episodes - inner.episodes.len();
+            pruned.history = history - inner.history.len();
+        }
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl super::Fixtures for MemoryStore {
+    async fn insert_episode(&self, username: &str, episode: EpisodeRaw, meta: super::EpisodeMeta) {
+        self.lock().unwrap().episodes.push(EpisodeEntry {
+            username: username.into(),
+            episode: EpisodeRaw {
+                modified: Some(meta.modified),
+                ..episode
+            },
+            content_hash: meta.content_hash,
+            hash_version: meta.hash_version,
+            id: 0,
+        });
+    }
+
+    async fn episode_meta(&self, username: &str) -> Vec<super::EpisodeMeta> {
+        self.lock()
+            .unwrap()
+            .episodes
+            .iter()
+            .filter(|e| e.username == username)
+            .map(|e| super::EpisodeMeta {
+                modified: e.episode.modified.unwrap(),
+                content_hash: e.content_hash.clone(),
+                hash_version: e.hash_version,
+            })
+            .collect()
+    }
+
+    async fn set_episode_meta(&self, username: &str, meta: super::EpisodeMeta) {
+        for e in self
+            .lock()
+            .unwrap()
+            .episodes
+            .iter_mut()
+            .filter(|e| e.username == username)
+        {
+            e.episode.modified = Some(meta.modified);
+            e.content_hash = meta.content_hash.clone();
+            e.hash_version = meta.hash_version;
+        }
+    }
+
+    async fn insert_subscription(&self, username: &str, device: &str, sub: Subscription) {
+        self.lock().unwrap().subscriptions.push(SubscriptionEntry {
+            username: username.into(),
+            device: device.into(),
+            sub,
+        });
+    }
+}
@@ -0,0 +1,399 @@
+use std::{collections::HashMap, fmt, future::Future, path::Path, result};
+
+use async_trait::async_trait;
+use futures_util::{
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::device::{DeviceAndSub, DeviceUpdate};
+use crate::episode::{EpisodeRaw, Time};
+use crate::podsync::{Error, Result};
+use crate::subscription::{Subscription, SubscriptionChangesFromClient};
+use crate::time::{self, Timestamp};
+use crate::user::{Invite, User};
+
+mod sqlite;
+pub use sqlite::SqliteStore;
+
+mod memory;
+pub use memory::MemoryStore;
+
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStore;
+
+#[cfg(test)]
+mod bench;
+
+// Where podsync keeps its state. Each method is atomic - implementations
+// handle their own transactions.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn user(&self, username: &str) -> Result<Option<User>>;
+    async fn users_with_session(&self, session_id: &str) -> Result<Vec<User>>;
+    async fn set_session(&self, username: &str, session_id: Option<&str>) -> Result<()>;
+    async fn create_user(&self, username: &str, pwhash: &str) -> Result<()>;
+    async fn set_password(&self, username: &str, pwhash: &str) -> Result<()>;
+    async fn set_admin(&self, username: &str, admin: bool) -> Result<()>;
+    // disabling a user also ends their session
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<()>;
+    // every user, by name, with how much they've synced
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>>;
+    // Creates a user, using up one of `invite`'s uses if there is one, all or nothing.
+    // An unknown, used up or expired invite is `Forbidden`, a taken username `Conflict`.
+    async fn register(
+        &self,
+        username: &str,
+        pwhash: &str,
+        invite: Option<&str>,
+        now: Timestamp,
+    ) -> Result<()>;
+
+    async fn create_invite(&self, invite: &Invite) -> Result<()>;
+    // newest first
+    async fn invites(&self) -> Result<Vec<Invite>>;
+    async fn delete_invite(&self, code: &str) -> Result<()>;
+
+    async fn devices(&self, username: &str) -> Result<Vec<DeviceAndSub>>;
+    async fn update_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        update: DeviceUpdate,
+    ) -> Result<()>;
+    // the device, with its active subscriptions deleted at `now`, so their removal still
+    // reaches clients syncing them
+    async fn delete_device(&self, username: &str, device_id: &str, now: Timestamp) -> Result<()>;
+
+    // subscriptions created or deleted after `since`
+    async fn subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Subscription>>;
+    async fn update_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        changes: &SubscriptionChangesFromClient,
+        now: Timestamp,
+    ) -> Result<()>;
+    // every subscription the user has had, on any device, with the device it's on
+    async fn all_subscriptions(&self, username: &str) -> Result<Vec<(String, Subscription)>>;
+    // insert subscriptions as-is (keeping their timestamps), skipping those we already hold,
+    // returning how many were new
+    async fn import_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        subscriptions: &[Subscription],
+    ) -> Result<usize>;
+
+    // Episode actions in the order pages are cut from them, read as the stream's polled,
+    // for responses too large to gather up first
+    fn episode_stream(&self, username: &str, filter: &EpisodeFilter) -> EpisodeStream;
+    async fn episodes(&self, username: &str, filter: &EpisodeFilter) -> Result<Vec<EpisodeRaw>> {
+        self.episode_stream(username, filter)
+            .map_ok(|row| row.episode)
+            .try_collect()
+            .await
+    }
+    // the latest action of the `count` episodes most recently changed, newest first
+    async fn recent_episodes(&self, username: &str, count: usize) -> Result<Vec<EpisodeRaw>>;
+    // An imported `history` goes into the history, less as many of each action as it
+    // already holds, so importing it twice adds nothing. Of it, only `latest` is applied,
+    // as `update_episodes` would, returning what was stale.
+    async fn import_episodes(
+        &self,
+        username: &str,
+        history: &[EpisodeRaw],
+        latest: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>>;
+    // returns the changes which were older than what we hold, and so not applied
+    async fn update_episodes(
+        &self,
+        username: &str,
+        changes: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>>;
+    // when the user's subscriptions or episode actions last changed, if they've any
+    async fn last_change(&self, username: &str) -> Result<Option<Timestamp>>;
+    // rehash any episodes (and history) stored under an older `HASH_VERSION`, returning how many
+    async fn rehash_episodes(&self) -> Result<usize>;
+
+    // a consistent copy of everything, safe to take while serving, to a file which mustn't exist
+    async fn backup(&self, dest: &Path) -> Result<()>;
+
+    // Looks for oddities left by older podsyncs, repairing them in the same transaction if
+    // `fix`. Anything repaired is `modified` at `now`, so clients fetch it again.
+    async fn diagnose(&self, fix: bool, now: Timestamp) -> Result<Diagnosis>;
+
+    // Drops subscriptions deleted before `deleted_before`, except for each device's latest
+    // removal of a podcast it's no longer subscribed to. That's kept as a tombstone, so
+    // clients syncing from before it still learn of it.
+    // Drops episode actions modified before `unsubscribed_before`, of podcasts the user
+    // isn't subscribed to on any device.
+    async fn prune(
+        &self,
+        deleted_before: Option<Timestamp>,
+        unsubscribed_before: Option<Timestamp>,
+    ) -> Result<Pruned>;
+}
+
+#[derive(Debug, Clone)]
+pub struct EpisodeFilter {
+    pub since: Timestamp,
+    pub podcast: Option<String>,
+    pub device: Option<String>,
+    // latest state per episode, otherwise every action from the history
+    pub aggregated: bool,
+    // a page: the actions after where the last ended, and at most `limit` of them
+    pub after: Option<EpisodeCursor>,
+    pub limit: Option<usize>,
+}
+
+// Where a page of episode actions ended. They're ordered by `modified`, with ties
+// broken by the episode for the aggregated actions, or by arrival for the history.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EpisodeCursor {
+    Aggregated {
+        #[serde(with = "time::millis")]
+        modified: Timestamp,
+        podcast: String,
+        episode: String,
+    },
+    History {
+        #[serde(with = "time::millis")]
+        modified: Timestamp,
+        id: i64,
+    },
+}
+
+impl EpisodeCursor {
+    pub fn of(row: &EpisodeRow) -> Option<Self> {
+        let modified = row.episode.modified?;
+
+        Some(match row.history_id {
+            Some(id) => Self::History { modified, id },
+            None => Self::Aggregated {
+                modified,
+                podcast: row.episode.podcast.clone(),
+                episode: row.episode.episode.clone(),
+            },
+        })
+    }
+}
+
+pub struct EpisodeRow {
+    pub episode: EpisodeRaw,
+    // its place in `episode_history`, for paging through it
+    pub history_id: Option<i64>,
+}
+
+pub type EpisodeStream = BoxStream<'static, Result<EpisodeRow>>;
+
+// Streams what `send` sends from a task of its own, so what it reads from can borrow
+// from what it owns
+pub fn spawn_stream<F, Fut>(send: F) -> EpisodeStream
+where
+    F: FnOnce(mpsc::Sender<Result<EpisodeRow>>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(send(tx));
+
+    stream::unfold(
+        rx,
+        |mut rx| async move { rx.recv().await.map(|row| (row, rx)) },
+    )
+    .boxed()
+}
+
+// Sends `rows` on, until they run out or fail, or nobody's listening
+pub async fn forward<S, E>(rows: S, tx: mpsc::Sender<Result<EpisodeRow>>)
+where
+    S: Stream<Item = result::Result<EpisodeRow, E>>,
+    E: fmt::Debug,
+{
+    futures_util::pin_mut!(rows);
+
+    while let Some(row) = rows.next().await {
+        let row = row.map_err(|e| {
+            error!("error selecting episodes: {e:?}");
+            Error::Internal
+        });
+        let failed = row.is_err();
+
+        if tx.send(row).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserSummary {
+    pub username: String,
+    pub admin: bool,
+    pub disabled: bool,
+    pub devices: i64,
+    // active ones, on any device
+    pub subscriptions: i64,
+    pub episodes: i64,
+}
+
+// What `Store::diagnose` found
+#[derive(Debug, Default)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct Diagnosis {
+    // an active subscription held more than once for a device: (username, device, url).
+    // We keep the oldest.
+    pub duplicate_subscriptions: Vec<(String, String, String)>,
+    // devices with active subscriptions but no entry in `devices`: (username, device).
+    // We add them, as `other` devices.
+    pub unknown_devices: Vec<(String, String)>,
+    // "play"s without a position, which fail the whole of their user's `episodes()`:
+    // (username, podcast, episode). We drop them from the history, and fall back to
+    // the latest valid action for each of these episodes.
+    pub positionless_plays: Vec<(String, String, String)>,
+    pub positionless_history: usize,
+    // episode actions (latest and history) still with the migration's `content_hash` of "".
+    // We hash them.
+    pub unhashed_episodes: usize,
+}
+
+impl Diagnosis {
+    pub fn is_healthy(&self) -> bool {
+        self.duplicate_subscriptions.is_empty()
+            && self.unknown_devices.is_empty()
+            && self.positionless_plays.is_empty()
+            && self.positionless_history == 0
+            && self.unhashed_episodes == 0
+    }
+}
+
+// What `Store::prune` removed
+#[derive(Debug, Default)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct Pruned {
+    pub subscriptions: usize,
+    pub episodes: usize,
+    pub history: usize,
+}
+
+// last-writer-wins is decided by the client's timestamp for the action,
+// not by when it reached us - a client may have been offline for a while
+pub fn is_stale(current: Option<&Time>, incoming: Option<&Time>) -> bool {
+    match (current, incoming) {
+        (Some(current), Some(incoming)) => incoming < current,
+        _ => false,
+    }
+}
+
+// Splits an upload into the changes which are stale against what's held (`current`
+// timestamps, by podcast and episode) and rounds of the rest, to upsert in turn. A
+// statement can't upsert one row twice, so each round has an episode at most once,
+// with later changes to it judged against earlier ones, as if applied one by one.
+pub fn upsert_rounds(
+    changes: Vec<EpisodeRaw>,
+    mut current: HashMap<(String, String), Time>,
+) -> (Vec<Vec<EpisodeRaw>>, Vec<EpisodeRaw>) {
+    let mut rounds: Vec<Vec<EpisodeRaw>> = vec![];
+    let mut upserted = HashMap::new();
+    let mut stale = vec![];
+
+    for change in changes {
+        let key = (change.podcast.clone(), change.episode.clone());
+
+        if is_stale(current.get(&key), change.timestamp.as_ref()) {
+            stale.push(change);
+            continue;
+        }
+
+        if let Some(timestamp) = &change.timestamp {
+            current.insert(key.clone(), timestamp.clone());
+        }
+
+        let round = upserted.entry(key).or_insert(0);
+        if *round == rounds.len() {
+            rounds.push(vec![]);
+        }
+        rounds[*round].push(change);
+        *round += 1;
+    }
+
+    (rounds, stale)
+}
+
+// An upload's actions for the history, less those resent: the same as the action
+// before it for its episode, in the same upload. Repeats in later uploads are kept, as
+// they happened again.
+pub fn without_resends(changes: &[EpisodeRaw]) -> Vec<&EpisodeRaw> {
+    let mut last = HashMap::new();
+
+    changes
+        .iter()
+        .filter(|change| {
+            let key = (change.podcast.as_str(), change.episode.as_str());
+            let hash = change.content_hash();
+            last.insert(key, hash.clone()) != Some(hash)
+        })
+        .collect()
+}
+
+// The actions of an imported history beyond those the history already `held`, as
+// (podcast, episode, content hash), counting an action held twice as matching twice
+pub fn unheld(
+    history: &[EpisodeRaw],
+    held: impl IntoIterator<Item = (String, String, String)>,
+) -> Vec<&EpisodeRaw> {
+    let mut held_counts = HashMap::<_, usize>::new();
+    for key in held {
+        *held_counts.entry(key).or_default() += 1;
+    }
+
+    history
+        .iter()
+        .filter(|change| {
+            let key = (
+                change.podcast.clone(),
+                change.episode.clone(),
+                change.content_hash(),
+            );
+            match held_counts.get_mut(&key) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            }
+        })
+        .collect()
+}
+
+// Direct access to what a store holds, for tests to set up and inspect
+// state that can't be reached through `Store`.
+#[cfg(test)]
+#[async_trait]
+pub trait Fixtures: Send + Sync {
+    async fn insert_episode(&self, username: &str, episode: EpisodeRaw, meta: EpisodeMeta);
+    async fn episode_meta(&self, username: &str) -> Vec<EpisodeMeta>;
+    async fn set_episode_meta(&self, username: &str, meta: EpisodeMeta);
+    // as is, without checking for an active duplicate
+    async fn insert_subscription(&self, username: &str, device: &str, sub: Subscription);
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpisodeMeta {
+    pub modified: Timestamp,
+    pub content_hash: String,
+    pub hash_version: i64,
+}
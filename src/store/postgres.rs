@@ -0,0 +1,1502 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    future::Future,
+    path::Path,
+};
+
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use log::error;
+use sqlx::{
+    postgres::PgRow, query, query_as, query_builder::Separated, query_scalar, FromRow, Pool,
+    Postgres, QueryBuilder, Row, Transaction,
+};
+
+use super::{
+    forward, spawn_stream, unheld, upsert_rounds, without_resends, Diagnosis, EpisodeCursor,
+    EpisodeFilter, EpisodeRow, EpisodeStream, Pruned, Store, UserSummary,
+};
+use crate::device::{DeviceAndSub, DeviceType, DeviceUpdate};
+use crate::episode::{EpisodeRaw, Time, HASH_VERSION};
+use crate::podsync::{Error, Result};
+use crate::subscription::{Subscription, SubscriptionChangesFromClient};
+use crate::time::Timestamp;
+use crate::user::{Invite, User};
+
+// sqlx-data.json only describes one database, so unlike `SqliteStore`
+// the queries here aren't checked at compile time.
+pub struct PostgresStore(Pool<Postgres>);
+
+// a statement's binds are numbered with a u16
+const MAX_BINDS: usize = 65535;
+
+// writes `bind_episode`'s rows into `{table}`, either `episodes` or `episode_history`
+const EPISODE_INSERT: &str = "
+    INSERT INTO {table}
+    (
+        username, device,
+        podcast, episode,
+        timestamp, guid,
+        action,
+        started, position, total,
+        modified, content_hash, hash_version
+    )
+    ";
+const EPISODE_BINDS: usize = 13;
+
+fn bind_episode<'q>(
+    mut row: Separated<'_, 'q, Postgres, &'static str>,
+    username: &'q str,
+    change: &'q EpisodeRaw,
+    now: Timestamp,
+) {
+    row.push_bind(username)
+        .push_bind(&change.device)
+        .push_bind(&change.podcast)
+        .push_bind(&change.episode)
+        .push_bind(&change.timestamp)
+        .push_bind(&change.guid)
+        .push_bind(&change.action)
+        .push_bind(change.started)
+        .push_bind(change.position)
+        .push_bind(change.total)
+        .push_bind(now)
+        .push_bind(change.content_hash())
+        .push_bind(HASH_VERSION);
+}
+
+impl PostgresStore {
+    pub fn new(db: Pool<Postgres>) -> Self {
+        Self(db)
+    }
+
+    async fn transact<'t, T, R, F>(&self, transaction: T) -> Result<R>
+    where
+        T: FnOnce(Transaction<'t, Postgres>) -> F,
+        F: Future<Output = Result<(Transaction<'t, Postgres>, R)>>,
+    {
+        let tx = self.0.begin().await.map_err(|e| {
+            error!("error beginning transaction: {:?}", e);
+            Error::Internal
+        })?;
+
+        let (tx, r) = transaction(tx).await?;
+
+        tx.commit().await.map_err(|e| {
+            error!("error committing transaction: {:?}", e);
+            Error::Internal
+        })?;
+
+        Ok(r)
+    }
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    async fn user(&self, username: &str) -> Result<Option<User>> {
+        query_as(
+            "
+            SELECT *
+            FROM users
+            WHERE username = $1
+            ",
+        )
+        .bind(username)
+        .fetch_optional(&self.0)
+        .await
+        .map_err(|e| {
+            error!("couldn't query for user {}: {e:?}", username);
+            Error::Internal
+        })
+    }
+
+    async fn users_with_session(&self, session_id: &str) -> Result<Vec<User>> {
+        query_as(
+            "
+            SELECT *
+            FROM users
+            WHERE session_id = $1
+            ",
+        )
+        .bind(session_id)
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("couldn't query for session {session_id}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_session(&self, username: &str, session_id: Option<&str>) -> Result<()> {
+        query(
+            "
+            UPDATE users
+            SET session_id = $1
+            WHERE username = $2
+            ",
+        )
+        .bind(session_id)
+        .bind(username)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating session_id for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_password(&self, username: &str, pwhash: &str) -> Result<()> {
+        query(
+            "
+            UPDATE users
+            SET pwhash = $1
+            WHERE username = $2
+            ",
+        )
+        .bind(pwhash)
+        .bind(username)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating password for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_admin(&self, username: &str, admin: bool) -> Result<()> {
+        query(
+            "
+            UPDATE users
+            SET admin = $1
+            WHERE username = $2
+            ",
+        )
+        .bind(admin)
+        .bind(username)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating admin for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<()> {
+        query(
+            "
+            UPDATE users
+            SET disabled = $1,
+                session_id = CASE WHEN $1 THEN NULL ELSE session_id END
+            WHERE username = $2
+            ",
+        )
+        .bind(disabled)
+        .bind(username)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating disabled for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>> {
+        query_as(
+            "
+            SELECT username, admin, disabled,
+                (
+                    SELECT COUNT(*) FROM devices
+                    WHERE devices.username = users.username
+                ) AS devices,
+                (
+                    SELECT COUNT(*) FROM subscriptions
+                    WHERE subscriptions.username = users.username AND deleted IS NULL
+                ) AS subscriptions,
+                (
+                    SELECT COUNT(*) FROM episodes
+                    WHERE episodes.username = users.username
+                ) AS episodes
+            FROM users
+            ORDER BY username
+            ",
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error summarising users: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn register(
+        &self,
+        username: &str,
+        pwhash: &str,
+        invite: Option<&str>,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.transact(|mut tx| async move {
+            if let Some(code) = invite {
+                let used = query(
+                    "
+                    UPDATE invites
+                    SET used = used + 1
+                    WHERE code = $1
+                    AND used < max_uses
+                    AND (expires IS NULL OR expires > $2)
+                    ",
+                )
+                .bind(code)
+                .bind(now)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| {
+                    error!("error using invite {code}: {e:?}");
+                    Error::Internal
+                })?;
+
+                if used.rows_affected() == 0 {
+                    error!("invite {code} is unknown, used up or expired");
+                    return Err(Error::Forbidden);
+                }
+            }
+
+            let created = query(
+                "
+                INSERT INTO users (username, pwhash)
+                VALUES ($1, $2)
+                ON CONFLICT (username) DO NOTHING
+                ",
+            )
+            .bind(username)
+            .bind(pwhash)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error registering user {username}: {e:?}");
+                Error::Internal
+            })?;
+
+            if created.rows_affected() == 0 {
+                error!("can't register {username}, who already exists");
+                return Err(Error::Conflict);
+            }
+
+            Ok((tx, ()))
+        })
+        .await
+    }
+
+    async fn create_invite(&self, invite: &Invite) -> Result<()> {
+        query(
+            "
+            INSERT INTO invites (code, created_by, created, expires, max_uses, used)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ",
+        )
+        .bind(&invite.code)
+        .bind(&invite.created_by)
+        .bind(invite.created)
+        .bind(invite.expires)
+        .bind(invite.max_uses)
+        .bind(invite.used)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error creating invite: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn invites(&self) -> Result<Vec<Invite>> {
+        query_as(
+            "
+            SELECT code, created_by, created, expires, max_uses, used
+            FROM invites
+            ORDER BY created DESC, code
+            ",
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting invites: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn delete_invite(&self, code: &str) -> Result<()> {
+        query(
+            "
+            DELETE FROM invites
+            WHERE code = $1
+            ",
+        )
+        .bind(code)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error deleting invite {code}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn create_user(&self, username: &str, pwhash: &str) -> Result<()> {
+        query(
+            "
+            INSERT INTO users (username, pwhash)
+            VALUES ($1, $2)
+            ",
+        )
+        .bind(username)
+        .bind(pwhash)
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error creating user {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn devices(&self, username: &str) -> Result<Vec<DeviceAndSub>> {
+        let devices: Vec<(String, String, String, i64)> = query_as(
+            "
+            SELECT id,
+                coalesce(caption, ''),
+                type,
+                COUNT(subscriptions.url)
+            FROM devices
+            LEFT JOIN subscriptions
+                ON devices.username = subscriptions.username
+                AND devices.id = subscriptions.device
+                AND subscriptions.deleted IS NULL
+            WHERE devices.username = $1
+            GROUP BY devices.id, devices.caption, devices.type
+            ORDER BY devices.id
+            ",
+        )
+        .bind(username)
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting devices: {:?}", e);
+            Error::Internal
+        })?;
+
+        // `DeviceType`'s derive would look for a postgres enum, we store text
+        devices
+            .into_iter()
+            .map(|(id, caption, r#type, subscriptions)| {
+                let r#type = DeviceType::try_from(&*r#type).map_err(|()| {
+                    error!("invalid device type {type:?} for device {id}");
+                    Error::Internal
+                })?;
+
+                Ok(DeviceAndSub {
+                    id,
+                    caption,
+                    r#type,
+                    subscriptions: subscriptions as _,
+                })
+            })
+            .collect()
+    }
+
+    async fn update_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        update: DeviceUpdate,
+    ) -> Result<()> {
+        let caption: Option<_> = update.caption;
+        let type_default = update.r#type.unwrap_or_default().as_str();
+        let r#type: Option<_> = update.r#type.map(|t| t.as_str());
+
+        let result = query(
+            "
+            INSERT INTO devices
+            (id, username, caption, type)
+            VALUES
+            ($1, $2, $3, $4)
+            ON CONFLICT (id, username)
+            DO
+                UPDATE SET
+                    caption = coalesce($3, devices.caption),
+                    type = coalesce($5, devices.type)
+            ",
+        )
+        .bind(device_id)
+        .bind(username)
+        .bind(caption)
+        .bind(type_default)
+        .bind(r#type)
+        .execute(&self.0)
+        .await;
+
+        match result {
+            Ok(_result) => Ok(()),
+            Err(e) => {
+                error!("error inserting device: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str, now: Timestamp) -> Result<()> {
+        self.transact(|mut tx| async move {
+            query(
+                "
+                UPDATE subscriptions
+                SET deleted = $1
+                WHERE username = $2 AND device = $3 AND deleted IS NULL
+                ",
+            )
+            .bind(now)
+            .bind(username)
+            .bind(device_id)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error deleting (updating) subscriptions of device {device_id}: {e:?}");
+                Error::Internal
+            })?;
+
+            query(
+                "
+                DELETE FROM devices
+                WHERE username = $1 AND id = $2
+                ",
+            )
+            .bind(username)
+            .bind(device_id)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error deleting device {device_id}: {e:?}");
+                Error::Internal
+            })?;
+
+            Ok((tx, ()))
+        })
+        .await
+    }
+
+    async fn subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Subscription>> {
+        let subscriptions: Vec<(String, Timestamp, Option<Timestamp>)> = query_as(
+            "
+            SELECT url, created, deleted
+            FROM subscriptions
+            WHERE username = $1
+                AND device = $2
+                AND created > $3
+            UNION ALL
+            -- rather than `created > $3 OR deleted > $3`, which can't use a single index
+            SELECT url, created, deleted
+            FROM subscriptions
+            WHERE username = $1
+                AND device = $2
+                AND deleted > $3
+                AND created <= $3
+            ",
+        )
+        .bind(username)
+        .bind(device_id)
+        .bind(since)
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting subscriptions: {e:?}");
+            Error::Internal
+        })?;
+
+        Ok(subscriptions
+            .into_iter()
+            .map(|(url, created, deleted)| Subscription {
+                url,
+                created,
+                deleted,
+            })
+            .collect())
+    }
+
+    async fn update_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        changes: &SubscriptionChangesFromClient,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.transact(|mut tx| async {
+            for urls in changes.remove.chunks(MAX_BINDS - 3) {
+                let mut update = QueryBuilder::new("UPDATE subscriptions SET deleted = ");
+                update
+                    .push_bind(now)
+                    .push(" WHERE username = ")
+                    .push_bind(username)
+                    .push(" AND device = ")
+                    .push_bind(device_id)
+                    .push(" AND deleted IS NULL AND url IN (");
+                let mut list = update.separated(", ");
+                for url in urls {
+                    list.push_bind(url);
+                }
+                update.push(")");
+
+                update.build().execute(&mut *tx).await.map_err(|e| {
+                    error!("error deleting (updating) subscriptions: {e:?}");
+                    Error::Internal
+                })?;
+            }
+
+            // as with sqlite, one statement doesn't see its own inserts
+            let mut seen = HashSet::new();
+            let add: Vec<_> = changes.add.iter().filter(|url| seen.insert(*url)).collect();
+
+            for urls in add.chunks(MAX_BINDS - 5) {
+                // as with sqlite, NULL `deleted`s never conflict
+                let mut insert = QueryBuilder::new(
+                    "INSERT INTO subscriptions (username, device, url, created) SELECT ",
+                );
+                insert
+                    .push_bind(username)
+                    .push(", ")
+                    .push_bind(device_id)
+                    .push(", new.column1, ")
+                    .push_bind(now)
+                    .push(" FROM (");
+                insert.push_values(urls, |mut row, url| {
+                    row.push_bind(url);
+                });
+                insert
+                    .push(
+                        ") AS new WHERE NOT EXISTS (\
+                            SELECT 1 FROM subscriptions WHERE username = ",
+                    )
+                    .push_bind(username)
+                    .push(" AND device = ")
+                    .push_bind(device_id)
+                    .push(" AND url = new.column1 AND deleted IS NULL)");
+
+                insert.build().execute(&mut *tx).await.map_err(|e| {
+                    error!("error inserting subscriptions: {e:?}");
+                    Error::Internal
+                })?;
+            }
+
+            Ok((tx, ()))
+        })
+        .await
+    }
+
+    async fn all_subscriptions(&self, username: &str) -> Result<Vec<(String, Subscription)>> {
+        let subscriptions: Vec<(String, String, Timestamp, Option<Timestamp>)> = query_as(
+            "
+            SELECT device, url, created, deleted
+            FROM subscriptions
+            WHERE username = $1
+            ORDER BY device, created, url
+            ",
+        )
+        .bind(username)
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting subscriptions: {e:?}");
+            Error::Internal
+        })?;
+
+        Ok(subscriptions
+            .into_iter()
+            .map(|(device, url, created, deleted)| {
+                let sub = Subscription {
+                    url,
+                    created,
+                    deleted,
+                };
+                (device, sub)
+            })
+            .collect())
+    }
+
+    async fn import_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        subscriptions: &[Subscription],
+    ) -> Result<usize> {
+        self.transact(|mut tx| async {
+            let mut count = 0;
+
+            for sub in subscriptions {
+                let result = query(
+                    "
+                    INSERT INTO subscriptions
+                    (username, device, url, created, deleted)
+                    SELECT $1, $2, $3, $4, $5
+                    WHERE NOT EXISTS (
+                        SELECT 1
+                        FROM subscriptions
+                        WHERE username = $1
+                            AND device = $2
+                            AND url = $3
+                            AND deleted IS NOT DISTINCT FROM $5
+                    )
+                    ",
+                )
+                .bind(username)
+                .bind(device_id)
+                .bind(&sub.url)
+                .bind(sub.created)
+                .bind(sub.deleted)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| {
+                    error!("error importing subscription: {e:?}");
+                    Error::Internal
+                })?;
+
+                count += result.rows_affected() as usize;
+            }
+
+            Ok((tx, count))
+        })
+        .await
+    }
+
+    async fn recent_episodes(&self, username: &str, count: usize) -> Result<Vec<EpisodeRaw>> {
+        query_as(
+            "
+            SELECT podcast, episode,
+                guid, device,
+                timestamp,
+                action,
+                started, position, total,
+                modified
+            FROM episodes
+            WHERE username = $1
+            ORDER BY modified DESC, podcast DESC, episode DESC
+            LIMIT $2
+            ",
+        )
+        .bind(username)
+        .bind(count as i64)
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting {username}'s recent episodes: {e:?}");
+            Error::Internal
+        })
+    }
+
+    fn episode_stream(&self, username: &str, filter: &EpisodeFilter) -> EpisodeStream {
+        let db = self.0.clone();
+        let username = username.to_string();
+        let filter = filter.clone();
+
+        spawn_stream(|tx| async move {
+            let EpisodeFilter {
+                since,
+                podcast: podcast_filter,
+                device: device_filter,
+                aggregated,
+                after,
+                limit,
+            } = filter;
+            // LIMIT NULL is none at all
+            let limit = limit.map(|limit| i64::try_from(limit).unwrap_or(i64::MAX));
+
+            if aggregated {
+                let (after_modified, after_podcast, after_episode) = match after {
+                    Some(EpisodeCursor::Aggregated {
+                        modified,
+                        podcast,
+                        episode,
+                    }) => (Some(modified), Some(podcast), Some(episode)),
+                    _ => (None, None, None),
+                };
+
+                let rows = query_as(
+                    "
+                    SELECT podcast, episode,
+                        guid, device,
+                        timestamp,
+                        action,
+                        started, position, total,
+                        modified
+                    FROM episodes
+                    WHERE username = $1
+                        AND modified > $2
+                        AND ($3::TEXT IS NULL OR podcast = $3)
+                        AND ($4::TEXT IS NULL OR device = $4)
+                        AND ($5::BIGINT IS NULL
+                            OR (modified, podcast, episode) > ($5, $6::TEXT, $7::TEXT))
+                    ORDER BY modified, podcast, episode
+                    LIMIT $8
+                    ",
+                )
+                .bind(&username)
+                .bind(since)
+                .bind(&podcast_filter)
+                .bind(&device_filter)
+                .bind(after_modified)
+                .bind(&after_podcast)
+                .bind(&after_episode)
+                .bind(limit)
+                .fetch(&db)
+                .map_ok(|episode| EpisodeRow {
+                    episode,
+                    history_id: None,
+                });
+
+                forward(rows, tx).await;
+            } else {
+                let (after_modified, after_id) = match after {
+                    Some(EpisodeCursor::History { modified, id }) => (Some(modified), Some(id)),
+                    _ => (None, None),
+                };
+
+                let rows = query(
+                    "
+                    SELECT id, podcast, episode,
+                        guid, device,
+                        timestamp,
+                        action,
+                        started, position, total,
+                        modified
+                    FROM episode_history
+                    WHERE username = $1
+                        AND modified > $2
+                        AND ($3::TEXT IS NULL OR podcast = $3)
+                        AND ($4::TEXT IS NULL OR device = $4)
+                        AND ($5::BIGINT IS NULL OR (modified, id) > ($5, $6::BIGINT))
+                    ORDER BY modified, id
+                    LIMIT $7
+                    ",
+                )
+                .bind(&username)
+                .bind(since)
+                .bind(&podcast_filter)
+                .bind(&device_filter)
+                .bind(after_modified)
+                .bind(after_id)
+                .bind(limit)
+                .fetch(&db)
+                .and_then(|row| async move {
+                    Ok(EpisodeRow {
+                        episode: EpisodeRaw::from_row(&row)?,
+                        history_id: Some(row.try_get("id")?),
+                    })
+                });
+
+                forward(rows, tx).await;
+            }
+        })
+    }
+
+    async fn import_episodes(
+        &self,
+        username: &str,
+        history: &[EpisodeRaw],
+        latest: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>> {
+        self.transact(|mut tx| async move {
+            let held: Vec<(String, String, String)> = query_as(
+                "
+                SELECT podcast, episode, content_hash
+                FROM episode_history
+                WHERE username = $1
+                ",
+            )
+            .bind(username)
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error selecting episode history to import into: {e:?}");
+                Error::Internal
+            })?;
+
+            append_history(&mut tx, username, &unheld(history, held), now).await?;
+            let stale = upsert_episodes(&mut tx, username, latest, now).await?;
+            Ok((tx, stale))
+        })
+        .await
+    }
+
+    async fn update_episodes(
+        &self,
+        username: &str,
+        changes: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>> {
+        self.transact(|mut tx| async {
+            // stale or not, the actions still happened, so go in the history
+            append_history(&mut tx, username, &without_resends(&changes), now).await?;
+
+            let stale = upsert_episodes(&mut tx, username, changes, now).await?;
+            Ok((tx, stale))
+        })
+        .await
+    }
+
+    async fn last_change(&self, username: &str) -> Result<Option<Timestamp>> {
+        query_scalar(
+            "
+            SELECT MAX(modified)
+            FROM (
+                SELECT MAX(modified) AS modified FROM episodes WHERE username = $1
+                UNION ALL
+                SELECT MAX(modified) FROM episode_history WHERE username = $1
+                UNION ALL
+                SELECT MAX(created) FROM subscriptions WHERE username = $1
+                UNION ALL
+                SELECT MAX(deleted) FROM subscriptions WHERE username = $1
+            ) AS changes
+            ",
+        )
+        .bind(username)
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting last change: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn rehash_episodes(&self) -> Result<usize> {
+        self.transact(|mut tx| async {
+            let count = rehash(&mut tx).await?;
+            Ok((tx, count))
+        })
+        .await
+    }
+
+    async fn backup(&self, _dest: &Path) -> Result<()> {
+        error!("podsync only backs up SQLite databases, use pg_dump for postgres");
+        Err(Error::BadRequest)
+    }
+
+    async fn diagnose(&self, fix: bool, now: Timestamp) -> Result<Diagnosis> {
+        self.transact(|mut tx| async move {
+            let duplicate_subscriptions = query_as(
+                "
+                SELECT username, device, url
+                FROM subscriptions
+                WHERE deleted IS NULL
+                GROUP BY username, device, url
+                HAVING COUNT(*) > 1
+                ORDER BY username, device, url
+                ",
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error finding duplicate subscriptions: {e:?}");
+                Error::Internal
+            })?;
+
+            let unknown_devices = query_as(
+                "
+                SELECT DISTINCT username, device
+                FROM subscriptions
+                WHERE deleted IS NULL
+                    AND NOT EXISTS (
+                        SELECT 1
+                        FROM devices
+                        WHERE devices.username = subscriptions.username
+                            AND devices.id = subscriptions.device
+                    )
+                ORDER BY username, device
+                ",
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error finding unknown devices: {e:?}");
+                Error::Internal
+            })?;
+
+            let positionless_plays = query_as(
+                "
+                SELECT username, podcast, episode
+                FROM episodes
+                WHERE lower(action) = 'play' AND position IS NULL
+                ORDER BY username, podcast, episode
+                ",
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error finding positionless plays: {e:?}");
+                Error::Internal
+            })?;
+
+            let (positionless_history, unhashed): (i64, i64) = query_as(
+                "
+                SELECT
+                    (
+                        SELECT COUNT(*)
+                        FROM episode_history
+                        WHERE lower(action) = 'play' AND position IS NULL
+                    ),
+                    (
+                        SELECT COUNT(*) FROM episodes WHERE content_hash = ''
+                    ) + (
+                        SELECT COUNT(*) FROM episode_history WHERE content_hash = ''
+                    )
+                ",
+            )
+            .fetch_one(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error counting episode oddities: {e:?}");
+                Error::Internal
+            })?;
+
+            let diagnosis = Diagnosis {
+                duplicate_subscriptions,
+                unknown_devices,
+                positionless_plays,
+                positionless_history: positionless_history as usize,
+                unhashed_episodes: unhashed as usize,
+            };
+
+            if fix {
+                repair(&mut tx, &diagnosis, now).await?;
+            }
+
+            Ok((tx, diagnosis))
+        })
+        .await
+    }
+
+    async fn prune(
+        &self,
+        deleted_before: Option<Timestamp>,
+        unsubscribed_before: Option<Timestamp>,
+    ) -> Result<Pruned> {
+        self.transact(|mut tx| async move {
+            let mut pruned = Pruned::default();
+
+            if let Some(deleted_before) = deleted_before {
+                pruned.subscriptions = query(
+                    "
+                    DELETE FROM subscriptions
+                    WHERE deleted < $1
+                        AND EXISTS (
+                            SELECT 1
+                            FROM subscriptions AS newer
+                            WHERE newer.username = subscriptions.username
+                                AND newer.device = subscriptions.device
+                                AND newer.url = subscriptions.url
+                                AND (newer.deleted IS NULL OR newer.deleted > subscriptions.deleted)
+                        )
+                    ",
+                )
+                .bind(deleted_before)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| {
+                    error!("error pruning subscriptions: {e:?}");
+                    Error::Internal
+                })?
+                .rows_affected() as usize;
+            }
+
+            if let Some(unsubscribed_before) = unsubscribed_before {
+                let mut counts = [0; 2];
+
+                for (table, count) in ["episodes", "episode_history"].into_iter().zip(&mut counts) {
+                    *count = query(&format!(
+                        "
+                        DELETE FROM {table}
+                        WHERE modified < $1
+                            AND NOT EXISTS (
+                                SELECT 1
+                                FROM subscriptions
+                                WHERE subscriptions.username = {table}.username
+                                    AND subscriptions.url = {table}.podcast
+                                    AND subscriptions.deleted IS NULL
+                            )
+                        "
+                    ))
+                    .bind(unsubscribed_before)
+                    .execute(&mut tx)
+                    .await
+                    .map_err(|e| {
+                        error!("error pruning {table}: {e:?}");
+                        Error::Internal
+                    })?
+                    .rows_affected() as usize;
+                }
+
+                [pruned.episodes, pruned.history] = counts;
+            }
+
+            Ok((tx, pruned))
+        })
+        .await
+    }
+}
+
+// applies episode actions to what we hold, for `update_episodes` and `import_episodes`,
+// returning those which were stale
+async fn upsert_episodes(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    changes: Vec<EpisodeRaw>,
+    now: Timestamp,
+) -> Result<Vec<EpisodeRaw>> {
+    let keys: BTreeSet<_> = changes
+        .iter()
+        .map(|change| (&change.podcast, &change.episode))
+        .collect();
+    let keys: Vec<_> = keys.into_iter().collect();
+    let mut current = HashMap::new();
+
+    // Locked until we're done, so a concurrent upload can't slip in between our
+    // reading its timestamp and upserting over it. In order, so two can't deadlock.
+    for keys in keys.chunks((MAX_BINDS - 1) / 2) {
+        let mut select =
+            QueryBuilder::new("SELECT podcast, episode, timestamp FROM episodes WHERE username = ");
+        select
+            .push_bind(username)
+            .push(" AND (podcast, episode) IN (");
+        select.push_values(keys, |mut row, (podcast, episode)| {
+            row.push_bind(*podcast).push_bind(*episode);
+        });
+        select.push(") ORDER BY podcast, episode FOR UPDATE");
+
+        let rows: Vec<(String, String, Option<Time>)> = select
+            .build_query_as()
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|e| {
+                error!("error querying mid-transaction: {:?}", e);
+                Error::Internal
+            })?;
+
+        current.extend(
+            rows.into_iter()
+                .filter_map(|(podcast, episode, timestamp)| Some(((podcast, episode), timestamp?))),
+        );
+    }
+
+    let (rounds, stale) = upsert_rounds(changes, current);
+
+    for round in rounds {
+        for changes in round.chunks(MAX_BINDS / EPISODE_BINDS) {
+            let mut upsert = QueryBuilder::new(EPISODE_INSERT.replace("{table}", "episodes"));
+            upsert.push_values(changes, |row, change| {
+                bind_episode(row, username, change, now)
+            });
+            upsert.push(
+                "
+                ON CONFLICT (username, podcast, episode)
+                DO
+                    UPDATE SET
+                        timestamp = coalesce(excluded.timestamp, episodes.timestamp),
+                        guid = coalesce(excluded.guid, episodes.guid),
+                        action = coalesce(excluded.action, episodes.action),
+                        started = coalesce(excluded.started, episodes.started),
+                        position = coalesce(excluded.position, episodes.position),
+                        total = coalesce(excluded.total, episodes.total),
+                        modified = excluded.modified,
+                        content_hash = excluded.content_hash,
+                        hash_version = excluded.hash_version
+                    -- only update if we've changed the contents
+                    WHERE episodes.content_hash <> excluded.content_hash
+                    -- and never to an older action, which a concurrent upload
+                    -- inserted since we looked
+                    AND (
+                        episodes.timestamp IS NULL
+                        OR excluded.timestamp IS NULL
+                        OR excluded.timestamp >= episodes.timestamp
+                    )
+                ",
+            );
+
+            upsert.build().execute(&mut *tx).await.map_err(|e| {
+                error!("error querying mid-transaction: {:?}", e);
+                Error::Internal
+            })?;
+        }
+    }
+
+    Ok(stale)
+}
+
+// for `update_episodes` and `import_episodes`, once they've picked what the history lacks
+async fn append_history(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    changes: &[&EpisodeRaw],
+    now: Timestamp,
+) -> Result<()> {
+    for changes in changes.chunks(MAX_BINDS / EPISODE_BINDS) {
+        let mut insert = QueryBuilder::new(EPISODE_INSERT.replace("{table}", "episode_history"));
+        insert.push_values(changes, |row, change| {
+            bind_episode(row, username, change, now)
+        });
+
+        insert.build().execute(&mut *tx).await.map_err(|e| {
+            error!("error appending episode history: {:?}", e);
+            Error::Internal
+        })?;
+    }
+    Ok(())
+}
+
+// rehashes what's behind `HASH_VERSION`, for startup's `rehash_episodes` and the doctor
+async fn rehash(tx: &mut Transaction<'_, Postgres>) -> Result<usize> {
+    let episodes = query(
+        "
+        SELECT username,
+            podcast, episode,
+            guid, device,
+            timestamp,
+            action,
+            started, position, total,
+            NULL::BIGINT as modified
+        FROM episodes
+        WHERE hash_version < $1
+        ",
+    )
+    .bind(HASH_VERSION)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error selecting episodes to rehash: {e:?}");
+        Error::Internal
+    })?;
+
+    let count = episodes.len();
+    for row in episodes {
+        let (username, ep) = keyed_episode::<String>(&row, "username")?;
+        query(
+            "
+            UPDATE episodes
+            SET content_hash = $1, hash_version = $2
+            WHERE username = $3
+                AND podcast = $4
+                AND episode = $5
+            ",
+        )
+        .bind(ep.content_hash())
+        .bind(HASH_VERSION)
+        .bind(username)
+        .bind(&ep.podcast)
+        .bind(&ep.episode)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error rehashing episode: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    let history = query(
+        "
+        SELECT id,
+            podcast, episode,
+            guid, device,
+            timestamp,
+            action,
+            started, position, total,
+            NULL::BIGINT as modified
+        FROM episode_history
+        WHERE hash_version < $1
+        ",
+    )
+    .bind(HASH_VERSION)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error selecting episode history to rehash: {e:?}");
+        Error::Internal
+    })?;
+
+    let history_count = history.len();
+    for row in history {
+        let (id, ep) = keyed_episode::<i64>(&row, "id")?;
+        query(
+            "
+            UPDATE episode_history
+            SET content_hash = $1, hash_version = $2
+            WHERE id = $3
+            ",
+        )
+        .bind(ep.content_hash())
+        .bind(HASH_VERSION)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error rehashing episode history: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    Ok(count + history_count)
+}
+
+// fixes what `diagnose` found
+async fn repair(
+    tx: &mut Transaction<'_, Postgres>,
+    diagnosis: &Diagnosis,
+    now: Timestamp,
+) -> Result<()> {
+    query(
+        "
+        DELETE FROM subscriptions AS newer
+        USING subscriptions AS older
+        WHERE newer.deleted IS NULL
+            AND older.deleted IS NULL
+            AND newer.username = older.username
+            AND newer.device = older.device
+            AND newer.url = older.url
+            AND (newer.created, newer.ctid) > (older.created, older.ctid)
+        ",
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error removing duplicate subscriptions: {e:?}");
+        Error::Internal
+    })?;
+
+    for (username, device) in &diagnosis.unknown_devices {
+        query(
+            "
+            INSERT INTO devices
+            (id, username, type)
+            VALUES
+            ($1, $2, $3)
+            ",
+        )
+        .bind(device)
+        .bind(username)
+        .bind(DeviceType::default().as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error adding device: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    query(
+        "
+        DELETE FROM episode_history
+        WHERE lower(action) = 'play' AND position IS NULL
+        ",
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error removing positionless plays from history: {e:?}");
+        Error::Internal
+    })?;
+
+    for (username, podcast, episode) in &diagnosis.positionless_plays {
+        query(
+            "
+            DELETE FROM episodes
+            WHERE username = $1 AND podcast = $2 AND episode = $3
+            ",
+        )
+        .bind(username)
+        .bind(podcast)
+        .bind(episode)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error removing positionless play: {e:?}");
+            Error::Internal
+        })?;
+
+        // as `update_episodes` would have it, the action with the latest timestamp
+        query(
+            "
+            INSERT INTO episodes
+            (
+                username, device,
+                podcast, episode,
+                timestamp, guid,
+                action,
+                started, position, total,
+                modified, content_hash, hash_version
+            )
+            SELECT
+                username, device,
+                podcast, episode,
+                timestamp, guid,
+                action,
+                started, position, total,
+                $4, content_hash, hash_version
+            FROM episode_history
+            WHERE username = $1 AND podcast = $2 AND episode = $3
+            ORDER BY timestamp DESC NULLS LAST, id DESC
+            LIMIT 1
+            ",
+        )
+        .bind(username)
+        .bind(podcast)
+        .bind(episode)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error restoring episode from history: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    for table in ["episodes", "episode_history"] {
+        query(&format!(
+            "
+            UPDATE {table}
+            SET hash_version = 0
+            WHERE content_hash = ''
+            "
+        ))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error marking {table} for rehashing: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    rehash(tx).await.map(|_| ())
+}
+
+// an episode along with the column identifying its row
+fn keyed_episode<'r, K>(row: &'r PgRow, key: &str) -> Result<(K, EpisodeRaw)>
+where
+    K: sqlx::Decode<'r, Postgres> + sqlx::Type<Postgres>,
+{
+    let key = row.try_get(key);
+    let ep = EpisodeRaw::from_row(row);
+
+    key.and_then(|key| Ok((key, ep?))).map_err(|e| {
+        error!("error decoding episode to rehash: {e:?}");
+        Error::Internal
+    })
+}
+
+#[cfg(test)]
+#[async_trait]
+impl super::Fixtures for PostgresStore {
+    async fn insert_episode(&self, username: &str, ep: EpisodeRaw, meta: super::EpisodeMeta) {
+        query(
+            "
+            INSERT INTO episodes
+            (
+                username, device,
+                podcast, episode,
+                timestamp, guid,
+                action,
+                started, position, total,
+                modified, content_hash, hash_version
+            )
+            VALUES
+            (
+                $1, $2,
+                $3, $4,
+                $5, $6,
+                $7,
+                $8, $9, $10,
+                $11, $12, $13
+            )
+            ",
+        )
+        .bind(username)
+        .bind(ep.device)
+        .bind(ep.podcast)
+        .bind(ep.episode)
+        .bind(ep.timestamp)
+        .bind(ep.guid)
+        .bind(ep.action)
+        .bind(ep.started)
+        .bind(ep.position)
+        .bind(ep.total)
+        .bind(meta.modified)
+        .bind(meta.content_hash)
+        .bind(meta.hash_version)
+        .execute(&self.0)
+        .await
+        .unwrap();
+    }
+
+    async fn episode_meta(&self, username: &str) -> Vec<super::EpisodeMeta> {
+        let meta: Vec<(Timestamp, String, i64)> = query_as(
+            "
+            SELECT modified, content_hash, hash_version
+            FROM episodes
+            WHERE username = $1
+            ",
+        )
+        .bind(username)
+        .fetch_all(&self.0)
+        .await
+        .unwrap();
+
+        meta.into_iter()
+            .map(
+                |(modified, content_hash, hash_version)| super::EpisodeMeta {
+                    modified,
+                    content_hash,
+                    hash_version,
+                },
+            )
+            .collect()
+    }
+
+    async fn set_episode_meta(&self, username: &str, meta: super::EpisodeMeta) {
+        query(
+            "
+            UPDATE episodes
+            SET modified = $1, content_hash = $2, hash_version = $3
+            WHERE username = $4
+            ",
+        )
+        .bind(meta.modified)
+        .bind(meta.content_hash)
+        .bind(meta.hash_version)
+        .bind(username)
+        .execute(&self.0)
+        .await
+        .unwrap();
+    }
+
+    async fn insert_subscription(&self, username: &str, device: &str, sub: Subscription) {
+        query(
+            "
+            INSERT INTO subscriptions
+            (username, device, url, created, deleted)
+            VALUES
+            ($1, $2, $3, $4, $5)
+            ",
+        )
+        .bind(username)
+        .bind(device)
+        .bind(sub.url)
+        .bind(sub.created)
+        .bind(sub.deleted)
+        .execute(&self.0)
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,1552 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    future::Future,
+    path::Path,
+};
+
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use log::error;
+use sqlx::{
+    query, query_as, query_builder::Separated, query_scalar, Pool, QueryBuilder, Sqlite,
+    Transaction,
+};
+
+use super::{
+    forward, spawn_stream, unheld, upsert_rounds, without_resends, Diagnosis, EpisodeCursor,
+    EpisodeFilter, EpisodeRow, EpisodeStream, Pruned, Store, UserSummary,
+};
+use crate::device::{DeviceAndSub, DeviceType, DeviceUpdate};
+use crate::episode::{EpisodeActionRaw, EpisodeRaw, Time, HASH_VERSION};
+use crate::podsync::{Error, Result};
+use crate::subscription::{Subscription, SubscriptionChangesFromClient};
+use crate::time::Timestamp;
+use crate::user::{Invite, User};
+
+pub struct SqliteStore(Pool<Sqlite>);
+
+// SQLITE_MAX_VARIABLE_NUMBER, since 3.32
+const MAX_VARIABLES: usize = 32766;
+
+// writes `bind_episode`'s rows into `{table}`, either `episodes` or `episode_history`
+const EPISODE_INSERT: &str = "
+    INSERT INTO {table}
+    (
+        username, device,
+        podcast, episode,
+        timestamp, guid,
+        action,
+        started, position, total,
+        modified, content_hash, hash_version
+    )
+    ";
+const EPISODE_VARIABLES: usize = 13;
+
+fn bind_episode<'q>(
+    mut row: Separated<'_, 'q, Sqlite, &'static str>,
+    username: &'q str,
+    change: &'q EpisodeRaw,
+    now: Timestamp,
+) {
+    row.push_bind(username)
+        .push_bind(&change.device)
+        .push_bind(&change.podcast)
+        .push_bind(&change.episode)
+        .push_bind(&change.timestamp)
+        .push_bind(&change.guid)
+        .push_bind(&change.action)
+        .push_bind(change.started)
+        .push_bind(change.position)
+        .push_bind(change.total)
+        .push_bind(now)
+        .push_bind(change.content_hash())
+        .push_bind(HASH_VERSION);
+}
+
+impl SqliteStore {
+    pub fn new(db: Pool<Sqlite>) -> Self {
+        Self(db)
+    }
+
+    async fn transact<'t, T, R, F>(&self, transaction: T) -> Result<R>
+    where
+        T: FnOnce(Transaction<'t, Sqlite>) -> F,
+        F: Future<Output = Result<(Transaction<'t, Sqlite>, R)>>,
+    {
+        let tx = self.0.begin().await.map_err(|e| {
+            error!("error beginning transaction: {:?}", e);
+            Error::Internal
+        })?;
+
+        // could probably pass &mut *tx here
+        let (tx, r) = transaction(tx).await?;
+
+        tx.commit().await.map_err(|e| {
+            error!("error committing transaction: {:?}", e);
+            Error::Internal
+        })?;
+
+        Ok(r)
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn user(&self, username: &str) -> Result<Option<User>> {
+        query_as!(
+            User,
+            "
+                SELECT *
+                FROM users
+                WHERE username = ?
+                ",
+            username,
+        )
+        .fetch_optional(&self.0)
+        .await
+        .map_err(|e| {
+            error!("couldn't query for user {}: {e:?}", username);
+            Error::Internal
+        })
+    }
+
+    async fn users_with_session(&self, session_id: &str) -> Result<Vec<User>> {
+        query_as!(
+            User,
+            "
+            SELECT *
+            FROM users
+            WHERE session_id = ?
+            ",
+            session_id,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("couldn't query for session {session_id}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_session(&self, username: &str, session_id: Option<&str>) -> Result<()> {
+        query!(
+            "
+            UPDATE users
+            SET session_id = ?
+            WHERE username = ?
+            ",
+            session_id,
+            username,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating session_id for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_password(&self, username: &str, pwhash: &str) -> Result<()> {
+        query!(
+            "
+            UPDATE users
+            SET pwhash = ?
+            WHERE username = ?
+            ",
+            pwhash,
+            username,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating password for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_admin(&self, username: &str, admin: bool) -> Result<()> {
+        query!(
+            "
+            UPDATE users
+            SET admin = ?
+            WHERE username = ?
+            ",
+            admin,
+            username,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating admin for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<()> {
+        query!(
+            "
+            UPDATE users
+            SET disabled = ?1,
+                session_id = CASE WHEN ?1 THEN NULL ELSE session_id END
+            WHERE username = ?2
+            ",
+            disabled,
+            username,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error updating disabled for {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>> {
+        query_as!(
+            UserSummary,
+            r#"
+            SELECT username, admin, disabled,
+                (
+                    SELECT COUNT(*) FROM devices
+                    WHERE devices.username = users.username
+                ) AS "devices!: i64",
+                (
+                    SELECT COUNT(*) FROM subscriptions
+                    WHERE subscriptions.username = users.username AND deleted IS NULL
+                ) AS "subscriptions!: i64",
+                (
+                    SELECT COUNT(*) FROM episodes
+                    WHERE episodes.username = users.username
+                ) AS "episodes!: i64"
+            FROM users
+            ORDER BY username
+            "#,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error summarising users: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn register(
+        &self,
+        username: &str,
+        pwhash: &str,
+        invite: Option<&str>,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.transact(|mut tx| async move {
+            if let Some(code) = invite {
+                let used = query!(
+                    "
+                    UPDATE invites
+                    SET used = used + 1
+                    WHERE code = ?
+                    AND used < max_uses
+                    AND (expires IS NULL OR expires > ?)
+                    ",
+                    code,
+                    now,
+                )
+                .execute(&mut tx)
+                .await
+                .map_err(|e| {
+                    error!("error using invite {code}: {e:?}");
+                    Error::Internal
+                })?;
+
+                if used.rows_affected() == 0 {
+                    error!("invite {code} is unknown, used up or expired");
+                    return Err(Error::Forbidden);
+                }
+            }
+
+            let created = query!(
+                "
+                INSERT INTO users (username, pwhash)
+                VALUES (?, ?)
+                ON CONFLICT (username) DO NOTHING
+                ",
+                username,
+                pwhash,
+            )
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error registering user {username}: {e:?}");
+                Error::Internal
+            })?;
+
+            if created.rows_affected() == 0 {
+                error!("can't register {username}, who already exists");
+                return Err(Error::Conflict);
+            }
+
+            Ok((tx, ()))
+        })
+        .await
+    }
+
+    async fn create_invite(&self, invite: &Invite) -> Result<()> {
+        query!(
+            "
+            INSERT INTO invites (code, created_by, created, expires, max_uses, used)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ",
+            invite.code,
+            invite.created_by,
+            invite.created,
+            invite.expires,
+            invite.max_uses,
+            invite.used,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error creating invite: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn invites(&self) -> Result<Vec<Invite>> {
+        query_as!(
+            Invite,
+            r#"
+            SELECT code, created_by,
+                created as "created: Timestamp",
+                expires as "expires: Timestamp",
+                max_uses, used
+            FROM invites
+            ORDER BY created DESC, code
+            "#,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting invites: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn delete_invite(&self, code: &str) -> Result<()> {
+        query!(
+            "
+            DELETE FROM invites
+            WHERE code = ?
+            ",
+            code,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error deleting invite {code}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn create_user(&self, username: &str, pwhash: &str) -> Result<()> {
+        query!(
+            "
+            INSERT INTO users (username, pwhash)
+            VALUES (?, ?)
+            ",
+            username,
+            pwhash,
+        )
+        .execute(&self.0)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!("error creating user {username}: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn devices(&self, username: &str) -> Result<Vec<DeviceAndSub>> {
+        query_as!(
+            DeviceAndSub,
+            r#"
+            SELECT id,
+                coalesce(caption, '') as "caption!: _",
+                type as "type!: _",
+                COUNT(subscriptions.url) as "subscriptions!: _"
+            FROM devices
+            LEFT JOIN subscriptions
+                ON devices.username = subscriptions.username
+                AND devices.id = subscriptions.device
+                AND subscriptions.deleted IS NULL
+            WHERE devices.username = ?
+            GROUP BY devices.id
+            ORDER BY devices.id
+            "#,
+            username,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting devices: {:?}", e);
+            Error::Internal
+        })
+    }
+
+    async fn update_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        update: DeviceUpdate,
+    ) -> Result<()> {
+        let caption: Option<_> = update.caption;
+        let type_default = update.r#type.unwrap_or_default();
+        let r#type: Option<_> = update.r#type;
+
+        let result = query!(
+            "
+            INSERT INTO devices
+            (id, username, caption, type)
+            VALUES
+            (?, ?, ?, ?)
+            ON CONFLICT
+            DO
+                UPDATE SET
+                    caption = coalesce(?, devices.caption),
+                    type = coalesce(?, devices.type)
+                WHERE id = ? AND username = ?
+            ",
+            device_id,
+            username,
+            caption,
+            type_default,
+            caption,
+            r#type,
+            device_id,
+            username
+        )
+        .execute(&self.0)
+        .await;
+
+        match result {
+            Ok(_result) => Ok(()),
+            Err(e) => {
+                error!("error inserting device: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str, now: Timestamp) -> Result<()> {
+        self.transact(|mut tx| async move {
+            query!(
+                "
+                UPDATE subscriptions
+                SET deleted = ?
+                WHERE username = ? AND device = ? AND deleted IS NULL
+                ",
+                now,
+                username,
+                device_id,
+            )
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error deleting (updating) subscriptions of device {device_id}: {e:?}");
+                Error::Internal
+            })?;
+
+            query!(
+                "
+                DELETE FROM devices
+                WHERE username = ? AND id = ?
+                ",
+                username,
+                device_id,
+            )
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error deleting device {device_id}: {e:?}");
+                Error::Internal
+            })?;
+
+            Ok((tx, ()))
+        })
+        .await
+    }
+
+    async fn subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Subscription>> {
+        query_as!(
+            Subscription,
+            r#"
+            SELECT url,
+                deleted as "deleted: _",
+                created as "created!: _"
+            FROM subscriptions
+            WHERE username = ?
+                AND device = ?
+                AND created > ?
+            UNION ALL
+            -- rather than `created > ? OR deleted > ?`, which can't use a single index
+            SELECT url, deleted, created
+            FROM subscriptions
+            WHERE username = ?
+                AND device = ?
+                AND deleted > ?
+                AND created <= ?
+            "#,
+            username,
+            device_id,
+            since,
+            username,
+            device_id,
+            since,
+            since,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting subscriptions: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn update_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        changes: &SubscriptionChangesFromClient,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.transact(|mut tx| async {
+            for urls in changes.remove.chunks(MAX_VARIABLES - 3) {
+                let mut update = QueryBuilder::new("UPDATE subscriptions SET deleted = ");
+                update
+                    .push_bind(now)
+                    .push(" WHERE username = ")
+                    .push_bind(username)
+                    .push(" AND device = ")
+                    .push_bind(device_id)
+                    .push(" AND deleted IS NULL AND url IN (");
+                let mut list = update.separated(", ");
+                for url in urls {
+                    list.push_bind(url);
+                }
+                update.push(")");
+
+                update.build().execute(&mut *tx).await.map_err(|e| {
+                    error!("error deleting (updating) subscriptions: {e:?}");
+                    Error::Internal
+                })?;
+            }
+
+            // one statement doesn't see its own inserts, so can't skip repeats itself
+            let mut seen = HashSet::new();
+            let add: Vec<_> = changes.add.iter().filter(|url| seen.insert(*url)).collect();
+
+            for urls in add.chunks(MAX_VARIABLES - 5) {
+                // `deleted` is NULL for active subscriptions, and NULLs never conflict
+                // in a UNIQUE constraint, so we check for an active one ourselves
+                let mut insert = QueryBuilder::new(
+                    "INSERT INTO subscriptions (username, device, url, created) SELECT ",
+                );
+                insert
+                    .push_bind(username)
+                    .push(", ")
+                    .push_bind(device_id)
+                    .push(", new.column1, ")
+                    .push_bind(now)
+                    .push(" FROM (");
+                insert.push_values(urls, |mut row, url| {
+                    row.push_bind(url);
+                });
+                insert
+                    .push(
+                        ") AS new WHERE NOT EXISTS (\
+                            SELECT 1 FROM subscriptions WHERE username = ",
+                    )
+                    .push_bind(username)
+                    .push(" AND device = ")
+                    .push_bind(device_id)
+                    .push(" AND url = new.column1 AND deleted IS NULL)");
+
+                insert.build().execute(&mut *tx).await.map_err(|e| {
+                    error!("error inserting subscriptions: {e:?}");
+                    Error::Internal
+                })?;
+            }
+
+            Ok((tx, ()))
+        })
+        .await
+    }
+
+    async fn all_subscriptions(&self, username: &str) -> Result<Vec<(String, Subscription)>> {
+        let subscriptions = query!(
+            r#"
+            SELECT device, url,
+                created as "created!: Timestamp",
+                deleted as "deleted: Timestamp"
+            FROM subscriptions
+            WHERE username = ?
+            ORDER BY device, created, url
+            "#,
+            username,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting subscriptions: {e:?}");
+            Error::Internal
+        })?;
+
+        Ok(subscriptions
+            .into_iter()
+            .map(|row| {
+                let sub = Subscription {
+                    url: row.url,
+                    created: row.created,
+                    deleted: row.deleted,
+                };
+                (row.device, sub)
+            })
+            .collect())
+    }
+
+    async fn import_subscriptions(
+        &self,
+        username: &str,
+        device_id: &str,
+        subscriptions: &[Subscription],
+    ) -> Result<usize> {
+        self.transact(|mut tx| async {
+            let mut count = 0;
+
+            for sub in subscriptions {
+                // `IS` rather than `=`, so a NULL (active) `deleted` matches
+                let result = query!(
+                    "
+                    INSERT INTO subscriptions
+                    (username, device, url, created, deleted)
+                    SELECT ?, ?, ?, ?, ?
+                    WHERE NOT EXISTS (
+                        SELECT 1
+                        FROM subscriptions
+                        WHERE username = ?
+                            AND device = ?
+                            AND url = ?
+                            AND deleted IS ?
+                    )
+                    ",
+                    username,
+                    device_id,
+                    sub.url,
+                    sub.created,
+                    sub.deleted,
+                    username,
+                    device_id,
+                    sub.url,
+                    sub.deleted,
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| {
+                    error!("error importing subscription: {e:?}");
+                    Error::Internal
+                })?;
+
+                count += result.rows_affected() as usize;
+            }
+
+            Ok((tx, count))
+        })
+        .await
+    }
+
+    async fn recent_episodes(&self, username: &str, count: usize) -> Result<Vec<EpisodeRaw>> {
+        let count = count as i64;
+
+        query_as!(
+            EpisodeRaw,
+            r#"
+            SELECT podcast as "podcast!", episode as "episode!",
+                guid, device,
+                timestamp as "timestamp: _",
+                action as "action!: _",
+                started, position, total,
+                modified as "modified?: _"
+            FROM episodes
+            WHERE username = ?
+            ORDER BY modified DESC, podcast DESC, episode DESC
+            LIMIT ?
+            "#,
+            username,
+            count,
+        )
+        .fetch_all(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting {username}'s recent episodes: {e:?}");
+            Error::Internal
+        })
+    }
+
+    fn episode_stream(&self, username: &str, filter: &EpisodeFilter) -> EpisodeStream {
+        let db = self.0.clone();
+        let username = username.to_string();
+        let filter = filter.clone();
+
+        spawn_stream(|tx| async move {
+            let EpisodeFilter {
+                since,
+                podcast: podcast_filter,
+                device: device_filter,
+                aggregated,
+                after,
+                limit,
+            } = filter;
+            // a negative LIMIT is none at all
+            let limit = limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+
+            if aggregated {
+                let (after_modified, after_podcast, after_episode) = match after {
+                    Some(EpisodeCursor::Aggregated {
+                        modified,
+                        podcast,
+                        episode,
+                    }) => (Some(modified), Some(podcast), Some(episode)),
+                    _ => (None, None, None),
+                };
+
+                let rows = query_as!(
+                    EpisodeRaw,
+                    r#"
+                    SELECT podcast as "podcast!", episode as "episode!",
+                        guid, device,
+                        timestamp as "timestamp: _",
+                        action as "action!: _",
+                        started, position, total,
+                        modified as "modified?: _"
+                    FROM episodes
+                    WHERE username = ?1
+                        AND modified > ?2
+                        AND (?3 IS NULL OR podcast = ?3)
+                        AND (?4 IS NULL OR device = ?4)
+                        AND (?5 IS NULL OR (modified, podcast, episode) > (?5, ?6, ?7))
+                    ORDER BY modified, podcast, episode
+                    LIMIT ?8
+                    "#,
+                    username,
+                    since,
+                    podcast_filter,
+                    device_filter,
+                    after_modified,
+                    after_podcast,
+                    after_episode,
+                    limit,
+                )
+                .fetch(&db)
+                .map_ok(|episode| EpisodeRow {
+                    episode,
+                    history_id: None,
+                });
+
+                forward(rows, tx).await;
+            } else {
+                let (after_modified, after_id) = match after {
+                    Some(EpisodeCursor::History { modified, id }) => (Some(modified), Some(id)),
+                    _ => (None, None),
+                };
+
+                let rows = query!(
+                    r#"
+                    SELECT id as "id!", podcast as "podcast!", episode as "episode!",
+                        guid, device,
+                        timestamp as "timestamp: Time",
+                        action as "action!: EpisodeActionRaw",
+                        started, position, total,
+                        modified as "modified!: Timestamp"
+                    FROM episode_history
+                    WHERE username = ?1
+                        AND modified > ?2
+                        AND (?3 IS NULL OR podcast = ?3)
+                        AND (?4 IS NULL OR device = ?4)
+                        AND (?5 IS NULL OR (modified, id) > (?5, ?6))
+                    ORDER BY modified, id
+                    LIMIT ?7
+                    "#,
+                    username,
+                    since,
+                    podcast_filter,
+                    device_filter,
+                    after_modified,
+                    after_id,
+                    limit,
+                )
+                .fetch(&db)
+                .map_ok(|row| EpisodeRow {
+                    episode: EpisodeRaw {
+                        podcast: row.podcast,
+                        episode: row.episode,
+                        timestamp: row.timestamp,
+                        guid: row.guid,
+                        action: row.action,
+                        started: row.started,
+                        position: row.position,
+                        total: row.total,
+                        device: row.device,
+                        modified: Some(row.modified),
+                    },
+                    history_id: Some(row.id),
+                });
+
+                forward(rows, tx).await;
+            }
+        })
+    }
+
+    async fn import_episodes(
+        &self,
+        username: &str,
+        history: &[EpisodeRaw],
+        latest: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>> {
+        self.transact(|mut tx| async move {
+            let held = query!(
+                "
+                SELECT podcast, episode, content_hash
+                FROM episode_history
+                WHERE username = ?
+                ",
+                username,
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error selecting episode history to import into: {e:?}");
+                Error::Internal
+            })?
+            .into_iter()
+            .map(|r| (r.podcast, r.episode, r.content_hash));
+
+            append_history(&mut tx, username, &unheld(history, held), now).await?;
+            let stale = upsert_episodes(&mut tx, username, latest, now).await?;
+            Ok((tx, stale))
+        })
+        .await
+    }
+
+    async fn update_episodes(
+        &self,
+        username: &str,
+        changes: Vec<EpisodeRaw>,
+        now: Timestamp,
+    ) -> Result<Vec<EpisodeRaw>> {
+        self.transact(|mut tx| async {
+            // stale or not, the actions still happened, so go in the history
+            append_history(&mut tx, username, &without_resends(&changes), now).await?;
+
+            let stale = upsert_episodes(&mut tx, username, changes, now).await?;
+            Ok((tx, stale))
+        })
+        .await
+    }
+
+    async fn last_change(&self, username: &str) -> Result<Option<Timestamp>> {
+        query_scalar!(
+            r#"
+            SELECT MAX(modified) as "modified: Timestamp"
+            FROM (
+                SELECT MAX(modified) AS modified FROM episodes WHERE username = ?1
+                UNION ALL
+                SELECT MAX(modified) FROM episode_history WHERE username = ?1
+                UNION ALL
+                SELECT MAX(created) FROM subscriptions WHERE username = ?1
+                UNION ALL
+                SELECT MAX(deleted) FROM subscriptions WHERE username = ?1
+            )
+            "#,
+            username,
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| {
+            error!("error selecting last change: {e:?}");
+            Error::Internal
+        })
+    }
+
+    async fn rehash_episodes(&self) -> Result<usize> {
+        self.transact(|mut tx| async {
+            let count = rehash(&mut tx).await?;
+            Ok((tx, count))
+        })
+        .await
+    }
+
+    async fn backup(&self, dest: &Path) -> Result<()> {
+        let Some(dest) = dest.to_str() else {
+            error!("can't back up to a non-UTF-8 path {}", dest.display());
+            return Err(Error::BadRequest);
+        };
+
+        // unlike copying the file, this can't catch a transaction half-written
+        query("VACUUM INTO ?")
+            .bind(dest)
+            .execute(&self.0)
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                error!("couldn't back up to {dest}: {e:?}");
+                Error::Internal
+            })
+    }
+
+    async fn diagnose(&self, fix: bool, now: Timestamp) -> Result<Diagnosis> {
+        self.transact(|mut tx| async move {
+            let duplicate_subscriptions = query!(
+                "
+                SELECT username, device, url
+                FROM subscriptions
+                WHERE deleted IS NULL
+                GROUP BY username, device, url
+                HAVING COUNT(*) > 1
+                ORDER BY username, device, url
+                ",
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error finding duplicate subscriptions: {e:?}");
+                Error::Internal
+            })?
+            .into_iter()
+            .map(|r| (r.username, r.device, r.url))
+            .collect();
+
+            let unknown_devices = query!(
+                "
+                SELECT DISTINCT username, device
+                FROM subscriptions
+                WHERE deleted IS NULL
+                    AND NOT EXISTS (
+                        SELECT 1
+                        FROM devices
+                        WHERE devices.username = subscriptions.username
+                            AND devices.id = subscriptions.device
+                    )
+                ORDER BY username, device
+                ",
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error finding unknown devices: {e:?}");
+                Error::Internal
+            })?
+            .into_iter()
+            .map(|r| (r.username, r.device))
+            .collect();
+
+            let positionless_plays = query!(
+                "
+                SELECT username, podcast, episode
+                FROM episodes
+                WHERE lower(action) = 'play' AND position IS NULL
+                ORDER BY username, podcast, episode
+                ",
+            )
+            .fetch_all(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error finding positionless plays: {e:?}");
+                Error::Internal
+            })?
+            .into_iter()
+            .map(|r| (r.username, r.podcast, r.episode))
+            .collect();
+
+            let counts = query!(
+                r#"
+                SELECT
+                    (
+                        SELECT COUNT(*)
+                        FROM episode_history
+                        WHERE lower(action) = 'play' AND position IS NULL
+                    ) as "positionless_history!: i64",
+                    (
+                        SELECT COUNT(*) FROM episodes WHERE content_hash = ''
+                    ) + (
+                        SELECT COUNT(*) FROM episode_history WHERE content_hash = ''
+                    ) as "unhashed!: i64"
+                "#,
+            )
+            .fetch_one(&mut tx)
+            .await
+            .map_err(|e| {
+                error!("error counting episode oddities: {e:?}");
+                Error::Internal
+            })?;
+
+            let diagnosis = Diagnosis {
+                duplicate_subscriptions,
+                unknown_devices,
+                positionless_plays,
+                positionless_history: counts.positionless_history as usize,
+                unhashed_episodes: counts.unhashed as usize,
+            };
+
+            if fix {
+                repair(&mut tx, &diagnosis, now).await?;
+            }
+
+            Ok((tx, diagnosis))
+        })
+        .await
+    }
+
+    async fn prune(
+        &self,
+        deleted_before: Option<Timestamp>,
+        unsubscribed_before: Option<Timestamp>,
+    ) -> Result<Pruned> {
+        self.transact(|mut tx| async move {
+            let mut pruned = Pruned::default();
+
+            if let Some(deleted_before) = deleted_before {
+                pruned.subscriptions = query!(
+                    "
+                    DELETE FROM subscriptions
+                    WHERE deleted < ?
+                        AND EXISTS (
+                            SELECT 1
+                            FROM subscriptions AS newer
+                            WHERE newer.username = subscriptions.username
+                                AND newer.device = subscriptions.device
+                                AND newer.url = subscriptions.url
+                                AND (newer.deleted IS NULL OR newer.deleted > subscriptions.deleted)
+                        )
+                    ",
+                    deleted_before,
+                )
+                .execute(&mut tx)
+                .await
+                .map_err(|e| {
+                    error!("error pruning subscriptions: {e:?}");
+                    Error::Internal
+                })?
+                .rows_affected() as usize;
+            }
+
+            if let Some(unsubscribed_before) = unsubscribed_before {
+                pruned.episodes = query!(
+                    "
+                    DELETE FROM episodes
+                    WHERE modified < ?
+                        AND NOT EXISTS (
+                            SELECT 1
+                            FROM subscriptions
+                            WHERE subscriptions.username = episodes.username
+                                AND subscriptions.url = episodes.podcast
+                                AND subscriptions.deleted IS NULL
+                        )
+                    ",
+                    unsubscribed_before,
+                )
+                .execute(&mut tx)
+                .await
+                .map_err(|e| {
+                    error!("error pruning episodes: {e:?}");
+                    Error::Internal
+                })?
+                .rows_affected() as usize;
+
+                pruned.history = query!(
+                    "
+                    DELETE FROM episode_history
+                    WHERE modified < ?
+                        AND NOT EXISTS (
+                            SELECT 1
+                            FROM subscriptions
+                            WHERE subscriptions.username = episode_history.username
+                                AND subscriptions.url = episode_history.podcast
+                                AND subscriptions.deleted IS NULL
+                        )
+                    ",
+                    unsubscribed_before,
+                )
+                .execute(&mut tx)
+                .await
+                .map_err(|e| {
+                    error!("error pruning episode history: {e:?}");
+                    Error::Internal
+                })?
+                .rows_affected() as usize;
+            }
+
+            Ok((tx, pruned))
+        })
+        .await
+    }
+}
+
+// applies episode actions to what we hold, for `update_episodes` and `import_episodes`,
+// returning those which were stale
+async fn upsert_episodes(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &str,
+    changes: Vec<EpisodeRaw>,
+    now: Timestamp,
+) -> Result<Vec<EpisodeRaw>> {
+    let keys: BTreeSet<_> = changes
+        .iter()
+        .map(|change| (&change.podcast, &change.episode))
+        .collect();
+    let keys: Vec<_> = keys.into_iter().collect();
+    let mut current = HashMap::new();
+
+    for keys in keys.chunks((MAX_VARIABLES - 1) / 2) {
+        let mut select =
+            QueryBuilder::new("SELECT podcast, episode, timestamp FROM episodes WHERE username = ");
+        select
+            .push_bind(username)
+            .push(" AND (podcast, episode) IN (");
+        select.push_values(keys, |mut row, (podcast, episode)| {
+            row.push_bind(*podcast).push_bind(*episode);
+        });
+        select.push(")");
+
+        let rows: Vec<(String, String, Option<Time>)> = select
+            .build_query_as()
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|e| {
+                error!("error querying mid-transaction: {:?}", e);
+                Error::Internal
+            })?;
+
+        current.extend(
+            rows.into_iter()
+                .filter_map(|(podcast, episode, timestamp)| Some(((podcast, episode), timestamp?))),
+        );
+    }
+
+    let (rounds, stale) = upsert_rounds(changes, current);
+
+    for round in rounds {
+        for changes in round.chunks(MAX_VARIABLES / EPISODE_VARIABLES) {
+            let mut upsert = QueryBuilder::new(EPISODE_INSERT.replace("{table}", "episodes"));
+            upsert.push_values(changes, |row, change| {
+                bind_episode(row, username, change, now)
+            });
+            upsert.push(
+                "
+                ON CONFLICT
+                DO
+                    UPDATE SET
+                        timestamp = coalesce(excluded.timestamp, episodes.timestamp),
+                        guid = coalesce(excluded.guid, episodes.guid),
+                        action = coalesce(excluded.action, episodes.action),
+                        started = coalesce(excluded.started, episodes.started),
+                        position = coalesce(excluded.position, episodes.position),
+                        total = coalesce(excluded.total, episodes.total),
+                        modified = excluded.modified,
+                        content_hash = excluded.content_hash,
+                        hash_version = excluded.hash_version
+                    -- only update if we've changed the contents
+                    WHERE episodes.content_hash <> excluded.content_hash
+                ",
+            );
+
+            upsert.build().execute(&mut *tx).await.map_err(|e| {
+                error!("error querying mid-transaction: {:?}", e);
+                Error::Internal
+            })?;
+        }
+    }
+
+    Ok(stale)
+}
+
+// for `update_episodes` and `import_episodes`, once they've picked what the history lacks
+async fn append_history(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &str,
+    changes: &[&EpisodeRaw],
+    now: Timestamp,
+) -> Result<()> {
+    for changes in changes.chunks(MAX_VARIABLES / EPISODE_VARIABLES) {
+        let mut insert = QueryBuilder::new(EPISODE_INSERT.replace("{table}", "episode_history"));
+        insert.push_values(changes, |row, change| {
+            bind_episode(row, username, change, now)
+        });
+
+        insert.build().execute(&mut *tx).await.map_err(|e| {
+            error!("error appending episode history: {:?}", e);
+            Error::Internal
+        })?;
+    }
+    Ok(())
+}
+
+// rehashes what's behind `HASH_VERSION`, for startup's `rehash_episodes` and the doctor
+async fn rehash(tx: &mut Transaction<'_, Sqlite>) -> Result<usize> {
+    let episodes = query!(
+        r#"
+        SELECT rowid as "rowid!: i64",
+            podcast, episode,
+            guid, device,
+            timestamp as "timestamp: Time",
+            action as "action!: EpisodeActionRaw",
+            started, position, total
+        FROM episodes
+        WHERE hash_version < ?
+        "#,
+        HASH_VERSION,
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error selecting episodes to rehash: {e:?}");
+        Error::Internal
+    })?;
+
+    let count = episodes.len();
+    for ep in episodes {
+        let hash = EpisodeRaw {
+            device: ep.device,
+            podcast: ep.podcast,
+            episode: ep.episode,
+            timestamp: ep.timestamp,
+            guid: ep.guid,
+            action: ep.action,
+            started: ep.started,
+            position: ep.position,
+            total: ep.total,
+            modified: None,
+        }
+        .content_hash();
+
+        query!(
+            "
+            UPDATE episodes
+            SET content_hash = ?, hash_version = ?
+            WHERE rowid = ?
+            ",
+            hash,
+            HASH_VERSION,
+            ep.rowid,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error rehashing episode: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    let history = query!(
+        r#"
+        SELECT id,
+            podcast, episode,
+            guid, device,
+            timestamp as "timestamp: Time",
+            action as "action!: EpisodeActionRaw",
+            started, position, total
+        FROM episode_history
+        WHERE hash_version < ?
+        "#,
+        HASH_VERSION,
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error selecting episode history to rehash: {e:?}");
+        Error::Internal
+    })?;
+
+    let history_count = history.len();
+    for ep in history {
+        let hash = EpisodeRaw {
+            device: ep.device,
+            podcast: ep.podcast,
+            episode: ep.episode,
+            timestamp: ep.timestamp,
+            guid: ep.guid,
+            action: ep.action,
+            started: ep.started,
+            position: ep.position,
+            total: ep.total,
+            modified: None,
+        }
+        .content_hash();
+
+        query!(
+            "
+            UPDATE episode_history
+            SET content_hash = ?, hash_version = ?
+            WHERE id = ?
+            ",
+            hash,
+            HASH_VERSION,
+            ep.id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error rehashing episode history: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    Ok(count + history_count)
+}
+
+// fixes what `diagnose` found
+async fn repair(
+    tx: &mut Transaction<'_, Sqlite>,
+    diagnosis: &Diagnosis,
+    now: Timestamp,
+) -> Result<()> {
+    query!(
+        "
+        DELETE FROM subscriptions
+        WHERE deleted IS NULL
+            AND rowid NOT IN (
+                SELECT MIN(rowid)
+                FROM subscriptions
+                WHERE deleted IS NULL
+                GROUP BY username, device, url
+            )
+        ",
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error removing duplicate subscriptions: {e:?}");
+        Error::Internal
+    })?;
+
+    for (username, device) in &diagnosis.unknown_devices {
+        let r#type = DeviceType::default();
+
+        query!(
+            "
+            INSERT INTO devices
+            (id, username, type)
+            VALUES
+            (?, ?, ?)
+            ",
+            device,
+            username,
+            r#type,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error adding device: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    query!(
+        "
+        DELETE FROM episode_history
+        WHERE lower(action) = 'play' AND position IS NULL
+        ",
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        error!("error removing positionless plays from history: {e:?}");
+        Error::Internal
+    })?;
+
+    for (username, podcast, episode) in &diagnosis.positionless_plays {
+        query!(
+            "
+            DELETE FROM episodes
+            WHERE username = ? AND podcast = ? AND episode = ?
+            ",
+            username,
+            podcast,
+            episode,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error removing positionless play: {e:?}");
+            Error::Internal
+        })?;
+
+        // as `update_episodes` would have it, the action with the latest timestamp
+        query!(
+            "
+            INSERT INTO episodes
+            (
+                username, device,
+                podcast, episode,
+                timestamp, guid,
+                action,
+                started, position, total,
+                modified, content_hash, hash_version
+            )
+            SELECT
+                username, device,
+                podcast, episode,
+                timestamp, guid,
+                action,
+                started, position, total,
+                ?, content_hash, hash_version
+            FROM episode_history
+            WHERE username = ? AND podcast = ? AND episode = ?
+            ORDER BY timestamp DESC, id DESC
+            LIMIT 1
+            ",
+            now,
+            username,
+            podcast,
+            episode,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!("error restoring episode from history: {e:?}");
+            Error::Internal
+        })?;
+    }
+
+    query!(
+        "
+        UPDATE episodes
+        SET hash_version = 0
+        WHERE content_hash = ''
+        ",
+    )
+    .execute(&mut *tx)
+    .await
+    .and(
+        query!(
+            "
+            UPDATE episode_history
+            SET hash_version = 0
+            WHERE content_hash = ''
+            ",
+        )
+        .execute(&mut *tx)
+        .await,
+    )
+    .map_err(|e| {
+        error!("error marking episodes for rehashing: {e:?}");
+        Error::Internal
+    })?;
+
+    rehash(tx).await.map(|_| ())
+}
+
+#[cfg(test)]
+#[async_trait]
+impl super::Fixtures for SqliteStore {
+    async fn insert_episode(&self, username: &str, ep: EpisodeRaw, meta: super::EpisodeMeta) {
+        query!(
+            "
+            INSERT INTO episodes
+            (
+                username, device,
+                podcast, episode,
+                timestamp, guid,
+                action,
+                started, position, total,
+                modified, content_hash, hash_version
+            )
+            VALUES
+            (
+                ?, ?,
+                ?, ?,
+                ?, ?,
+                ?,
+                ?, ?, ?,
+                ?, ?, ?
+            )
+            ",
+            username,
+            ep.device,
+            ep.podcast,
+            ep.episode,
+            ep.timestamp,
+            ep.guid,
+            ep.action,
+            ep.started,
+            ep.position,
+            ep.total,
+            meta.modified,
+            meta.content_hash,
+            meta.hash_version,
+        )
+        .execute(&self.0)
+        .await
+        .unwrap();
+    }
+
+    async fn episode_meta(&self, username: &str) -> Vec<super::EpisodeMeta> {
+        query_as!(
+            super::EpisodeMeta,
+            r#"
+            SELECT modified as "modified: _", content_hash, hash_version
+            FROM episodes
+            WHERE username = ?
+            "#,
+            username
+        )
+        .fetch_all(&self.0)
+        .await
+        .unwrap()
+    }
+
+    async fn set_episode_meta(&self, username: &str, meta: super::EpisodeMeta) {
+        query!(
+            "
+            UPDATE episodes
+            SET modified = ?, content_hash = ?, hash_version = ?
+            WHERE username = ?
+            ",
+            meta.modified,
+            meta.content_hash,
+            meta.hash_version,
+            username
+        )
+        .execute(&self.0)
+        .await
+        .unwrap();
+    }
+
+    async fn insert_subscription(&self, username: &str, device: &str, sub: Subscription) {
+        query!(
+            "
+            INSERT INTO subscriptions
+            (username, device, url, created, deleted)
+            VALUES
+            (?, ?, ?, ?, ?)
+            ",
+            username,
+            device,
+            sub.url,
+            sub.created,
+            sub.deleted,
+        )
+        .execute(&self.0)
+        .await
+        .unwrap();
+    }
+}
@@ -14,3 +14,10 @@ pub struct SubscriptionChangesFromClient {
     pub add: Vec<String>,
     pub remove: Vec<String>,
 }
+
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub url: String,
+    pub created: Timestamp,
+    pub deleted: Option<Timestamp>, // if none, subscription is active
+}
@@ -1,54 +1,183 @@
 use std::{fmt, time};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(transparent)]
-#[derive(sqlx::Type)]
+// Milliseconds since the epoch.
+//
+// gpodder clients only deal in whole seconds, so that's what we send and receive.
+// Sending the floor of our timestamp means a client's next `since` may see a
+// change twice, but never misses one made later in the same second.
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, sqlx::Type)]
 #[sqlx(transparent)]
 pub struct Timestamp(i64);
 
-impl Timestamp {
-    #[cfg(test)]
-    pub fn now() -> Result<Self, time::SystemTimeError> {
-        Ok(Self::from_i64(25))
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Result<Timestamp, time::SystemTimeError>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<Timestamp, time::SystemTimeError> {
+        Timestamp::now()
     }
+}
 
-    #[cfg(not(test))]
+impl Timestamp {
     pub fn now() -> Result<Self, time::SystemTimeError> {
         use std::time::SystemTime;
 
         SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .map(|duration| duration.as_secs() as i64)
+            .map(|duration| duration.as_millis() as i64)
             .map(Self)
     }
 
     #[cfg(test)]
-    pub fn from_i64(i: i64) -> Self {
-        Self(i)
+    pub const fn from_millis(ms: i64) -> Self {
+        Self(ms)
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    pub fn from_secs(secs: i64) -> Self {
+        Self(secs.saturating_mul(1000))
+    }
+
+    pub fn as_secs(&self) -> i64 {
+        self.0.div_euclid(1000)
     }
 
     pub fn zero() -> Self {
         Self(0)
     }
+
+    pub fn days_before(&self, days: u32) -> Self {
+        Self(self.0.saturating_sub(i64::from(days) * 24 * 60 * 60 * 1000))
+    }
+
+    pub fn days_after(&self, days: u32) -> Self {
+        Self(self.0.saturating_add(i64::from(days) * 24 * 60 * 60 * 1000))
+    }
+
+    pub fn to_datetime(self) -> Option<::time::OffsetDateTime> {
+        ::time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(self.0) * 1_000_000).ok()
+    }
+
+    // As in `Last-Modified` and `If-Modified-Since`, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+    pub fn to_http_date(self) -> Option<String> {
+        ::time::OffsetDateTime::from_unix_timestamp(self.as_secs())
+            .ok()?
+            .format(HTTP_DATE)
+            .ok()
+    }
+
+    pub fn from_http_date(s: &str) -> Option<Self> {
+        ::time::PrimitiveDateTime::parse(s, HTTP_DATE)
+            .ok()
+            .map(|when| Self::from_secs(when.assume_utc().unix_timestamp()))
+    }
 }
 
-impl fmt::Display for Timestamp {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.0 == 0 {
-            return write!(fmt, "<epoch>");
+const HTTP_DATE: &[::time::format_description::FormatItem<'static>] = ::time::macros::format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.as_secs())
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        i64::deserialize(deserializer).map(Self::from_secs)
+    }
+}
+
+// Full precision, for `#[serde(with = "...")]` where we're not talking to a gpodder client
+pub mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(t: &Timestamp, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(t.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        i64::deserialize(deserializer).map(Timestamp)
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            t: &Option<Timestamp>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            t.map(|t| t.0).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Timestamp>, D::Error> {
+            Option::<i64>::deserialize(deserializer).map(|t| t.map(Timestamp))
         }
+    }
+}
 
-        use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
+impl fmt::Display for Timestamp {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ::time::format_description::well_known::Rfc3339;
 
-        let formatted = OffsetDateTime::from_unix_timestamp(self.0)
-            .ok()
+        let formatted = self
+            .to_datetime()
             .and_then(|when| when.format(&Rfc3339).ok());
 
         match formatted {
             Some(s) => write!(fmt, "{}", s),
-            None => write!(fmt, "{}", self.0),
+            None => write!(fmt, "{}ms", self.0),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wire_format_is_seconds() {
+        let t = Timestamp::from_millis(1_680_000_000_999);
+
+        assert_eq!(serde_json::to_string(&t).unwrap(), "1680000000");
+        assert_eq!(
+            serde_json::from_str::<Timestamp>("1680000000").unwrap(),
+            Timestamp::from_millis(1_680_000_000_000)
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Timestamp::zero().to_string(), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            Timestamp::from_millis(1_500).to_string(),
+            "1970-01-01T00:00:01.5Z"
+        );
+    }
+    #[test]
+    fn http_date() {
+        let t = Timestamp::from_millis(784_111_777_500);
+
+        let date = t.to_http_date().unwrap();
+        assert_eq!(date, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            Timestamp::from_http_date(&date),
+            Some(Timestamp::from_secs(784_111_777))
+        );
+        assert_eq!(
+            Timestamp::from_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            None
+        );
+    }
+}
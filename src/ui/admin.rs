@@ -0,0 +1,575 @@
+// `/admin`: every user and how much they've synced, the instance's totals and recent
+// failed logins, and forms to create and disable users, reset their passwords, and
+// invite people to register.
+// Pages and forms work as the rest of the UI's, for users `PodSyncAuthed::admin` allows.
+
+use std::{future::Future, sync::Arc};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use warp::{http::StatusCode, reply::Response, Filter, Rejection};
+
+use super::{
+    action, csrf_token, error_page, escape, forms, html, layout, see_other, session, Form, Notice,
+    QueryNotice,
+};
+use crate::limits::MAX_USERNAME;
+use crate::podsync::{
+    self, FailedLogin, InstanceStats, InviteOverview, PodSync, PodSyncAdmin, PodSyncAuthed,
+    UserOverview,
+};
+use crate::time::Timestamp;
+use crate::user::Registration;
+
+#[derive(Deserialize)]
+struct CreateForm {
+    csrf: String,
+    username: String,
+    password: String,
+    // a checkbox, only sent when ticked
+    admin: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UserForm {
+    csrf: String,
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct ResetForm {
+    csrf: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct InviteForm {
+    csrf: String,
+    // as typed, `days` may well be empty, for never
+    uses: String,
+    days: String,
+}
+
+#[derive(Deserialize)]
+struct RevokeForm {
+    csrf: String,
+    code: String,
+}
+
+forms!(CreateForm, UserForm, ResetForm, InviteForm, RevokeForm);
+
+pub(super) fn routes(
+    podsync: Arc<PodSync>,
+    max: u64,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let console = warp::path!("admin")
+        .and(warp::get())
+        .and(session(podsync.clone()))
+        .and(warp::query())
+        .then(
+            |podsync: Option<PodSyncAuthed<true>>, query: QueryNotice| async move {
+                let Some(podsync) = podsync else {
+                    return see_other("/ui/login");
+                };
+                let csrf = csrf_token(podsync.session_id());
+                let notice = query.notice.as_deref().and_then(Notice::from_code);
+
+                let page = async {
+                    let admin = podsync.admin().await?;
+                    render_console(&admin, &csrf, notice).await
+                };
+                match page.await {
+                    Ok(page) => html(StatusCode::OK, page),
+                    Err(e) => error_page(e),
+                }
+            },
+        );
+
+    let create = warp::path!("admin" / "users").and(admin_action(
+        podsync.clone(),
+        max,
+        |admin, form: CreateForm| async move {
+            let created = admin
+                .create_user(&form.username, &form.password, form.admin.is_some())
+                .await;
+
+            match created {
+                Ok(()) => Ok(Notice::UserCreated),
+                Err(podsync::Error::Conflict) => Ok(Notice::UserExists),
+                Err(podsync::Error::BadRequest) => Ok(Notice::InvalidUser),
+                Err(e) => Err(e),
+            }
+        },
+    ));
+
+    let disable = warp::path!("admin" / "users" / "disable").and(admin_action(
+        podsync.clone(),
+        max,
+        |admin, form: UserForm| async move {
+            if form.username == admin.username() {
+                return Ok(Notice::NotYourself);
+            }
+            admin.set_disabled(&form.username, true).await?;
+
+            Ok(Notice::UserDisabled)
+        },
+    ));
+
+    let enable = warp::path!("admin" / "users" / "enable").and(admin_action(
+        podsync.clone(),
+        max,
+        |admin, form: UserForm| async move {
+            admin.set_disabled(&form.username, false).await?;
+
+            Ok(Notice::UserEnabled)
+        },
+    ));
+
+    let reset = warp::path!("admin" / "users" / "password").and(admin_action(
+        podsync.clone(),
+        max,
+        |admin, form: ResetForm| async move {
+            match admin.reset_password(&form.username, &form.password).await {
+                Ok(()) => Ok(Notice::PasswordReset),
+                Err(podsync::Error::BadRequest) => Ok(Notice::InvalidUser),
+                Err(e) => Err(e),
+            }
+        },
+    ));
+
+    let invite = warp::path!("admin" / "invites").and(admin_action(
+        podsync.clone(),
+        max,
+        |admin, form: InviteForm| async move {
+            let uses = form.uses.trim().parse();
+            let days = match form.days.trim() {
+                "" => Ok(None),
+                days => days.parse().map(Some),
+            };
+            let (Ok(uses), Ok(days)) = (uses, days) else {
+                return Ok(Notice::InvalidInvite);
+            };
+
+            match admin.create_invite(uses, days).await {
+                Ok(_) => Ok(Notice::InviteCreated),
+                Err(podsync::Error::BadRequest) => Ok(Notice::InvalidInvite),
+                Err(e) => Err(e),
+            }
+        },
+    ));
+
+    let revoke = warp::path!("admin" / "invites" / "revoke").and(admin_action(
+        podsync,
+        max,
+        |admin, form: RevokeForm| async move {
+            admin.delete_invite(&form.code).await?;
+
+            Ok(Notice::InviteRevoked)
+        },
+    ));
+
+    console
+        .or(create)
+        .unify()
+        .or(disable)
+        .unify()
+        .or(enable)
+        .unify()
+        .or(reset)
+        .unify()
+        .or(invite)
+        .unify()
+        .or(revoke)
+        .unify()
+}
+
+// As `action`, for admins, back to the console with what was done
+fn admin_action<T, F, Fut>(
+    podsync: Arc<PodSync>,
+    max: u64,
+    act: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    T: Form + DeserializeOwned + Send + 'static,
+    F: Fn(PodSyncAdmin, T) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = podsync::Result<Notice>> + Send,
+{
+    action(podsync, max, move |podsync, form: T| {
+        let act = act.clone();
+
+        async move {
+            let admin = podsync.admin().await?;
+            let notice = act(admin, form).await?;
+
+            Ok(notice.redirect("/admin"))
+        }
+    })
+}
+
+fn when(at: Option<Timestamp>) -> String {
+    at.and_then(Timestamp::to_http_date)
+        .unwrap_or_else(|| "never".into())
+}
+
+async fn render_console(
+    admin: &PodSyncAdmin,
+    csrf: &str,
+    notice: Option<Notice>,
+) -> podsync::Result<String> {
+    let users = admin.users().await?;
+    let stats = admin.stats(&users)?;
+    let failed = admin.failed_logins()?;
+    let invites = admin.invites().await?;
+
+    let csrf = format!(
+        r#"<input type="hidden" name="csrf" value="{}">"#,
+        escape(csrf)
+    );
+
+    let notice = notice
+        .map(|notice| {
+            format!(
+                r#"<p class="notice{}">{}</p>"#,
+                if notice.is_error() { " error" } else { "" },
+                notice.message(),
+            )
+        })
+        .unwrap_or_default();
+
+    let users: String = users.iter().map(|user| render_user(user, &csrf)).collect();
+
+    Ok(layout(
+        "Admin",
+        &format!(
+            r#"<header>
+<h1>podsync admin</h1>
+<p>Logged in as <strong>{username}</strong> <a href="/ui">Your devices</a></p>
+<form method="post" action="/ui/logout">{csrf}<button>Log out</button></form>
+</header>
+<main>
+{notice}
+<section>
+<h2>Instance</h2>
+{stats}
+</section>
+<section>
+<h2>Users</h2>
+<table>
+<thead><tr><th>User</th><th>Devices</th><th>Subscriptions</th><th>Episodes</th><th>Last active</th><th></th></tr></thead>
+<tbody>
+{users}</tbody>
+</table>
+</section>
+<section>
+<h2>New user</h2>
+<form method="post" action="/admin/users" class="stack">
+{csrf}
+<label>Username <input name="username" maxlength="{MAX_USERNAME}" autocomplete="off" required></label>
+<label>Password <input type="password" name="password" autocomplete="new-password" required></label>
+<label class="check"><input type="checkbox" name="admin"> Admin</label>
+<button>Create user</button>
+</form>
+</section>
+<section>
+<h2>Invites</h2>
+<p class="meta">{policy}</p>
+{invites}
+<form method="post" action="/admin/invites" class="stack">
+{csrf}
+<label>Users it can register <input type="number" name="uses" value="1" min="1" required></label>
+<label>Expires after (days, blank for never) <input type="number" name="days" value="7" min="0"></label>
+<button>Create invite</button>
+</form>
+</section>
+<section>
+<h2>Recent failed logins</h2>
+{failed}
+</section>
+</main>"#,
+            username = escape(admin.username()),
+            stats = render_stats(&stats),
+            failed = render_failed(&failed),
+            policy = match admin.registration() {
+                Registration::Closed => {
+                    "Registration is closed, so invites can't be used until podsync runs with <code>--registration invite</code>."
+                }
+                Registration::Invite => "People can register with an invite's link or code.",
+                Registration::Open => "Registration is open to anyone, so invites aren't needed.",
+            },
+            invites = render_invites(&invites, &csrf),
+        ),
+    ))
+}
+
+fn render_stats(stats: &InstanceStats) -> String {
+    let stat = |name: &str, value: String| format!("<div><dt>{name}</dt><dd>{value}</dd></div>\n");
+
+    format!(
+        "<dl class=\"stats\">\n{}{}{}{}{}{}{}</dl>",
+        stat("Users", stats.users.to_string()),
+        stat("Active this week", stats.active.to_string()),
+        stat("Admins", stats.admins.to_string()),
+        stat("Disabled", stats.disabled.to_string()),
+        stat("Devices", stats.devices.to_string()),
+        stat("Subscriptions", stats.subscriptions.to_string()),
+        stat("Episodes", stats.episodes.to_string()),
+    )
+}
+
+fn render_user(user: &UserOverview, csrf: &str) -> String {
+    let summary = &user.summary;
+    let username = escape(&summary.username);
+
+    let tags = [(summary.admin, "admin"), (summary.disabled, "disabled")]
+        .into_iter()
+        .filter(|(tagged, _)| *tagged)
+        .map(|(_, tag)| format!(r#" <span class="tag">{tag}</span>"#))
+        .collect::<String>();
+
+    let (toggle, label) = if summary.disabled {
+        ("enable", "Enable")
+    } else {
+        ("disable", "Disable")
+    };
+
+    format!(
+        r#"<tr><td>{username}{tags}</td><td>{devices}</td><td>{subscriptions}</td><td>{episodes}</td><td>{last_active}</td>
+<td><div class="actions">
+<form method="post" action="/admin/users/{toggle}">{csrf}<input type="hidden" name="username" value="{username}"><button>{label}</button></form>
+<form method="post" action="/admin/users/password">{csrf}<input type="hidden" name="username" value="{username}"><input type="password" name="password" autocomplete="new-password" aria-label="New password" required><button>Reset password</button></form>
+</div></td></tr>
+"#,
+        devices = summary.devices,
+        subscriptions = summary.subscriptions,
+        episodes = summary.episodes,
+        last_active = when(user.last_active),
+    )
+}
+
+fn render_invites(invites: &[InviteOverview], csrf: &str) -> String {
+    if invites.is_empty() {
+        return r#"<p class="empty">No invites.</p>"#.into();
+    }
+
+    let rows: String = invites
+        .iter()
+        .map(|InviteOverview { invite, usable }| {
+            let code = escape(&invite.code);
+            let tag = match (usable, invite.used >= invite.max_uses) {
+                (true, _) => "",
+                (false, true) => r#" <span class="tag">used up</span>"#,
+                (false, false) => r#" <span class="tag">expired</span>"#,
+            };
+
+            format!(
+                r#"<tr><td><a href="/ui/register?invite={code}" class="url">{code}</a>{tag}</td><td>{used} / {max_uses}</td><td>{expires}</td><td>{created_by}</td>
+<td><form method="post" action="/admin/invites/revoke">{csrf}<input type="hidden" name="code" value="{code}"><button class="danger">Revoke</button></form></td></tr>
+"#,
+                used = invite.used,
+                max_uses = invite.max_uses,
+                expires = when(invite.expires),
+                created_by = escape(&invite.created_by),
+            )
+        })
+        .collect();
+
+    format!(
+        "<table>\n<thead><tr><th>Code</th><th>Used</th><th>Expires</th><th>Created by</th><th></th></tr></thead>\n<tbody>\n{rows}</tbody>\n</table>"
+    )
+}
+
+fn render_failed(failed: &[FailedLogin]) -> String {
+    if failed.is_empty() {
+        return r#"<p class="empty">None since podsync started.</p>"#.into();
+    }
+
+    let rows: String = failed
+        .iter()
+        .map(|login| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                when(Some(login.at)),
+                escape(&login.username),
+                login.reason,
+            )
+        })
+        .collect();
+
+    format!(
+        "<table>\n<thead><tr><th>When</th><th>Username</th><th>Reason</th></tr></thead>\n<tbody>\n{rows}</tbody>\n</table>"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::SESSION_COOKIE;
+    use super::*;
+    use crate::auth::{self, AuthAttempt};
+    use crate::mock;
+
+    mock::store_tests!(admin_console);
+
+    fn post(path: &str, cookie: &str, body: String) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("cookie", cookie)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+    }
+
+    async fn admin_console(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        for user in ["alice", "bob"] {
+            store.create_user(user, &auth::pwhash(user)).await.unwrap();
+        }
+        store.set_admin("alice", true).await.unwrap();
+
+        let podsync = Arc::new(PodSync::new(store));
+        let filter = super::super::routes(Arc::clone(&podsync), true);
+        let login = |user: &str, pass: &str| {
+            let podsync = Arc::clone(&podsync);
+            let attempt = AuthAttempt::new(user.into(), pass.into());
+            async move { podsync.login(attempt, None).await }
+        };
+
+        let bob = login("bob", "bob").await.unwrap();
+        let bob_session = format!("{SESSION_COOKIE}={}", bob.session_id());
+        let alice = login("alice", "alice").await.unwrap();
+        let session = format!("{SESSION_COOKIE}={}", alice.session_id());
+        let csrf = csrf_token(alice.session_id());
+
+        // only admins are let in
+        let res = warp::test::request()
+            .path("/admin")
+            .header("cookie", &bob_session)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 403);
+
+        let res = post(
+            "/admin/users",
+            &bob_session,
+            format!(
+                "csrf={}&username=eve&password=x",
+                csrf_token(bob.session_id())
+            ),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), 403);
+
+        let res = warp::test::request()
+            .path("/admin")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(std::str::from_utf8(res.body()).unwrap().contains("bob"));
+
+        let created = |body: &str| {
+            post("/admin/users", &session, format!("csrf={csrf}&{body}")).reply(&filter)
+        };
+        let res = created("username=carol.c&password=pw&admin=on").await;
+        assert_eq!(res.headers()["location"], "/admin?notice=user-created");
+        assert!(login("carol.c", "pw")
+            .await
+            .unwrap()
+            .is_admin()
+            .await
+            .unwrap());
+
+        let res = created("username=carol.c&password=pw").await;
+        assert_eq!(res.headers()["location"], "/admin?notice=user-exists");
+        let res = created("username=carol+c%2Fd&password=pw").await;
+        assert_eq!(res.headers()["location"], "/admin?notice=invalid-user");
+
+        // a reset logs the user out, so their apps need the new password
+        let res = post(
+            "/admin/users/password",
+            &session,
+            format!("csrf={csrf}&username=bob&password=new"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/admin?notice=password-reset");
+        assert!(podsync
+            .authenticate(bob.session_id().to_string().parse().unwrap())
+            .await
+            .is_err());
+        login("bob", "new").await.unwrap();
+
+        let res = post(
+            "/admin/users/disable",
+            &session,
+            format!("csrf={csrf}&username=bob"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/admin?notice=user-disabled");
+        assert!(login("bob", "new").await.is_err());
+
+        let res = post(
+            "/admin/users/disable",
+            &session,
+            format!("csrf={csrf}&username=alice"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/admin?notice=not-yourself");
+
+        let res = warp::test::request()
+            .path("/admin")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        let page = std::str::from_utf8(res.body()).unwrap();
+        assert!(page.contains("<td>bob</td><td>disabled</td>"));
+        assert!(page.contains(r#"<span class="tag">disabled</span>"#));
+
+        let res = post(
+            "/admin/users/enable",
+            &session,
+            format!("csrf={csrf}&username=bob"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/admin?notice=user-enabled");
+        login("bob", "new").await.unwrap();
+
+        let invited = |body: &str| {
+            post("/admin/invites", &session, format!("csrf={csrf}&{body}")).reply(&filter)
+        };
+        let res = invited("uses=0&days=").await;
+        assert_eq!(res.headers()["location"], "/admin?notice=invalid-invite");
+        let res = invited("uses=2&days=soon").await;
+        assert_eq!(res.headers()["location"], "/admin?notice=invalid-invite");
+        let res = invited("uses=2&days=").await;
+        assert_eq!(res.headers()["location"], "/admin?notice=invite-created");
+
+        let admin = alice.admin().await.unwrap();
+        let invites = admin.invites().await.unwrap();
+        assert_eq!(invites.len(), 1);
+        let invite = &invites[0].invite;
+        assert_eq!((invite.max_uses, invite.expires), (2, None));
+
+        let res = warp::test::request()
+            .path("/admin")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        let page = std::str::from_utf8(res.body()).unwrap();
+        assert!(page.contains(&format!("/ui/register?invite={}", invite.code)));
+        assert!(page.contains("<td>0 / 2</td><td>never</td><td>alice</td>"));
+
+        let res = post(
+            "/admin/invites/revoke",
+            &session,
+            format!("csrf={csrf}&code={}", invite.code),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/admin?notice=invite-revoked");
+        assert!(admin.invites().await.unwrap().is_empty());
+    }
+}
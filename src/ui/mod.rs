@@ -0,0 +1,1340 @@
+// A small web interface under `/ui`, for people rather than podcast apps: their devices,
+// subscriptions and recent episodes, and their password. Pages are rendered here and the
+// stylesheet's compiled in, so there's nothing to fetch from anywhere else.
+//
+// Forms carry a CSRF token: the session's, or before there is one, a token the login
+// (or registration) page also sets as a cookie, which the form has to match.
+//
+// Admins also get `/admin`, see `admin.rs`.
+
+use std::{convert::Infallible, future::Future, sync::Arc};
+
+use ::time::{ext::NumericalDuration, Duration};
+use cookie::{Cookie, SameSite};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize};
+use sha256::digest;
+use uuid::Uuid;
+use warp::{
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    },
+    hyper::Body,
+    reply::Response,
+    Filter, Rejection,
+};
+
+use crate::auth::{AuthAttempt, SessionId};
+use crate::device::{DeviceAndSub, DeviceUpdate};
+use crate::episode::{Episode, EpisodeAction};
+use crate::limits::{MAX_CAPTION, MAX_USERNAME};
+use crate::podsync::{self, PodSync, PodSyncAuthed};
+use crate::subscription::SubscriptionChangesFromClient;
+use crate::user::Registration;
+
+mod admin;
+
+static STYLE: &str = include_str!("style.css");
+// the API's `sessionid` is only sent to `/api`, this one's for `/ui` and `/admin`
+static SESSION_COOKIE: &str = "podsync-ui";
+static LOGIN_CSRF_COOKIE: &str = "podsync-csrf";
+const RECENT_EPISODES: usize = 50;
+
+#[derive(Deserialize)]
+struct LoginForm {
+    csrf: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RegisterForm {
+    csrf: String,
+    username: String,
+    password: String,
+    confirm: String,
+    // only asked for under `Registration::Invite`
+    invite: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LogoutForm {
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct RenameForm {
+    csrf: String,
+    device: String,
+    caption: String,
+}
+
+#[derive(Deserialize)]
+struct DeleteForm {
+    csrf: String,
+    device: String,
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeForm {
+    csrf: String,
+    device: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    csrf: String,
+    current: String,
+    new: String,
+    confirm: String,
+}
+
+trait Form {
+    fn csrf(&self) -> &str;
+}
+
+macro_rules! forms {
+    ($($form:ident),*) => {
+        $(
+            impl Form for $form {
+                fn csrf(&self) -> &str {
+                    &self.csrf
+                }
+            }
+        )*
+    };
+}
+pub(super) use forms;
+
+forms!(
+    LogoutForm,
+    RenameForm,
+    DeleteForm,
+    UnsubscribeForm,
+    PasswordForm
+);
+
+#[derive(Deserialize)]
+struct QueryNotice {
+    notice: Option<String>,
+}
+
+// an invite link's code, to fill in
+#[derive(Deserialize)]
+struct QueryInvite {
+    invite: Option<String>,
+}
+
+// what an action did, shown on the page it redirects to. Only these codes are shown,
+// so nothing a link says ends up on the page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notice {
+    Renamed,
+    Deleted,
+    Unsubscribed,
+    PasswordChanged,
+    WrongPassword,
+    PasswordMismatch,
+    EmptyPassword,
+    UserCreated,
+    UserExists,
+    InvalidUser,
+    UserDisabled,
+    UserEnabled,
+    PasswordReset,
+    NotYourself,
+    InviteCreated,
+    InviteRevoked,
+    InvalidInvite,
+}
+
+impl Notice {
+    const ALL: [Self; 17] = [
+        Self::Renamed,
+        Self::Deleted,
+        Self::Unsubscribed,
+        Self::PasswordChanged,
+        Self::WrongPassword,
+        Self::PasswordMismatch,
+        Self::EmptyPassword,
+        Self::UserCreated,
+        Self::UserExists,
+        Self::InvalidUser,
+        Self::UserDisabled,
+        Self::UserEnabled,
+        Self::PasswordReset,
+        Self::NotYourself,
+        Self::InviteCreated,
+        Self::InviteRevoked,
+        Self::InvalidInvite,
+    ];
+
+    fn code(self) -> &'static str {
+        match self {
+            Self::Renamed => "renamed",
+            Self::Deleted => "deleted",
+            Self::Unsubscribed => "unsubscribed",
+            Self::PasswordChanged => "password-changed",
+            Self::WrongPassword => "wrong-password",
+            Self::PasswordMismatch => "password-mismatch",
+            Self::EmptyPassword => "empty-password",
+            Self::UserCreated => "user-created",
+            Self::UserExists => "user-exists",
+            Self::InvalidUser => "invalid-user",
+            Self::UserDisabled => "user-disabled",
+            Self::UserEnabled => "user-enabled",
+            Self::PasswordReset => "password-reset",
+            Self::NotYourself => "not-yourself",
+            Self::InviteCreated => "invite-created",
+            Self::InviteRevoked => "invite-revoked",
+            Self::InvalidInvite => "invalid-invite",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::Renamed => "Device renamed.",
+            Self::Deleted => "Device deleted.",
+            Self::Unsubscribed => "Unsubscribed.",
+            Self::PasswordChanged => "Password changed.",
+            Self::WrongPassword => "Your current password was wrong, so it's unchanged.",
+            Self::PasswordMismatch => "The new passwords didn't match, so it's unchanged.",
+            Self::EmptyPassword => "Your new password can't be empty.",
+            Self::UserCreated => "User created.",
+            Self::UserExists => "There's already a user by that name.",
+            Self::InvalidUser => {
+                "Usernames are a-z, A-Z, digits, _ . + and -, and passwords can't be empty."
+            }
+            Self::UserDisabled => "User disabled, and logged out.",
+            Self::UserEnabled => "User enabled.",
+            Self::PasswordReset => "Password reset, and the user logged out.",
+            Self::NotYourself => "You can't disable yourself.",
+            Self::InviteCreated => "Invite created.",
+            Self::InviteRevoked => "Invite revoked.",
+            Self::InvalidInvite => "Invites are for at least one user, for a whole number of days.",
+        }
+    }
+
+    fn is_error(self) -> bool {
+        matches!(
+            self,
+            Self::WrongPassword
+                | Self::PasswordMismatch
+                | Self::EmptyPassword
+                | Self::UserExists
+                | Self::InvalidUser
+                | Self::NotYourself
+                | Self::InvalidInvite
+        )
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|notice| notice.code() == code)
+    }
+
+    // back to `page`, to show the notice
+    fn redirect(self, page: &str) -> Response {
+        see_other(&format!("{page}?notice={}", self.code()))
+    }
+}
+
+pub fn routes(
+    podsync: Arc<PodSync>,
+    secure: bool,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let max = podsync.limits().body;
+    let registration = podsync.registration();
+    let can_register = registration != Registration::Closed;
+
+    let style = warp::path!("ui" / "style.css").and(warp::get()).map(|| {
+        let mut res = Response::new(Body::from(STYLE));
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/css; charset=utf-8"),
+        );
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=3600"),
+        );
+        res
+    });
+
+    let dashboard = warp::path!("ui")
+        .and(warp::get())
+        .and(session(podsync.clone()))
+        .and(warp::query())
+        .then(
+            |podsync: Option<PodSyncAuthed<true>>, query: QueryNotice| async move {
+                let Some(podsync) = podsync else {
+                    return see_other("/ui/login");
+                };
+                let notice = query.notice.as_deref().and_then(Notice::from_code);
+
+                match render_dashboard(&podsync, notice).await {
+                    Ok(page) => html(StatusCode::OK, page),
+                    Err(e) => error_page(e),
+                }
+            },
+        );
+
+    let login_page = warp::path!("ui" / "login")
+        .and(warp::get())
+        .and(session(podsync.clone()))
+        .map(move |podsync: Option<PodSyncAuthed<true>>| {
+            if podsync.is_some() {
+                return see_other("/ui");
+            }
+
+            let (token, cookie) = login_csrf(secure);
+            let mut res = html(StatusCode::OK, render_login(&token, None, can_register));
+            set_cookie(&mut res, cookie);
+            res
+        });
+
+    let login = warp::path!("ui" / "login")
+        .and(warp::post())
+        .and(warp::cookie::optional::<String>(LOGIN_CSRF_COOKIE))
+        .and(form::<LoginForm>(max))
+        .then({
+            let podsync = Arc::clone(&podsync);
+            move |token: Option<String>, form: LoginForm| {
+                let podsync = Arc::clone(&podsync);
+
+                async move {
+                    if !token.is_some_and(|token| same_token(&token, &form.csrf)) {
+                        warn!("login form with a missing or mismatched csrf token");
+                        return forbidden();
+                    }
+
+                    let attempt = AuthAttempt::new(form.username, form.password);
+                    match podsync.login(attempt, None).await {
+                        Ok(podsync) => logged_in(&podsync, secure),
+                        Err(podsync::Error::Unauthorized) => html(
+                            StatusCode::UNAUTHORIZED,
+                            render_login(
+                                &form.csrf,
+                                Some("Wrong username or password."),
+                                can_register,
+                            ),
+                        ),
+                        Err(e) => error_page(e),
+                    }
+                }
+            }
+        });
+
+    let register_page = warp::path!("ui" / "register")
+        .and(registration_allowed(registration))
+        .and(warp::get())
+        .and(session(podsync.clone()))
+        .and(warp::query())
+        .map(
+            move |podsync: Option<PodSyncAuthed<true>>, query: QueryInvite| {
+                if podsync.is_some() {
+                    return see_other("/ui");
+                }
+
+                let (token, cookie) = login_csrf(secure);
+                let invite = query.invite.unwrap_or_default();
+                let page = render_register(&token, registration, "", &invite, None);
+                let mut res = html(StatusCode::OK, page);
+                set_cookie(&mut res, cookie);
+                res
+            },
+        );
+
+    let register = warp::path!("ui" / "register")
+        .and(registration_allowed(registration))
+        .and(warp::post())
+        .and(warp::cookie::optional::<String>(LOGIN_CSRF_COOKIE))
+        .and(form::<RegisterForm>(max))
+        .then({
+            let podsync = Arc::clone(&podsync);
+            move |token: Option<String>, form: RegisterForm| {
+                let podsync = Arc::clone(&podsync);
+
+                async move {
+                    if !token.is_some_and(|token| same_token(&token, &form.csrf)) {
+                        warn!("registration form with a missing or mismatched csrf token");
+                        return forbidden();
+                    }
+
+                    let invite = form.invite.as_deref().map(str::trim).unwrap_or_default();
+                    let retry = |status, error| {
+                        let page = render_register(
+                            &form.csrf,
+                            registration,
+                            &form.username,
+                            invite,
+                            Some(error),
+                        );
+                        html(status, page)
+                    };
+
+                    if form.password != form.confirm {
+                        return retry(StatusCode::BAD_REQUEST, "The passwords didn't match.");
+                    }
+
+                    let invite = Some(invite).filter(|invite| !invite.is_empty());
+                    match podsync
+                        .register(&form.username, &form.password, invite)
+                        .await
+                    {
+                        Ok(()) => {}
+                        Err(podsync::Error::Forbidden) => {
+                            return retry(
+                                StatusCode::FORBIDDEN,
+                                "That invite code is unknown, used up or expired.",
+                            )
+                        }
+                        Err(podsync::Error::Conflict) => {
+                            return retry(StatusCode::CONFLICT, Notice::UserExists.message())
+                        }
+                        Err(podsync::Error::BadRequest) => {
+                            return retry(StatusCode::BAD_REQUEST, Notice::InvalidUser.message())
+                        }
+                        Err(e) => return error_page(e),
+                    }
+
+                    // straight in, rather than back to the login page
+                    let attempt = AuthAttempt::new(form.username, form.password);
+                    match podsync.login(attempt, None).await {
+                        Ok(podsync) => logged_in(&podsync, secure),
+                        Err(e) => error_page(e),
+                    }
+                }
+            }
+        });
+
+    let logout = warp::path!("ui" / "logout").and(action(
+        podsync.clone(),
+        max,
+        move |podsync, _: LogoutForm| async move {
+            podsync.logout().await?;
+
+            let mut res = see_other("/ui/login");
+            set_cookie(
+                &mut res,
+                session_cookie(String::new(), secure, Duration::ZERO),
+            );
+            Ok(res)
+        },
+    ));
+
+    let rename = warp::path!("ui" / "device" / "rename").and(action(
+        podsync.clone(),
+        max,
+        |podsync, form: RenameForm| async move {
+            let update = DeviceUpdate {
+                caption: Some(form.caption),
+                r#type: None,
+            };
+            podsync.update_device(&form.device, update).await?;
+
+            Ok(Notice::Renamed.redirect("/ui"))
+        },
+    ));
+
+    let delete = warp::path!("ui" / "device" / "delete").and(action(
+        podsync.clone(),
+        max,
+        |podsync, form: DeleteForm| async move {
+            podsync.delete_device(&form.device).await?;
+
+            Ok(Notice::Deleted.redirect("/ui"))
+        },
+    ));
+
+    let unsubscribe = warp::path!("ui" / "unsubscribe").and(action(
+        podsync.clone(),
+        max,
+        |podsync, form: UnsubscribeForm| async move {
+            let changes = SubscriptionChangesFromClient {
+                add: vec![],
+                remove: vec![form.url],
+            };
+            podsync.update_subscriptions(&form.device, changes).await?;
+
+            Ok(Notice::Unsubscribed.redirect("/ui"))
+        },
+    ));
+
+    let password = warp::path!("ui" / "password").and(action(
+        podsync.clone(),
+        max,
+        |podsync, form: PasswordForm| async move {
+            if form.new != form.confirm {
+                return Ok(Notice::PasswordMismatch.redirect("/ui"));
+            }
+
+            let notice = match podsync.change_password(&form.current, &form.new).await {
+                Ok(()) => Notice::PasswordChanged,
+                Err(podsync::Error::Unauthorized) => Notice::WrongPassword,
+                Err(podsync::Error::BadRequest) => Notice::EmptyPassword,
+                Err(e) => return Err(e),
+            };
+            Ok(notice.redirect("/ui"))
+        },
+    ));
+
+    style
+        .or(dashboard)
+        .unify()
+        .or(login_page)
+        .unify()
+        .or(login)
+        .unify()
+        .or(register_page)
+        .unify()
+        .or(register)
+        .unify()
+        .or(logout)
+        .unify()
+        .or(rename)
+        .unify()
+        .or(delete)
+        .unify()
+        .or(unsubscribe)
+        .unify()
+        .or(password)
+        .unify()
+        .or(admin::routes(podsync, max))
+        .unify()
+        .map(|mut res: Response| {
+            security_headers(res.headers_mut());
+            res
+        })
+}
+
+// The logged in user, if the session cookie's still good
+fn session(
+    podsync: Arc<PodSync>,
+) -> impl Filter<Extract = (Option<PodSyncAuthed<true>>,), Error = Infallible> + Clone {
+    warp::cookie::optional::<String>(SESSION_COOKIE).then(move |cookie: Option<String>| {
+        let podsync = Arc::clone(&podsync);
+
+        async move {
+            let session_id = cookie?.parse::<SessionId>().ok()?;
+
+            podsync
+                .authenticate(session_id)
+                .await
+                .ok()
+                .map(PodSyncAuthed::for_session_user)
+        }
+    })
+}
+
+// `/ui/register` isn't there at all when registration's closed. Checked before the
+// method, so the other method's route doesn't make it a 405.
+fn registration_allowed(
+    registration: Registration,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            match registration {
+                Registration::Closed => Err(warp::reject::not_found()),
+                Registration::Invite | Registration::Open => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+fn form<T: DeserializeOwned + Send + 'static>(
+    max: u64,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(max).and(warp::body::form())
+}
+
+// A form posted by a logged in user, with their session's token, redirecting once done
+fn action<T, F, Fut>(
+    podsync: Arc<PodSync>,
+    max: u64,
+    act: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    T: Form + DeserializeOwned + Send + 'static,
+    F: Fn(PodSyncAuthed<true>, T) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = podsync::Result<Response>> + Send,
+{
+    warp::post().and(session(podsync)).and(form::<T>(max)).then(
+        move |podsync: Option<PodSyncAuthed<true>>, form: T| {
+            let act = act.clone();
+
+            async move {
+                let Some(podsync) = podsync else {
+                    return see_other("/ui/login");
+                };
+
+                if !same_token(&csrf_token(podsync.session_id()), form.csrf()) {
+                    warn!("{} sent a form with a bad csrf token", podsync.username());
+                    return forbidden();
+                }
+
+                act(podsync, form).await.unwrap_or_else(error_page)
+            }
+        },
+    )
+}
+
+fn csrf_token(session_id: &SessionId) -> String {
+    digest(format!("podsync-csrf:{session_id}"))
+}
+
+// in constant time, so a guess can't be refined by timing the response
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// a token for the login and registration forms, and the cookie it has to match
+fn login_csrf(secure: bool) -> (String, Cookie<'static>) {
+    let token = Uuid::new_v4().simple().to_string();
+    let cookie = Cookie::build(LOGIN_CSRF_COOKIE, token.clone())
+        .secure(secure)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .path("/ui")
+        .finish();
+
+    (token, cookie)
+}
+
+fn logged_in(podsync: &PodSyncAuthed<true>, secure: bool) -> Response {
+    let mut res = see_other("/ui");
+    set_cookie(
+        &mut res,
+        session_cookie(podsync.session_id().to_string(), secure, 2.weeks()),
+    );
+    res
+}
+
+fn session_cookie(value: String, secure: bool, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, value)
+        .secure(secure)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(max_age)
+        .path("/")
+        .finish()
+}
+
+fn set_cookie(res: &mut Response, cookie: Cookie) {
+    if let Ok(cookie) = HeaderValue::from_str(&cookie.to_string()) {
+        res.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+}
+
+fn security_headers(headers: &mut HeaderMap) {
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(
+            "default-src 'none'; style-src 'self'; form-action 'self'; frame-ancestors 'none'",
+        ),
+    );
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::REFERRER_POLICY,
+        HeaderValue::from_static("no-referrer"),
+    );
+    headers
+        .entry(header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static("no-store"));
+}
+
+fn html(status: StatusCode, page: String) -> Response {
+    let mut res = Response::new(Body::from(page));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    res
+}
+
+fn see_other(location: &str) -> Response {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::SEE_OTHER;
+    if let Ok(location) = HeaderValue::from_str(location) {
+        res.headers_mut().insert(header::LOCATION, location);
+    }
+    res
+}
+
+fn forbidden() -> Response {
+    html(
+        StatusCode::FORBIDDEN,
+        layout(
+            "Forbidden",
+            r#"<main class="narrow"><h1>Forbidden</h1>
+<p>This form has expired. Please <a href="/ui">reload the page</a> and try again.</p></main>"#,
+        ),
+    )
+}
+
+fn error_page(e: podsync::Error) -> Response {
+    let status: StatusCode = e.into();
+    let reason = status.canonical_reason().unwrap_or("Error");
+
+    html(
+        status,
+        layout(
+            reason,
+            &format!(
+                r#"<main class="narrow"><h1>{reason}</h1>
+<p>That didn't work. <a href="/ui">Back to your devices</a>.</p></main>"#
+            ),
+        ),
+    )
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// seconds, as `m:ss` or `h:mm:ss`
+fn clock(secs: i64) -> String {
+    let secs = secs.max(0);
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if h > 0 {
+        format!("{h}:{m:02}:{s:02}")
+    } else {
+        format!("{m}:{s:02}")
+    }
+}
+
+fn layout(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title} · podsync</title>
+<link rel="stylesheet" href="/ui/style.css">
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title),
+    )
+}
+
+fn render_login(csrf: &str, error: Option<&str>, can_register: bool) -> String {
+    let error = error
+        .map(|e| format!(r#"<p class="notice error">{}</p>"#, escape(e)))
+        .unwrap_or_default();
+    let register = if can_register {
+        r#"<p class="meta">New here? <a href="/ui/register">Create an account</a>.</p>"#
+    } else {
+        ""
+    };
+
+    layout(
+        "Log in",
+        &format!(
+            r#"<main class="narrow">
+<h1>podsync</h1>
+{error}
+<form method="post" action="/ui/login" class="stack">
+<input type="hidden" name="csrf" value="{csrf}">
+<label>Username <input name="username" autocomplete="username" required autofocus></label>
+<label>Password <input type="password" name="password" autocomplete="current-password" required></label>
+<button>Log in</button>
+</form>
+{register}
+</main>"#,
+            csrf = escape(csrf),
+        ),
+    )
+}
+
+fn render_register(
+    csrf: &str,
+    registration: Registration,
+    username: &str,
+    invite: &str,
+    error: Option<&str>,
+) -> String {
+    let error = error
+        .map(|e| format!(r#"<p class="notice error">{}</p>"#, escape(e)))
+        .unwrap_or_default();
+    let invite = match registration {
+        Registration::Invite => format!(
+            r#"<label>Invite code <input name="invite" value="{}" autocomplete="off" required></label>"#,
+            escape(invite)
+        ),
+        Registration::Closed | Registration::Open => String::new(),
+    };
+
+    layout(
+        "Create an account",
+        &format!(
+            r#"<main class="narrow">
+<h1>Create an account</h1>
+{error}
+<form method="post" action="/ui/register" class="stack">
+<input type="hidden" name="csrf" value="{csrf}">
+{invite}
+<label>Username <input name="username" value="{username}" maxlength="{MAX_USERNAME}" autocomplete="username" required autofocus></label>
+<label>Password <input type="password" name="password" autocomplete="new-password" required></label>
+<label>Confirm password <input type="password" name="confirm" autocomplete="new-password" required></label>
+<button>Create account</button>
+</form>
+<p class="meta">Usernames are a-z, A-Z, digits, _ . + and -, as your apps will need it in their URLs. Already have an account? <a href="/ui/login">Log in</a>.</p>
+</main>"#,
+            csrf = escape(csrf),
+            username = escape(username),
+        ),
+    )
+}
+
+async fn render_dashboard(
+    podsync: &PodSyncAuthed<true>,
+    notice: Option<Notice>,
+) -> podsync::Result<String> {
+    let mut devices = podsync.devices().await?;
+    let mut subscriptions = podsync.device_subscriptions().await?;
+    let episodes = podsync.recent_episodes(RECENT_EPISODES).await?;
+    let admin_link = if podsync.is_admin().await? {
+        r#"<a href="/admin">Admin</a>"#
+    } else {
+        ""
+    };
+
+    // subscriptions may have outlived their device's entry, they're still worth seeing
+    for id in subscriptions.keys() {
+        if !devices.iter().any(|device| &device.id == id) {
+            devices.push(DeviceAndSub {
+                id: id.clone(),
+                caption: String::new(),
+                r#type: Default::default(),
+                subscriptions: 0,
+            });
+        }
+    }
+
+    let csrf = format!(
+        r#"<input type="hidden" name="csrf" value="{}">"#,
+        escape(&csrf_token(podsync.session_id()))
+    );
+
+    let notice = notice
+        .map(|notice| {
+            format!(
+                r#"<p class="notice{}">{}</p>"#,
+                if notice.is_error() { " error" } else { "" },
+                notice.message(),
+            )
+        })
+        .unwrap_or_default();
+
+    let devices = if devices.is_empty() {
+        r#"<p class="empty">No devices have synced yet.</p>"#.to_string()
+    } else {
+        devices
+            .iter()
+            .map(|device| {
+                let urls = subscriptions.remove(&device.id).unwrap_or_default();
+                render_device(device, &urls, &csrf)
+            })
+            .collect()
+    };
+
+    let episodes = if episodes.is_empty() {
+        r#"<p class="empty">No episode actions yet.</p>"#.to_string()
+    } else {
+        format!(
+            r#"<table>
+<thead><tr><th>Episode</th><th>Action</th><th>Progress</th><th>When</th></tr></thead>
+<tbody>
+{}</tbody>
+</table>"#,
+            episodes.iter().map(render_episode).collect::<String>()
+        )
+    };
+
+    Ok(layout(
+        "Your devices",
+        &format!(
+            r#"<header>
+<h1>podsync</h1>
+<p>Logged in as <strong>{username}</strong> {admin_link}</p>
+<form method="post" action="/ui/logout">{csrf}<button>Log out</button></form>
+</header>
+<main>
+{notice}
+<section>
+<h2>Devices</h2>
+{devices}
+</section>
+<section>
+<h2>Recent episodes</h2>
+{episodes}
+</section>
+<section>
+<h2>Password</h2>
+<form method="post" action="/ui/password" class="stack">
+{csrf}
+<label>Current password <input type="password" name="current" autocomplete="current-password" required></label>
+<label>New password <input type="password" name="new" autocomplete="new-password" required></label>
+<label>Confirm new password <input type="password" name="confirm" autocomplete="new-password" required></label>
+<button>Change password</button>
+</form>
+</section>
+</main>"#,
+            username = escape(podsync.username()),
+        ),
+    ))
+}
+
+fn render_device(device: &DeviceAndSub, urls: &[String], csrf: &str) -> String {
+    let id = escape(&device.id);
+    let name = if device.caption.is_empty() {
+        id.clone()
+    } else {
+        escape(&device.caption)
+    };
+
+    let subscriptions = if urls.is_empty() {
+        r#"<p class="empty">No subscriptions.</p>"#.to_string()
+    } else {
+        let items: String = urls
+            .iter()
+            .map(|url| {
+                let url = escape(url);
+                format!(
+                    r#"<li><span class="url">{url}</span>
+<form method="post" action="/ui/unsubscribe">{csrf}<input type="hidden" name="device" value="{id}"><input type="hidden" name="url" value="{url}"><button>Unsubscribe</button></form></li>
+"#
+                )
+            })
+            .collect();
+        format!("<ul>\n{items}</ul>")
+    };
+
+    format!(
+        r#"<article class="device">
+<h3>{name}</h3>
+<p class="meta">{id} · {kind} · {count} subscriptions</p>
+<div class="actions">
+<form method="post" action="/ui/device/rename">{csrf}<input type="hidden" name="device" value="{id}"><input name="caption" value="{caption}" maxlength="{MAX_CAPTION}" aria-label="Name"><button>Rename</button></form>
+<form method="post" action="/ui/device/delete">{csrf}<input type="hidden" name="device" value="{id}"><button class="danger">Delete device and its subscriptions</button></form>
+</div>
+<details>
+<summary>Subscriptions</summary>
+{subscriptions}
+</details>
+</article>
+"#,
+        kind = device.r#type.as_str(),
+        count = urls.len(),
+        caption = escape(&device.caption),
+    )
+}
+
+fn render_episode(episode: &Episode) -> String {
+    let (action, progress) = match &episode.action {
+        EpisodeAction::New => ("New".to_string(), String::new()),
+        EpisodeAction::Download => ("Downloaded".to_string(), String::new()),
+        EpisodeAction::Play {
+            position, total, ..
+        } => (
+            "Played".to_string(),
+            match total {
+                Some(total) if *total > 0 => format!(
+                    r#"<progress value="{}" max="{total}"></progress> {} / {}"#,
+                    position.clamp(&0, total),
+                    clock(*position),
+                    clock(*total),
+                ),
+                _ => clock(*position),
+            },
+        ),
+        EpisodeAction::Delete => ("Deleted".to_string(), String::new()),
+        EpisodeAction::Flattr => ("Flattred".to_string(), String::new()),
+        EpisodeAction::Other(action) => (escape(action), String::new()),
+    };
+
+    let when = episode
+        .timestamp
+        .as_ref()
+        .map(|time| time.canonical().replacen('T', " ", 1))
+        .unwrap_or_default();
+
+    format!(
+        r#"<tr><td><span class="episode">{episode}</span><span class="podcast">{podcast}</span></td><td>{action}</td><td>{progress}</td><td>{when}</td></tr>
+"#,
+        episode = escape(&episode.episode),
+        podcast = escape(&episode.podcast),
+        when = escape(&when),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth;
+    use crate::episode::{EpisodeActionRaw, EpisodeRaw, Time};
+    use crate::mock;
+
+    mock::store_tests!(login, dashboard_actions, register);
+
+    fn cookie(res: &warp::http::Response<warp::hyper::body::Bytes>, name: &str) -> String {
+        res.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|cookie| Cookie::parse(cookie.to_str().unwrap().to_string()).unwrap())
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| cookie.value().to_string())
+            .expect("cookie")
+    }
+
+    fn post(path: &str, cookie: &str, body: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("cookie", cookie)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+    }
+
+    async fn login(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+        let filter = routes(Arc::new(PodSync::new(store)), true);
+
+        // logged out, we're sent to the login page
+        let res = warp::test::request().path("/ui").reply(&filter).await;
+        assert_eq!(res.status(), 303);
+        assert_eq!(res.headers()["location"], "/ui/login");
+        assert!(res.headers().contains_key("content-security-policy"));
+
+        let res = warp::test::request().path("/ui/login").reply(&filter).await;
+        assert_eq!(res.status(), 200);
+        let token = cookie(&res, LOGIN_CSRF_COOKIE);
+        let csrf = format!("{LOGIN_CSRF_COOKIE}={token}");
+
+        // the form's token has to match the cookie's
+        let res = post("/ui/login", &csrf, "csrf=other&username=bob&password=abc")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 403);
+
+        let res = post(
+            "/ui/login",
+            &csrf,
+            &format!("csrf={token}&username=bob&password=wrong"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), 401);
+
+        let res = post(
+            "/ui/login",
+            &csrf,
+            &format!("csrf={token}&username=bob&password=abc"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), 303);
+        assert_eq!(res.headers()["location"], "/ui");
+        let session = format!("{SESSION_COOKIE}={}", cookie(&res, SESSION_COOKIE));
+
+        let res = warp::test::request()
+            .path("/ui")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(std::str::from_utf8(res.body()).unwrap().contains("bob"));
+
+        // and logging out ends the session
+        let session_id: SessionId = cookie_value(&session).parse().unwrap();
+        let body = format!("csrf={}", csrf_token(&session_id));
+        let res = post("/ui/logout", &session, &body).reply(&filter).await;
+        assert_eq!(res.status(), 303);
+        assert_eq!(res.headers()["location"], "/ui/login");
+
+        let res = warp::test::request()
+            .path("/ui")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 303);
+    }
+
+    fn cookie_value(cookie: &str) -> &str {
+        cookie.split_once('=').unwrap().1
+    }
+
+    async fn dashboard_actions(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("bob", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+
+        let url = "http://example.com/feed?a=1&b=2";
+        let changes = SubscriptionChangesFromClient {
+            add: vec![url.into()],
+            remove: vec![],
+        };
+        store
+            .update_subscriptions("bob", "phone", &changes, mock::NOW)
+            .await
+            .unwrap();
+        store
+            .update_episodes(
+                "bob",
+                vec![EpisodeRaw {
+                    podcast: url.into(),
+                    episode: "http://example.com/ep<1>.mp3".into(),
+                    timestamp: Time::from_unix_millis(1_677_664_800_000),
+                    guid: None,
+                    action: EpisodeActionRaw::Play,
+                    started: Some(0),
+                    position: Some(90),
+                    total: Some(3600),
+                    device: Some("phone".into()),
+                    modified: None,
+                }],
+                mock::NOW,
+            )
+            .await
+            .unwrap();
+
+        let podsync = Arc::new(PodSync::new(Arc::clone(&store)));
+        let filter = routes(Arc::clone(&podsync), true);
+        let authed = podsync
+            .login(AuthAttempt::new("bob".into(), "abc".into()), None)
+            .await
+            .unwrap();
+        let session = format!("{SESSION_COOKIE}={}", authed.session_id());
+        let csrf = csrf_token(authed.session_id());
+
+        let res = warp::test::request()
+            .path("/ui")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        let page = std::str::from_utf8(res.body()).unwrap();
+        assert!(page.contains("http://example.com/feed?a=1&amp;b=2"));
+        assert!(page.contains("ep&lt;1&gt;.mp3"));
+        assert!(page.contains(r#"<progress value="90" max="3600"></progress> 1:30 / 1:00:00"#));
+
+        let form_url = "http%3A%2F%2Fexample.com%2Ffeed%3Fa%3D1%26b%3D2";
+
+        // a form without the session's token does nothing
+        let res = post(
+            "/ui/unsubscribe",
+            &session,
+            &format!("csrf=nonsense&device=phone&url={form_url}"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(authed.device_subscriptions().await.unwrap().len(), 1);
+
+        let res = post(
+            "/ui/unsubscribe",
+            &session,
+            &format!("csrf={csrf}&device=phone&url={form_url}"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), 303);
+        assert_eq!(res.headers()["location"], "/ui?notice=unsubscribed");
+        assert!(authed.device_subscriptions().await.unwrap().is_empty());
+
+        let res = post(
+            "/ui/device/rename",
+            &session,
+            &format!("csrf={csrf}&device=phone&caption=Pixel"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/ui?notice=renamed");
+        assert_eq!(authed.devices().await.unwrap()[0].caption, "Pixel");
+
+        let res = post(
+            "/ui/device/delete",
+            &session,
+            &format!("csrf={csrf}&device=phone"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/ui?notice=deleted");
+        assert!(authed.devices().await.unwrap().is_empty());
+
+        let res = post(
+            "/ui/password",
+            &session,
+            &format!("csrf={csrf}&current=wrong&new=xyz&confirm=xyz"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/ui?notice=wrong-password");
+
+        let res = post(
+            "/ui/password",
+            &session,
+            &format!("csrf={csrf}&current=abc&new=xyz&confirm=xzy"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/ui?notice=password-mismatch");
+
+        let res = post(
+            "/ui/password",
+            &session,
+            &format!("csrf={csrf}&current=abc&new=xyz&confirm=xyz"),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.headers()["location"], "/ui?notice=password-changed");
+
+        assert!(podsync
+            .login(AuthAttempt::new("bob".into(), "abc".into()), None)
+            .await
+            .is_err());
+        podsync
+            .login(AuthAttempt::new("bob".into(), "xyz".into()), None)
+            .await
+            .unwrap();
+    }
+
+    async fn register(backend: mock::Backend) {
+        let (store, _) = mock::create_store(backend).await;
+        store
+            .create_user("alice", &auth::pwhash("abc"))
+            .await
+            .unwrap();
+        store.set_admin("alice", true).await.unwrap();
+
+        // closed, there's no page, nor a link to it
+        let podsync = PodSync::new(Arc::clone(&store));
+        let filter = routes(Arc::new(podsync), true);
+        let res = warp::test::request()
+            .path("/ui/register")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 404);
+        let res = warp::test::request().path("/ui/login").reply(&filter).await;
+        assert!(!std::str::from_utf8(res.body())
+            .unwrap()
+            .contains("/ui/register"));
+
+        let podsync = Arc::new(PodSync::new(store).with_registration(Registration::Invite));
+        let filter = routes(Arc::clone(&podsync), true);
+        let res = warp::test::request().path("/ui/login").reply(&filter).await;
+        assert!(std::str::from_utf8(res.body())
+            .unwrap()
+            .contains(r#"<a href="/ui/register">"#));
+
+        let res = warp::test::request()
+            .path("/ui/register?invite=c%22de")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(std::str::from_utf8(res.body())
+            .unwrap()
+            .contains(r#"name="invite" value="c&quot;de""#));
+        let token = cookie(&res, LOGIN_CSRF_COOKIE);
+        let csrf = format!("{LOGIN_CSRF_COOKIE}={token}");
+
+        let registered = |body: &str| {
+            post("/ui/register", &csrf, &format!("csrf={token}&{body}")).reply(&filter)
+        };
+
+        let res = post(
+            "/ui/register",
+            &csrf,
+            "csrf=other&username=bob&password=a&confirm=a&invite=x",
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), 403);
+
+        let res = registered("username=bob&password=a&confirm=a&invite=nonsense").await;
+        assert_eq!(res.status(), 403);
+        assert!(std::str::from_utf8(res.body())
+            .unwrap()
+            .contains("unknown, used up or expired"));
+
+        let admin = podsync
+            .login(AuthAttempt::new("alice".into(), "abc".into()), None)
+            .await
+            .unwrap()
+            .admin()
+            .await
+            .unwrap();
+        let invite = admin.create_invite(1, Some(7)).await.unwrap();
+
+        let res = registered(&format!(
+            "username=bob&password=a&confirm=b&invite={}",
+            invite.code
+        ))
+        .await;
+        assert_eq!(res.status(), 400);
+
+        let res = registered(&format!(
+            "username=bob%2Fx&password=a&confirm=a&invite={}",
+            invite.code
+        ))
+        .await;
+        assert_eq!(res.status(), 400);
+
+        // registering logs straight in
+        let res = registered(&format!(
+            "username=bob&password=a&confirm=a&invite={}",
+            invite.code
+        ))
+        .await;
+        assert_eq!(res.status(), 303);
+        assert_eq!(res.headers()["location"], "/ui");
+        let session = format!("{SESSION_COOKIE}={}", cookie(&res, SESSION_COOKIE));
+        let res = warp::test::request()
+            .path("/ui")
+            .header("cookie", &session)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        // and the invite's used up
+        let res = registered(&format!(
+            "username=carol&password=a&confirm=a&invite={}",
+            invite.code
+        ))
+        .await;
+        assert_eq!(res.status(), 403);
+    }
+}
@@ -1,6 +1,38 @@
-#[derive(Debug, sqlx::Type)]
+use crate::time::Timestamp;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct User {
     pub username: String,
     pub pwhash: String,
     pub session_id: Option<String>,
+    pub admin: bool,
+    pub disabled: bool,
+}
+
+// who can create their own account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Registration {
+    /// Nobody, users are added by an admin.
+    #[default]
+    Closed,
+    /// Those with an invite code from an admin.
+    Invite,
+    /// Anyone.
+    Open,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Invite {
+    pub code: String,
+    pub created_by: String,
+    pub created: Timestamp,
+    pub expires: Option<Timestamp>,
+    pub max_uses: i64,
+    pub used: i64,
+}
+
+impl Invite {
+    pub fn usable(&self, now: Timestamp) -> bool {
+        self.used < self.max_uses && self.expires.map_or(true, |expires| expires > now)
+    }
 }